@@ -0,0 +1,40 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+// Records what went into the build for `mocketd --version`, since bug reports need more than
+// the crate version
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .filter(|hash| !hash.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=MOCKETD_GIT_HASH={}", commit);
+
+    // The version actually resolved, which the requirement in Cargo.toml doesn't pin down
+    let wasmtime = fs::read_to_string("Cargo.lock")
+        .ok()
+        .and_then(|lock| locked_version(&lock, "wasmtime"))
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=MOCKETD_WASMTIME_VERSION={}", wasmtime);
+
+    // A missing path would rerun this on every build, e.g. from a source tarball
+    for path in [".git/HEAD", ".git/refs", "Cargo.lock"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
+
+fn locked_version(lock: &str, package: &str) -> Option<String> {
+    let name = format!("name = \"{}\"", package);
+    let mut lines = lock.lines();
+    lines.find(|line| *line == name)?;
+    let version = lines.next()?.strip_prefix("version = \"")?;
+    Some(version.trim_end_matches('"').to_string())
+}
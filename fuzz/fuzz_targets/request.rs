@@ -0,0 +1,52 @@
+//! Feeds arbitrary bytes to the HTTP/1.1 request parser, as one connection's worth of
+//! requests. The first byte picks how the bytes are read: its low six bits how many at a time
+//! (plus one), its top bit whether trailers are checked against the `Trailer` header.
+//!
+//! Parsing must never panic or hang, and whatever it accepts must be a request we could
+//! safely hand to a guest.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// Matches the parser's limit
+const MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&flags, data)) = data.split_first() else {
+        return;
+    };
+    let read_size = usize::from(flags & 0x3f) + 1;
+    let strict_trailers = flags & 0x80 != 0;
+
+    let parsed = mocketd::fuzzing::parse_requests(data, read_size, strict_trailers);
+
+    // Requests come off the data in order and never add up to more than it
+    let body_len: usize = parsed
+        .iter()
+        .filter_map(|request| request.as_ref().ok())
+        .map(|request| request.body.len())
+        .sum();
+    assert!(body_len <= data.len());
+    for (i, request) in parsed.iter().enumerate() {
+        match request {
+            Ok(request) => {
+                assert!(request.body.len() <= MAX_BODY_SIZE);
+                let is_tchar = |b: u8| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b);
+                assert!(!request.method.is_empty() && request.method.bytes().all(is_tchar));
+                assert!(!request.path.is_empty());
+                assert!(!request.path.contains(char::is_whitespace));
+                assert!(request.version.starts_with("HTTP/"));
+                for name in request.headers.keys().chain(request.trailers.keys()) {
+                    assert_eq!(*name, name.to_ascii_lowercase());
+                }
+                assert!(!request.id.is_empty());
+            }
+            // A refused request ends the connection
+            Err(status_code) => {
+                assert_eq!(i, parsed.len() - 1);
+                assert!((400..500).contains(status_code), "{}", status_code);
+            }
+        }
+    }
+});
@@ -0,0 +1,37 @@
+use chrono::Local;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+lazy_static! {
+    static ref ACCESS_LOG: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+}
+
+/// Enables the access log, appending to `path` or writing to stdout when `path` is `-`.
+pub fn set_access_log(path: &str) -> io::Result<()> {
+    let sink: Box<dyn Write + Send> = if path == "-" {
+        Box::new(io::stdout())
+    } else {
+        Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+    };
+    *ACCESS_LOG.lock().unwrap() = Some(sink);
+    Ok(())
+}
+
+// Writes one completed request in NCSA Common Log Format
+pub(crate) fn record(host: IpAddr, request_line: &str, status_code: u16, bytes: usize) {
+    let mut access_log = ACCESS_LOG.lock().unwrap();
+    if let Some(sink) = access_log.as_mut() {
+        let date = Local::now().format("%d/%b/%Y:%H:%M:%S %z");
+        let bytes = match bytes {
+            0 => "-".to_string(),
+            n => n.to_string(),
+        };
+        let _ = writeln!(
+            sink,
+            "{host} - - [{date}] \"{request_line}\" {status_code} {bytes}"
+        );
+        let _ = sink.flush();
+    }
+}
@@ -0,0 +1,109 @@
+use serde_json::{json, Value};
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use crate::diagnostics::pending;
+use crate::middleware::MiddlewareFuture;
+use crate::nodehttp::{self, Request, Response};
+use crate::{kill, log};
+
+// The bearer token admin requests must carry
+static TOKEN: Mutex<Option<String>> = Mutex::new(None);
+
+/// Serves the admin API on `address`, for operators to deal with a guest that wedges. Every
+/// request needs `Authorization: Bearer <token>`.
+///
+/// - `GET /requests` lists the requests handed to the guest that it hasn't answered, oldest
+///   first, with their `id`, `method`, `path`, `requestId` and `ageMs`
+/// - `DELETE /requests/<id>` answers one with `503` and tells the guest `http.aborted`
+///
+/// It's bound here, so a privileged port works before `set_run_as` gives up root, and it's
+/// served from the running async runtime. Its connections and requests aren't the guest's:
+/// no connection events, no middleware.
+pub fn start_admin(address: SocketAddr, token: &str) -> io::Result<()> {
+    if token.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the admin API needs a token",
+        ));
+    }
+    let listener = std::net::TcpListener::bind(address)?;
+    *TOKEN.lock().unwrap() = Some(token.to_string());
+    let server = nodehttp::create_server(handle)
+        .listener(listener)
+        .internal();
+    tokio::spawn(async move {
+        if let Err(err) = server.listen(address.port(), || {}).await {
+            eprintln!("Admin API on {} stopped: {}", address, err);
+        }
+    });
+    log(1, &format!("Admin API listening on {}", address));
+    Ok(())
+}
+
+// Compares in time that doesn't depend on where the token first differs
+fn is_authorized(request: &Request) -> bool {
+    let token = TOKEN.lock().unwrap();
+    let (Some(token), Some(given)) = (
+        token.as_deref(),
+        request
+            .headers
+            .get("authorization")
+            .and_then(|value| value.strip_prefix("Bearer ")),
+    ) else {
+        return false;
+    };
+    let given = given.trim().as_bytes();
+    given.len() == token.len()
+        && given
+            .iter()
+            .zip(token.as_bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn handle(request: &Request, response: Response) -> MiddlewareFuture {
+    let authorized = is_authorized(request);
+    let method = request.method.clone();
+    let path = request.path.clone();
+    Box::pin(async move {
+        if !authorized {
+            let headers = vec![("WWW-Authenticate".to_string(), "Bearer".to_string())];
+            return send(response, 401, headers, json!({ "error": "unauthorized" })).await;
+        }
+        let path = path.split('?').next().unwrap_or("");
+        let (status_code, body) = match (method.as_str(), path) {
+            ("GET", "/requests") => (200, json!({ "requests": pending() })),
+            ("DELETE", path) if path.starts_with("/requests/") => {
+                match path["/requests/".len()..].parse::<usize>() {
+                    Ok(id) if kill(id) => (200, json!({ "id": id, "killed": true })),
+                    _ => (404, json!({ "error": "no such request" })),
+                }
+            }
+            (_, "/requests") => {
+                let headers = vec![("Allow".to_string(), "GET".to_string())];
+                let body = json!({ "error": "method not allowed" });
+                return send(response, 405, headers, body).await;
+            }
+            _ => (404, json!({ "error": "not found" })),
+        };
+        send(response, status_code, Vec::new(), body).await
+    })
+}
+
+async fn send(
+    response: Response,
+    status_code: u16,
+    mut headers: Vec<(String, String)>,
+    body: Value,
+) -> Result<(), Box<dyn Error>> {
+    headers.push(("Content-Type".to_string(), "application/json".to_string()));
+    headers.push(("Cache-Control".to_string(), "no-store".to_string()));
+    let body = format!("{}\n", body);
+    response
+        .send_whole(status_code, headers, body.as_bytes(), Vec::new())
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,485 @@
+//! Synthetic load for `mocketd bench`: serves a module in-process on an ephemeral port, the
+//! way the `testing` client does, and drives it with concurrent HTTP/1.1 clients.
+//!
+//! The clients share the server's async runtime, so the numbers include their own overhead;
+//! they're for comparing guests and settings, not for absolute capacity planning.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::{set_listener, Runtime};
+
+// How long a request may take before it counts as an error
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// How long the guest has to start answering before the run is given up
+const WARM_UP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Upper bounds of the histogram buckets, in microseconds
+const BUCKETS: [u64; 17] = [
+    100, 200, 500, 1_000, 2_000, 5_000, 10_000, 20_000, 50_000, 100_000, 200_000, 500_000,
+    1_000_000, 2_000_000, 5_000_000, 10_000_000, 30_000_000,
+];
+
+const BAR_WIDTH: usize = 40;
+
+/// What load to generate.
+pub struct BenchOptions {
+    /// Concurrent connections, each sending one request at a time.
+    pub connections: usize,
+    /// Stops after this long, or after `requests`, whichever comes first.
+    pub duration: Option<Duration>,
+    pub requests: Option<u64>,
+    /// Requests per second across all connections; as fast as possible without.
+    pub rate: Option<f64>,
+    pub method: String,
+    pub path: String,
+    /// Bytes of request body.
+    pub payload: usize,
+    /// Reuses connections, or opens one per request; `None` runs both, one after the other,
+    /// to compare them.
+    pub keep_alive: Option<bool>,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        BenchOptions {
+            connections: 10,
+            duration: Some(Duration::from_secs(10)),
+            requests: None,
+            rate: None,
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            payload: 0,
+            keep_alive: Some(true),
+        }
+    }
+}
+
+/// Serves `wasm_path` and prints throughput and latency under the load `options` describes.
+/// Must be called from within a Tokio runtime, before anything else starts the server.
+pub async fn run(wasm_path: &str, options: &BenchOptions) -> io::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    set_listener(listener)?;
+    Runtime::new(wasm_path).start();
+
+    let head_only = options.method.eq_ignore_ascii_case("HEAD");
+    warm_up(addr, &build_request(addr, options, false), head_only).await?;
+
+    let modes = match options.keep_alive {
+        Some(keep_alive) => vec![keep_alive],
+        None => vec![true, false],
+    };
+    let mut reports = Vec::new();
+    for keep_alive in modes {
+        let report = load(addr, options, keep_alive).await;
+        report.print(keep_alive);
+        reports.push(report);
+    }
+    if let [with, without] = &reports[..] {
+        compare(with, without);
+    }
+    Ok(())
+}
+
+// The request every client sends
+fn build_request(addr: SocketAddr, options: &BenchOptions, keep_alive: bool) -> Vec<u8> {
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: mocketd-bench\r\nConnection: {}\r\n",
+        options.method,
+        options.path,
+        addr,
+        if keep_alive { "keep-alive" } else { "close" }
+    );
+    if options.payload > 0 {
+        request.push_str("Content-Type: application/octet-stream\r\n");
+        request.push_str(&format!("Content-Length: {}\r\n", options.payload));
+    }
+    request.push_str("\r\n");
+    let mut request = request.into_bytes();
+    request.extend(std::iter::repeat_n(b'x', options.payload));
+    request
+}
+
+// Waits for the guest to listen and answer with something other than `503`
+async fn warm_up(addr: SocketAddr, request: &[u8], head_only: bool) -> io::Result<()> {
+    let deadline = Instant::now() + WARM_UP_TIMEOUT;
+    loop {
+        let mut connection = None;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let result = tokio::time::timeout(
+            remaining,
+            exchange(&mut connection, addr, request, false, head_only),
+        )
+        .await;
+        match result {
+            Ok(Ok(response)) if response.status != 503 => return Ok(()),
+            Ok(_) if Instant::now() < deadline => {
+                tokio::time::sleep(Duration::from_millis(100)).await
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the guest didn't start answering requests",
+                ))
+            }
+        }
+    }
+}
+
+async fn load(addr: SocketAddr, options: &BenchOptions, keep_alive: bool) -> Report {
+    let request = Arc::new(build_request(addr, options, keep_alive));
+    let connections = options.connections.max(1);
+    let started = Instant::now();
+    let deadline = options.duration.map(|duration| started + duration);
+    let sent = Arc::new(AtomicU64::new(0));
+    // Each connection gets its share of the rate, on a fixed schedule
+    let interval = options
+        .rate
+        .map(|rate| Duration::from_secs_f64(connections as f64 / rate));
+    let head_only = options.method.eq_ignore_ascii_case("HEAD");
+
+    let workers: Vec<_> = (0..connections)
+        .map(|worker| {
+            let request = request.clone();
+            let sent = sent.clone();
+            let requests = options.requests;
+            tokio::spawn(async move {
+                let mut stats = Stats::default();
+                let mut connection = None;
+                // Spread the first requests of rate-limited connections over one interval
+                let mut next = interval
+                    .map(|interval| started + interval.mul_f64(worker as f64 / connections as f64));
+                loop {
+                    if let Some(at) = next {
+                        if deadline.is_some_and(|deadline| at >= deadline) {
+                            break;
+                        }
+                        tokio::time::sleep_until(at.into()).await;
+                    }
+                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        break;
+                    }
+                    if requests.is_some_and(|max| sent.fetch_add(1, Ordering::Relaxed) >= max) {
+                        break;
+                    }
+
+                    let start = Instant::now();
+                    let result = tokio::time::timeout(
+                        TIMEOUT,
+                        exchange(&mut connection, addr, &request, keep_alive, head_only),
+                    )
+                    .await;
+                    match result {
+                        Ok(Ok(response)) => {
+                            stats.latencies.push(start.elapsed());
+                            *stats.statuses.entry(response.status).or_default() += 1;
+                            stats.bytes += response.len as u64;
+                        }
+                        Ok(Err(err)) => stats.error(err.to_string()),
+                        Err(_) => stats.error("timed out".to_string()),
+                    }
+                    next = next.zip(interval).map(|(at, interval)| at + interval);
+                }
+                stats
+            })
+        })
+        .collect();
+
+    let mut stats = Stats::default();
+    for worker in workers {
+        if let Ok(worker) = worker.await {
+            stats.merge(worker);
+        }
+    }
+    stats.latencies.sort_unstable();
+    Report {
+        elapsed: started.elapsed(),
+        stats,
+    }
+}
+
+struct Response {
+    status: u16,
+    len: usize,
+}
+
+// Sends one request and reads its response, connecting first unless `connection` is still
+// open. The connection is kept only when both sides agree to keep it alive.
+async fn exchange(
+    connection: &mut Option<(TcpStream, Vec<u8>)>,
+    addr: SocketAddr,
+    request: &[u8],
+    keep_alive: bool,
+    head_only: bool,
+) -> io::Result<Response> {
+    let (mut stream, mut buffer) = match connection.take() {
+        Some(connection) => connection,
+        None => {
+            let stream = TcpStream::connect(addr).await?;
+            stream.set_nodelay(true)?;
+            (stream, Vec::new())
+        }
+    };
+
+    stream.write_all(request).await?;
+    let (response, reusable) = read_response(&mut stream, &mut buffer, head_only).await?;
+    if keep_alive && reusable {
+        *connection = Some((stream, buffer));
+    }
+    Ok(response)
+}
+
+// Reads one response off the stream, leaving anything after it in `buffer`. Also returns
+// whether the connection can take another request.
+async fn read_response(
+    stream: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+    head_only: bool,
+) -> io::Result<(Response, bool)> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+    let head_end = loop {
+        if let Some(pos) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        fill(stream, buffer).await?;
+    };
+    let head = String::from_utf8_lossy(&buffer[..head_end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let status: u16 = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| invalid("malformed status line"))?;
+    let mut content_length = None;
+    let mut chunked = false;
+    let mut close = false;
+    for (key, value) in lines.filter_map(|line| line.split_once(':')) {
+        let value = value.trim();
+        match key.trim().to_ascii_lowercase().as_str() {
+            "content-length" => content_length = value.parse::<usize>().ok(),
+            "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+            "connection" => close = value.eq_ignore_ascii_case("close"),
+            _ => {}
+        }
+    }
+    buffer.drain(..head_end);
+
+    let len = if head_only || status == 204 || status == 304 {
+        0
+    } else if chunked {
+        read_chunked(stream, buffer).await?
+    } else if let Some(len) = content_length {
+        while buffer.len() < len {
+            fill(stream, buffer).await?;
+        }
+        buffer.drain(..len);
+        len
+    } else {
+        // The body runs to the end of the connection
+        close = true;
+        while stream.read_buf(buffer).await? > 0 {}
+        buffer.drain(..).count()
+    };
+    Ok((Response { status, len }, !close))
+}
+
+// Skips over a chunked body and its trailers, returning the length of the data
+async fn read_chunked(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> io::Result<usize> {
+    let mut len = 0;
+    loop {
+        let line_end = loop {
+            if let Some(pos) = buffer.windows(2).position(|w| w == b"\r\n") {
+                break pos;
+            }
+            fill(stream, buffer).await?;
+        };
+        let line = String::from_utf8_lossy(&buffer[..line_end]).into_owned();
+        buffer.drain(..line_end + 2);
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "malformed chunk size"))?;
+        if size == 0 {
+            break;
+        }
+        while buffer.len() < size + 2 {
+            fill(stream, buffer).await?;
+        }
+        buffer.drain(..size + 2);
+        len += size;
+    }
+    // Trailer fields, up to the blank line
+    loop {
+        if buffer.starts_with(b"\r\n") {
+            buffer.drain(..2);
+            return Ok(len);
+        }
+        match buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(pos) => {
+                buffer.drain(..pos + 4);
+                return Ok(len);
+            }
+            None => fill(stream, buffer).await?,
+        }
+    }
+}
+
+async fn fill(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> io::Result<()> {
+    buffer.reserve(8 * 1024);
+    if stream.read_buf(buffer).await? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct Stats {
+    latencies: Vec<Duration>,
+    statuses: BTreeMap<u16, u64>,
+    bytes: u64,
+    errors: u64,
+    // The first error, as an example of what went wrong
+    error: Option<String>,
+}
+
+impl Stats {
+    fn error(&mut self, err: String) {
+        self.errors += 1;
+        self.error.get_or_insert(err);
+    }
+
+    fn merge(&mut self, other: Stats) {
+        self.latencies.extend(other.latencies);
+        for (status, count) in other.statuses {
+            *self.statuses.entry(status).or_default() += count;
+        }
+        self.bytes += other.bytes;
+        self.errors += other.errors;
+        if self.error.is_none() {
+            self.error = other.error;
+        }
+    }
+}
+
+struct Report {
+    elapsed: Duration,
+    stats: Stats,
+}
+
+impl Report {
+    fn throughput(&self) -> f64 {
+        self.stats.latencies.len() as f64 / self.elapsed.as_secs_f64()
+    }
+
+    // The latency below which `percent` of the requests finished; latencies are sorted
+    fn percentile(&self, percent: f64) -> Duration {
+        let latencies = &self.stats.latencies;
+        if latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let index = ((latencies.len() - 1) as f64 * percent / 100.0).round() as usize;
+        latencies[index]
+    }
+
+    fn print(&self, keep_alive: bool) {
+        let stats = &self.stats;
+        let completed = stats.latencies.len();
+
+        println!();
+        println!(
+            "Keep-alive {}: {} requests in {:.2}s, {} errors",
+            if keep_alive { "on" } else { "off" },
+            completed,
+            self.elapsed.as_secs_f64(),
+            stats.errors
+        );
+        if let Some(err) = &stats.error {
+            println!("  First error: {}", err);
+        }
+        if completed == 0 {
+            return;
+        }
+        println!(
+            "  Throughput: {:.1} req/s, {:.1} KiB/s of response bodies",
+            self.throughput(),
+            stats.bytes as f64 / 1024.0 / self.elapsed.as_secs_f64()
+        );
+        let statuses: Vec<String> = stats
+            .statuses
+            .iter()
+            .map(|(status, count)| format!("{} x{}", status, count))
+            .collect();
+        println!("  Statuses: {}", statuses.join(", "));
+        let mean = stats.latencies.iter().sum::<Duration>() / completed as u32;
+        println!(
+            "  Latency: min {}, mean {}, p50 {}, p90 {}, p99 {}, max {}",
+            millis(stats.latencies[0]),
+            millis(mean),
+            millis(self.percentile(50.0)),
+            millis(self.percentile(90.0)),
+            millis(self.percentile(99.0)),
+            millis(stats.latencies[completed - 1])
+        );
+        self.print_histogram();
+    }
+
+    fn print_histogram(&self) {
+        let mut counts = [0u64; BUCKETS.len() + 1];
+        for latency in &self.stats.latencies {
+            let micros = latency.as_micros() as u64;
+            let bucket = BUCKETS.iter().position(|&upper| micros < upper);
+            counts[bucket.unwrap_or(BUCKETS.len())] += 1;
+        }
+        let first = counts.iter().position(|&count| count > 0).unwrap_or(0);
+        let last = counts.iter().rposition(|&count| count > 0).unwrap_or(0);
+        let max = counts.iter().copied().max().unwrap_or(1).max(1);
+
+        println!("  Histogram:");
+        for (bucket, &count) in counts.iter().enumerate().take(last + 1).skip(first) {
+            let label = match BUCKETS.get(bucket) {
+                Some(&upper) => format!("< {}", millis(Duration::from_micros(upper))),
+                None => format!(">= {}", millis(Duration::from_micros(BUCKETS[bucket - 1]))),
+            };
+            let width = (count * BAR_WIDTH as u64).div_ceil(max) as usize;
+            println!(
+                "    {:>10} | {:<BAR_WIDTH$} {}",
+                label,
+                "#".repeat(width),
+                count
+            );
+        }
+    }
+}
+
+// What keeping connections open bought
+fn compare(with: &Report, without: &Report) {
+    if with.stats.latencies.is_empty() || without.stats.latencies.is_empty() {
+        return;
+    }
+    println!();
+    println!(
+        "Keep-alive: {:.2}x the throughput, p50 {} instead of {}, p99 {} instead of {}",
+        with.throughput() / without.throughput(),
+        millis(with.percentile(50.0)),
+        millis(without.percentile(50.0)),
+        millis(with.percentile(99.0)),
+        millis(without.percentile(99.0))
+    );
+}
+
+fn millis(duration: Duration) -> String {
+    let millis = duration.as_secs_f64() * 1000.0;
+    if millis < 10.0 {
+        format!("{:.2}ms", millis)
+    } else {
+        format!("{:.0}ms", millis)
+    }
+}
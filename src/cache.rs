@@ -0,0 +1,465 @@
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+use crate::nodehttp::Response;
+
+// Statuses whose responses are worth keeping
+const CACHEABLE_STATUSES: [u16; 6] = [200, 203, 204, 301, 404, 410];
+
+// Responses kept for how long ago they were last modified are kept for this fraction of it,
+// and at most a day
+const HEURISTIC_FRACTION: u32 = 10;
+const MAX_HEURISTIC_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Requests waiting on an identical one the guest is answering give up on it after this long,
+// or by their deadline if that's sooner, and ask the guest themselves
+const MAX_FOLLOW_WAIT: Duration = Duration::from_secs(10);
+
+// The largest age a `Cache-Control` can give (RFC 9111 §1.2.2), about 68 years
+const MAX_AGE: u64 = 2_147_483_648;
+
+// A response kept for the requests that Vary the same way
+struct Entry {
+    // The request header fields the response varies on, with the values it was made for
+    vary: Vec<(String, Option<String>)>,
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    stored: Instant,
+    ttl: Duration,
+    // When it was last served, for evicting the least recently used
+    used: u64,
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        let headers: usize = self.headers.iter().map(|(k, v)| k.len() + v.len()).sum();
+        self.body.len() + headers
+    }
+
+    fn matches(&self, response: &Response) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| response.request_header(name) == value.as_deref())
+    }
+}
+
+#[derive(Default)]
+struct Cache {
+    max_bytes: usize,
+    // Responses by `host` and request target
+    entries: HashMap<String, Vec<Entry>>,
+    bytes: usize,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+    // Requests that waited for another's answer rather than asking the guest themselves
+    coalesced: u64,
+}
+
+impl Cache {
+    fn remove_where(&mut self, mut remove: impl FnMut(&str, &Entry) -> bool) {
+        let mut freed = 0;
+        self.entries.retain(|key, entries| {
+            entries.retain(|entry| {
+                let gone = remove(key, entry);
+                if gone {
+                    freed += entry.size();
+                }
+                !gone
+            });
+            !entries.is_empty()
+        });
+        self.bytes -= freed;
+    }
+
+    // Drops the least recently served responses until there's room for `size` more bytes
+    fn make_room(&mut self, size: usize) {
+        while self.bytes + size > self.max_bytes {
+            let Some(oldest) = self
+                .entries
+                .values()
+                .flatten()
+                .map(|entry| entry.used)
+                .min()
+            else {
+                return;
+            };
+            self.remove_where(|_, entry| entry.used == oldest);
+        }
+    }
+}
+
+static CACHE: Mutex<Option<Cache>> = Mutex::new(None);
+
+lazy_static! {
+    // The requests the guest is answering that may be kept, by cache key, each closing its
+    // channel once answered
+    static ref FLIGHTS: Mutex<HashMap<String, watch::Receiver<()>>> = Mutex::new(HashMap::new());
+}
+
+/// Keeps up to `max_bytes` of responses to `GET` requests the guest marks as cacheable, and
+/// answers matching `GET` and `HEAD` requests with them without bothering the guest. 0 (the
+/// default) caches nothing.
+///
+/// A response is kept when it's complete in one `http.end`, has a 200, 203, 204, 301, 404 or
+/// 410 status, no trailers or `Set-Cookie`, and is fresh for a while: for the seconds of the
+/// `s-maxage` or `max-age` of its `Cache-Control`, or else until its `Expires`, or else for a
+/// tenth of the time since its `Last-Modified`, up to a day. `no-store`, `no-cache` and
+/// `private` keep it out, as does any of those fields being malformed, which is logged. It's
+/// served to requests with the same `Host` and target, and the same values of the fields
+/// named in its `Vary` (never with `Vary: *`). Responses to requests with `Authorization`
+/// are only kept if `public`. `POST`, `PUT`, `PATCH` and `DELETE` requests drop the
+/// responses kept for their target, and reloading the guest drops them all. The least
+/// recently served go first when the cache is full. Requests arriving while the guest answers
+/// an identical `GET` wait for that answer, and are served it if it's kept; after ten seconds,
+/// or at their deadline if that's sooner, they go to the guest themselves.
+pub fn set_response_cache(max_bytes: usize) {
+    let mut cache = CACHE.lock().unwrap();
+    match (max_bytes, cache.as_mut()) {
+        (0, _) => *cache = None,
+        (_, Some(cache)) => {
+            cache.max_bytes = max_bytes;
+            cache.make_room(0);
+        }
+        (_, None) => {
+            *cache = Some(Cache {
+                max_bytes,
+                ..Cache::default()
+            })
+        }
+    }
+}
+
+// Where the responses to `target` are kept, for the host the request was for
+fn key(response: &Response, target: &str) -> String {
+    format!(
+        "{} {}",
+        response.request_header("host").unwrap_or(""),
+        target
+    )
+}
+
+// The method and target of the request `response` answers
+fn request(response: &Response) -> (&str, &str) {
+    let mut parts = response.request_line().split(' ');
+    (parts.next().unwrap_or(""), parts.next().unwrap_or(""))
+}
+
+// A kept response, as it's served again
+pub(crate) struct Hit {
+    pub(crate) status_code: u16,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+// A kept response to the request `response` answers, with its `Age`, if there's a fresh one
+pub(crate) fn lookup(response: &Response) -> Option<Hit> {
+    let mut cache = CACHE.lock().unwrap();
+    let cache = cache.as_mut()?;
+    let (method, target) = request(response);
+    if method != "GET" && method != "HEAD" {
+        return None;
+    }
+    let key = key(response, target);
+    let now = Instant::now();
+    cache.remove_where(|entry_key, entry| {
+        entry_key == key && now.duration_since(entry.stored) >= entry.ttl
+    });
+    cache.tick += 1;
+    let tick = cache.tick;
+    let entry = cache
+        .entries
+        .get_mut(&key)?
+        .iter_mut()
+        .find(|entry| entry.matches(response))?;
+    entry.used = tick;
+    let mut headers = entry.headers.clone();
+    let age = now.duration_since(entry.stored).as_secs();
+    headers.push(("Age".to_string(), age.to_string()));
+    let hit = Hit {
+        status_code: entry.status_code,
+        headers,
+        body: entry.body.clone(),
+    };
+    cache.hits += 1;
+    Some(hit)
+}
+
+// Where a request that missed the cache stands with identical ones the guest is answering
+pub(crate) enum Flight {
+    // It's the first, and goes to the guest
+    Lead(Leader),
+    // Another one is on its way; try the cache again once it's answered
+    Follow(watch::Receiver<()>),
+}
+
+// The request the guest is answering for a cache key, held by its response until it's done
+pub(crate) struct Leader {
+    key: String,
+    _done: watch::Sender<()>,
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        // The response is kept by now, if it may be; waking the followers is up to `_done`
+        FLIGHTS.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl Flight {
+    // Waits for the request being followed to be answered, but no later than `deadline` or
+    // `MAX_FOLLOW_WAIT`; false if it wasn't by then
+    pub(crate) async fn wait(self, deadline: Option<Instant>) -> bool {
+        let Flight::Follow(mut done) = self else {
+            return true;
+        };
+        let cap = Instant::now() + MAX_FOLLOW_WAIT;
+        let until = deadline.map_or(cap, |deadline| deadline.min(cap));
+        // Only ever closed, never sent on
+        tokio::time::timeout_at(until.into(), done.changed())
+            .await
+            .is_ok()
+    }
+}
+
+// Lines the request `response` answers up behind an identical one the guest is answering, or
+// makes it the one others line up behind. `None` when there's no cache, or for requests the
+// cache doesn't keep answers to.
+pub(crate) fn join(response: &Response) -> Option<Flight> {
+    let mut cache = CACHE.lock().unwrap();
+    let cache = cache.as_mut()?;
+    let (method, target) = request(response);
+    let key = key(response, target);
+    let mut flights = FLIGHTS.lock().unwrap();
+    if let Some(done) = flights.get(&key) {
+        cache.coalesced += 1;
+        return Some(Flight::Follow(done.clone()));
+    }
+    cache.misses += 1;
+    // HEAD responses aren't kept, so there'd be nothing to wait for
+    if method != "GET" {
+        return None;
+    }
+    let (sender, receiver) = watch::channel(());
+    flights.insert(key.clone(), receiver);
+    Some(Flight::Lead(Leader { key, _done: sender }))
+}
+
+// Keeps the response to the request `response` answers, if the guest said it may be
+pub(crate) fn store(
+    response: &Response,
+    status_code: u16,
+    headers: &[(String, String)],
+    body: &[u8],
+) {
+    let mut cache = CACHE.lock().unwrap();
+    let Some(cache) = cache.as_mut() else {
+        return;
+    };
+    let (method, target) = request(response);
+    if method != "GET" || !CACHEABLE_STATUSES.contains(&status_code) {
+        return;
+    }
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    };
+    let Some((ttl, public)) = lifetime(response, header) else {
+        return;
+    };
+    if header("Set-Cookie").is_some()
+        || (response.request_header("authorization").is_some() && !public)
+    {
+        return;
+    }
+    let vary: Vec<String> = header("Vary")
+        .map(|vary| {
+            vary.split(',')
+                .map(|name| name.trim().to_ascii_lowercase())
+                .filter(|name| !name.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+    if vary.iter().any(|name| name == "*") {
+        return;
+    }
+
+    cache.tick += 1;
+    let entry = Entry {
+        vary: vary
+            .into_iter()
+            .map(|name| {
+                let value = response.request_header(&name).map(str::to_string);
+                (name, value)
+            })
+            .collect(),
+        status_code,
+        headers: headers.to_vec(),
+        body: body.to_vec(),
+        stored: Instant::now(),
+        ttl,
+        used: cache.tick,
+    };
+    let size = entry.size();
+    if size > cache.max_bytes {
+        return;
+    }
+    let key = key(response, target);
+    cache.remove_where(|entry_key, kept| entry_key == key && kept.vary == entry.vary);
+    cache.make_room(size);
+    cache.bytes += size;
+    cache.entries.entry(key).or_default().push(entry);
+}
+
+// How long a response may be kept, and whether it's `public`: for its `Cache-Control`'s
+// `s-maxage` or `max-age`, or else until its `Expires`, or else, going by its `Last-Modified`,
+// for a tenth of the time since (RFC 9111's heuristic), up to a day. A malformed field is
+// logged and keeps the response out, rather than have it kept longer than meant.
+fn lifetime<'a>(
+    response: &Response,
+    header: impl Fn(&str) -> Option<&'a str>,
+) -> Option<(Duration, bool)> {
+    let malformed = |name: &str, value: &str| {
+        response.log(
+            1,
+            &format!(
+                "Not caching, malformed {} from the guest: {:?}",
+                name, value
+            ),
+        );
+    };
+    let date = |name: &str| match header(name) {
+        None => Ok(None),
+        Some(value) => match DateTime::parse_from_rfc2822(value.trim()) {
+            Ok(date) => Ok(Some(date.with_timezone(&Utc))),
+            Err(_) => {
+                malformed(name, value);
+                Err(())
+            }
+        },
+    };
+    let expires = date("Expires").ok()?;
+    let last_modified = date("Last-Modified").ok()?;
+    let freshness = match header("Cache-Control") {
+        Some(value) => match freshness(value) {
+            Some(freshness) => freshness,
+            None => {
+                malformed("Cache-Control", value);
+                return None;
+            }
+        },
+        None => Freshness::default(),
+    };
+    if freshness.forbidden {
+        return None;
+    }
+
+    let now = Utc::now();
+    let ttl = if let Some(seconds) = freshness.max_age {
+        Duration::from_secs(seconds)
+    } else if let Some(expires) = expires {
+        (expires - now).to_std().ok()?
+    } else {
+        ((now - last_modified?).to_std().ok()? / HEURISTIC_FRACTION).min(MAX_HEURISTIC_TTL)
+    };
+    (!ttl.is_zero()).then_some((ttl, freshness.public))
+}
+
+// What a `Cache-Control` says about keeping a response
+#[derive(Default)]
+struct Freshness {
+    // `s-maxage`, or else `max-age`
+    max_age: Option<u64>,
+    public: bool,
+    // `no-store`, `no-cache` or `private`
+    forbidden: bool,
+}
+
+// Reads a `Cache-Control`, or `None` if it isn't one: a list of directives, each a token with
+// an optional token or quoted-string argument, which for the ages is a number of seconds
+fn freshness(cache_control: &str) -> Option<Freshness> {
+    let is_token = |text: &str| {
+        !text.is_empty()
+            && text
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+    };
+    let mut freshness = Freshness::default();
+    let mut shared_max_age = None;
+    for directive in cache_control.split(',').map(str::trim) {
+        // Empty list elements are allowed, e.g. a trailing comma
+        if directive.is_empty() {
+            continue;
+        }
+        let (name, value) = match directive.split_once('=') {
+            Some((name, value)) => {
+                let value = value.trim();
+                let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+                    Some(quoted) => quoted,
+                    None if is_token(value) => value,
+                    None => return None,
+                };
+                (name.trim(), Some(value))
+            }
+            None => (directive, None),
+        };
+        if !is_token(name) {
+            return None;
+        }
+        let seconds = || {
+            value
+                .filter(|value| !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()))
+                // Ages past what fits are as good as forever
+                .map(|value| value.parse::<u64>().unwrap_or(MAX_AGE))
+        };
+        match name.to_ascii_lowercase().as_str() {
+            "no-store" | "no-cache" | "private" => freshness.forbidden = true,
+            "public" => freshness.public = true,
+            "max-age" => freshness.max_age = Some(seconds()?),
+            "s-maxage" => shared_max_age = Some(seconds()?),
+            _ => {}
+        }
+    }
+    freshness.max_age = shared_max_age.or(freshness.max_age);
+    Some(freshness)
+}
+
+// Drops the responses kept for the target of the request `response` answers, which changes
+// what's there
+pub(crate) fn invalidate(response: &Response) {
+    let mut cache = CACHE.lock().unwrap();
+    let Some(cache) = cache.as_mut() else {
+        return;
+    };
+    let key = key(response, request(response).1);
+    cache.remove_where(|entry_key, _| entry_key == key);
+}
+
+// Drops every kept response, e.g. when the guest is reloaded
+pub(crate) fn clear() {
+    if let Some(cache) = CACHE.lock().unwrap().as_mut() {
+        cache.entries.clear();
+        cache.bytes = 0;
+    }
+}
+
+// The cache's size and how often it answered, for `http.cacheStats` and diagnostics
+pub(crate) fn stats() -> Option<Value> {
+    let cache = CACHE.lock().unwrap();
+    let cache = cache.as_ref()?;
+    Some(json!({
+        "entries": cache.entries.values().map(Vec::len).sum::<usize>(),
+        "bytes": cache.bytes,
+        "maxBytes": cache.max_bytes,
+        "hits": cache.hits,
+        "misses": cache.misses,
+        "coalesced": cache.coalesced,
+    }))
+}
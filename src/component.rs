@@ -0,0 +1,75 @@
+use anyhow::{anyhow, Result};
+use wasmtime::component::{Func, Instance, Linker};
+use wasmtime::{Engine, Store};
+
+use crate::runtime::receive_message;
+
+// The interfaces of the `mocket` world, see `wit/mocket.wit`
+const HOST_INTERFACE: &str = "mocket:runtime/host@0.1.0";
+const GUEST_INTERFACE: &str = "mocket:runtime/guest@0.1.0";
+
+// Whether `bytes` hold a component rather than a core module: in the binary format, going by
+// the layer field after the version; in the text format, by the top-level form
+pub(crate) fn is_component(bytes: &[u8]) -> bool {
+    match bytes {
+        [0, b'a', b's', b'm', _, _, layer_low, layer_high, ..] => {
+            [*layer_low, *layer_high] == [1, 0]
+        }
+        _ => {
+            std::str::from_utf8(bytes).is_ok_and(|text| text.trim_start().starts_with("(component"))
+        }
+    }
+}
+
+// Links the `host` interface; nothing else is there for components to import
+pub(crate) fn linker(engine: &Engine) -> Result<Linker<()>> {
+    let mut linker = Linker::new(engine);
+    let mut host = linker.instance(HOST_INTERFACE)?;
+    host.func_wrap("send", |_, (event,): (String,)| {
+        receive_message(&event);
+        Ok(())
+    })?;
+    host.func_wrap("print", |_, (line,): (String,)| {
+        println!("{}", line);
+        Ok(())
+    })?;
+    Ok(linker)
+}
+
+// The function `name` of the component's `guest` export, if it has one
+fn guest_func(store: &mut Store<()>, instance: &Instance, name: &str) -> Option<Func> {
+    let interface = instance.get_export(&mut *store, None, GUEST_INTERFACE)?;
+    let func = instance.get_export(&mut *store, Some(&interface), name)?;
+    instance.get_func(&mut *store, func)
+}
+
+// Calls `func` with one string argument
+fn call_with(store: &mut Store<()>, func: Func, arg: &str) -> Result<()> {
+    let func = func.typed::<(&str,), ()>(&*store)?;
+    func.call(&mut *store, (arg,))?;
+    func.post_return(&mut *store)
+}
+
+// Hands the guest one event
+pub(crate) fn receive(store: &mut Store<()>, instance: &Instance, event: &str) -> Result<()> {
+    let func = guest_func(store, instance, "receive")
+        .ok_or_else(|| anyhow!("receive function not found"))?;
+    call_with(store, func, event)
+}
+
+// Passes the runtime's settings to the guest's `configure`; `false` if it has none
+pub(crate) fn configure(store: &mut Store<()>, instance: &Instance, config: &str) -> Result<bool> {
+    match guest_func(store, instance, "configure") {
+        Some(func) => call_with(store, func, config).map(|()| true),
+        None => Ok(false),
+    }
+}
+
+// Runs the guest's `start`; `None` if it has none
+pub(crate) fn start(store: &mut Store<()>, instance: &Instance) -> Option<Result<()>> {
+    let func = guest_func(store, instance, "start")?;
+    Some(func.typed::<(), ()>(&*store).and_then(|func| {
+        func.call(&mut *store, ())?;
+        func.post_return(&mut *store)
+    }))
+}
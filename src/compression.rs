@@ -0,0 +1,178 @@
+use flate2::write::{GzEncoder, ZlibEncoder};
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+use crate::log;
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Encoding {
+    Gzip,
+    Deflate,
+    Brotli,
+}
+
+impl Encoding {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            "br" => Some(Encoding::Brotli),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Brotli => "br",
+        }
+    }
+}
+
+struct Compression {
+    // In order of preference, for clients that accept several equally
+    encodings: Vec<Encoding>,
+    level: Option<u32>,
+}
+
+static COMPRESSION: Mutex<Option<Compression>> = Mutex::new(None);
+
+/// Compresses response bodies with the first of `encodings` (`gzip`, `deflate` or `br`) the
+/// client prefers. `level` goes from 0 (fastest) to 9 for gzip and deflate, or 11 for br,
+/// and defaults to 6 and 5 respectively, which suit compressing on the fly.
+pub fn set_compression(encodings: &[&str], level: Option<u32>) -> Result<(), String> {
+    let encodings = encodings
+        .iter()
+        .map(|name| Encoding::from_name(name).ok_or(format!("unknown encoding `{}`", name)))
+        .collect::<Result<Vec<_>, _>>()?;
+    *COMPRESSION.lock().unwrap() = if encodings.is_empty() {
+        None
+    } else {
+        Some(Compression { encodings, level })
+    };
+    Ok(())
+}
+
+pub(crate) fn is_enabled() -> bool {
+    COMPRESSION.lock().unwrap().is_some()
+}
+
+// Picks the encoding for a client's `Accept-Encoding`, or `None` for identity. The client's
+// weights decide; ties go to our order of preference, and `identity` wins over anything it
+// outweighs.
+pub(crate) fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let compression = COMPRESSION.lock().unwrap();
+    let compression = compression.as_ref()?;
+
+    let mut weights = Vec::new();
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = params
+            .find_map(|param| {
+                let (key, value) = param.split_once('=')?;
+                key.trim()
+                    .eq_ignore_ascii_case("q")
+                    .then(|| value.trim().parse::<f32>().ok())?
+            })
+            .unwrap_or(1.0);
+        if !coding.is_empty() {
+            weights.push((coding, q));
+        }
+    }
+    let weight = |coding: &str| {
+        let exact = weights.iter().find(|(c, _)| c == coding);
+        let wildcard = weights.iter().find(|(c, _)| c == "*");
+        exact.or(wildcard).map(|(_, q)| *q)
+    };
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for &encoding in &compression.encodings {
+        match weight(encoding.name()) {
+            Some(q) if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) => {
+                best = Some((encoding, q));
+            }
+            _ => {}
+        }
+    }
+    let (encoding, q) = best?;
+    // Identity is acceptable unless excluded, but only preferred when weighted higher
+    match weights.iter().find(|(c, _)| c == "identity") {
+        Some((_, identity_q)) if *identity_q > q => None,
+        _ => Some(encoding),
+    }
+}
+
+pub(crate) fn compress(encoding: Encoding, body: &[u8]) -> io::Result<Vec<u8>> {
+    let level = COMPRESSION
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|compression| compression.level);
+    match encoding {
+        Encoding::Gzip => {
+            let level = level.map_or(flate2::Compression::default(), |l| {
+                flate2::Compression::new(l.min(9))
+            });
+            let mut encoder = GzEncoder::new(Vec::new(), level);
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        Encoding::Deflate => {
+            let level = level.map_or(flate2::Compression::default(), |l| {
+                flate2::Compression::new(l.min(9))
+            });
+            // HTTP's `deflate` is the zlib format, not a raw deflate stream
+            let mut encoder = ZlibEncoder::new(Vec::new(), level);
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        Encoding::Brotli => {
+            let mut output = Vec::new();
+            let params = brotli::enc::BrotliEncoderParams {
+                quality: level.map_or(5, |l| l.min(11)) as i32,
+                ..Default::default()
+            };
+            brotli::BrotliCompress(&mut &body[..], &mut output, &params)?;
+            Ok(output)
+        }
+    }
+}
+
+// Compresses a response body for the client if it accepts one of our encodings and the body
+// isn't encoded already, adding the matching headers
+pub(crate) fn apply(
+    accept_encoding: Option<&str>,
+    headers: &mut Vec<(String, String)>,
+    body: Vec<u8>,
+) -> Vec<u8> {
+    if !is_enabled() {
+        return body;
+    }
+    let has_header = |headers: &Vec<(String, String)>, name: &str| {
+        headers
+            .iter()
+            .any(|(key, _)| key.eq_ignore_ascii_case(name))
+    };
+    // Caches must not hand a compressed body to a client that didn't ask for one
+    if !has_header(headers, "Vary") {
+        headers.push(("Vary".to_string(), "Accept-Encoding".to_string()));
+    }
+    if body.is_empty() || has_header(headers, "Content-Encoding") {
+        return body;
+    }
+    let Some(encoding) = accept_encoding.and_then(negotiate) else {
+        return body;
+    };
+    match compress(encoding, &body) {
+        Ok(compressed) => {
+            headers.push(("Content-Encoding".to_string(), encoding.name().to_string()));
+            compressed
+        }
+        Err(err) => {
+            log(1, &format!("Failed to compress response: {}", err));
+            body
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+
+/// Runtime options loaded from a JSON file, e.g.
+///
+/// ```json
+/// {
+///     "log": 1,
+///     "port": 8080,
+///     "tls": { "cert": "cert.pem", "key": "key.pem" },
+///     "accessLog": "access.log"
+/// }
+/// ```
+///
+/// Every field is optional; unknown fields are rejected so typos don't go unnoticed.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct Config {
+    pub log: Option<usize>,
+    pub log_format: Option<String>,
+    pub port: Option<u16>,
+    pub fd: Option<i32>,
+    pub ip_stack: Option<String>,
+    pub tls: Option<TlsConfig>,
+    pub http2: Option<bool>,
+    pub connection_events: Option<bool>,
+    pub access_log: Option<String>,
+    pub diagnostics_file: Option<String>,
+    pub content_length_threshold: Option<usize>,
+    pub response_cache: Option<usize>,
+    pub error_pages: Option<HashMap<u16, String>>,
+    pub error_template: Option<String>,
+    pub default_content_type: Option<String>,
+    pub server_header: Option<String>,
+    pub reject_malformed_json: Option<bool>,
+    pub allowed_methods: Option<Vec<String>>,
+    pub method_override: Option<bool>,
+    pub json_rpc: Option<bool>,
+    pub json_rpc_path: Option<String>,
+    pub profile: Option<bool>,
+    pub ready_timeout: Option<u64>,
+    pub idle_timeout: Option<u64>,
+    pub shutdown_timeout: Option<u64>,
+    pub trace_bodies: Option<usize>,
+    pub redact: Option<Vec<String>>,
+    pub threads: Option<usize>,
+    pub compress: Option<Vec<String>>,
+    pub compression_level: Option<u32>,
+    pub strong_etags: Option<bool>,
+    pub trusted_proxies: Option<Vec<String>>,
+    pub module_cache: Option<String>,
+    pub compiler: Option<String>,
+    pub opt_level: Option<String>,
+    pub parallel_compilation: Option<bool>,
+    pub isolation_pool: Option<usize>,
+    pub max_queued_requests: Option<usize>,
+    pub max_connections: Option<usize>,
+    pub connection_overflow: Option<String>,
+    pub connection_queue_timeout: Option<u64>,
+    pub max_response_body: Option<usize>,
+    pub response_body_warning: Option<usize>,
+    pub truncate_responses: Option<bool>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub admin: Option<String>,
+    pub admin_token: Option<String>,
+    pub otlp_endpoint: Option<String>,
+    pub otel_service_name: Option<String>,
+    pub warmup: Option<bool>,
+    pub warmup_requests: Option<Vec<WarmupRequest>>,
+}
+
+#[derive(Deserialize, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+    pub client_ca: Option<String>,
+    // Certificates served instead to clients asking for these hostnames with SNI
+    pub hosts: Option<HashMap<String, TlsHost>>,
+}
+
+#[derive(Deserialize, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TlsHost {
+    pub cert: String,
+    pub key: String,
+}
+
+impl Config {
+    pub fn load(path: &str) -> io::Result<Self> {
+        let config = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        Ok(config)
+    }
+}
+
+// A request sent through the guest before it takes real ones, see `set_warmup`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct WarmupRequest {
+    // GET when not given
+    pub method: Option<String>,
+    pub path: String,
+    pub headers: Option<HashMap<String, String>>,
+    // A string is sent as it is, anything else as JSON
+    pub body: Option<serde_json::Value>,
+}
@@ -0,0 +1,154 @@
+use serde_json::json;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::{log, queue_event, send_event};
+
+static CONNECTION_EVENTS: AtomicBool = AtomicBool::new(false);
+
+// Open connections are capped at this many, 0 for no cap
+static MAX_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+static OPEN_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+static OVERFLOW: Mutex<ConnectionOverflow> = Mutex::new(ConnectionOverflow::Reject);
+// Woken whenever a connection under the cap closes
+static SLOT_FREED: Notify = Notify::const_new();
+
+// Connection ids count up from 1, separately from request ids
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Tells the guest about every accepted connection with `connection.open`
+/// (`{ id, remoteAddress, remotePort, tls }`), and `connection.close` (`{ id }`) once the server
+/// is done with it. Requests carry the id of their connection as `connectionId`.
+pub fn set_connection_events(enabled: bool) {
+    CONNECTION_EVENTS.store(enabled, Ordering::Relaxed);
+}
+
+// A connection the guest was told about. Dropping it, when the connection closes or is taken
+// over with `socket.hijack`, tells the guest it's gone.
+pub(crate) struct Tracked {
+    pub(crate) id: u64,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        queue_event("connection.close", json!({ "id": self.id }));
+    }
+}
+
+// Sends `connection.open` for a newly accepted connection, if the guest asked for them. The
+// event is in before any request of the connection is.
+pub(crate) async fn open(remote_addr: SocketAddr, tls: bool) -> Option<Tracked> {
+    if !CONNECTION_EVENTS.load(Ordering::Relaxed) {
+        return None;
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let event = json!({
+        "id": id,
+        "remoteAddress": remote_addr.ip().to_string(),
+        "remotePort": remote_addr.port(),
+        "tls": tls,
+    });
+    let _ = tokio::task::spawn_blocking(move || send_event("connection.open", event)).await;
+    Some(Tracked { id })
+}
+
+/// What happens to connections arriving while `set_max_connections` are open.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ConnectionOverflow {
+    /// Answered with 503 and closed right away. Clients learn of the overload at once, at the
+    /// cost of accepting (and, over TLS, handshaking) connections only to turn them away.
+    Reject,
+    /// Kept waiting for a connection to close, and rejected as above if none does in time.
+    /// Absorbs short bursts, but each waiting client holds a socket and doesn't know why
+    /// nothing happens.
+    Queue(Duration),
+    /// Not accepted until a connection closes, leaving them in the listen backlog. Costs the
+    /// server nothing, but clients see only a slow connect, or a refused one once the
+    /// backlog is full.
+    Block,
+}
+
+/// Caps the connections served at once at `max` (0: no cap), with `overflow` deciding what
+/// happens to the ones past it. Connections already open are left alone when the cap drops.
+pub fn set_max_connections(max: usize, overflow: ConnectionOverflow) {
+    *OVERFLOW.lock().unwrap() = overflow;
+    MAX_CONNECTIONS.store(max, Ordering::Relaxed);
+    // Anyone waiting may fit under a raised cap, or have to give up under `Reject`
+    SLOT_FREED.notify_waiters();
+}
+
+// The connections open now, whether or not there's a cap
+pub(crate) fn open_connections() -> usize {
+    OPEN_CONNECTIONS.load(Ordering::Relaxed)
+}
+
+pub(crate) fn overflow() -> ConnectionOverflow {
+    *OVERFLOW.lock().unwrap()
+}
+
+// A connection counted against the cap, until it's dropped
+pub(crate) struct Slot;
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        OPEN_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+        SLOT_FREED.notify_waiters();
+    }
+}
+
+// A slot for a new connection, if one is free
+pub(crate) fn try_slot() -> Option<Slot> {
+    OPEN_CONNECTIONS
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| {
+            let max = MAX_CONNECTIONS.load(Ordering::Relaxed);
+            (max == 0 || open < max).then_some(open + 1)
+        })
+        .ok()
+        .map(|_| Slot)
+}
+
+// Waits for a slot, as `Queue` and `Block` do. `None` if `wait` (if any) runs out first, or
+// the policy changes to `Reject`.
+pub(crate) async fn wait_for_slot(wait: Option<Duration>) -> Option<Slot> {
+    let waiting = async {
+        loop {
+            // Registered before looking, so a slot freed in between isn't missed
+            let freed = SLOT_FREED.notified();
+            tokio::pin!(freed);
+            freed.as_mut().enable();
+            if let Some(slot) = try_slot() {
+                return Some(slot);
+            }
+            if overflow() == ConnectionOverflow::Reject {
+                return None;
+            }
+            freed.await;
+        }
+    };
+    match wait {
+        Some(wait) => tokio::time::timeout(wait, waiting).await.ok().flatten(),
+        None => waiting.await,
+    }
+}
+
+// A slot for a connection just accepted, or `None` if it's to be turned away
+pub(crate) async fn admit(remote_addr: SocketAddr) -> Option<Slot> {
+    if let Some(slot) = try_slot() {
+        return Some(slot);
+    }
+    let slot = match overflow() {
+        ConnectionOverflow::Queue(wait) => wait_for_slot(Some(wait)).await,
+        // `Block` only gets here if the cap dropped since the connection was accepted
+        ConnectionOverflow::Reject | ConnectionOverflow::Block => None,
+    };
+    if slot.is_none() {
+        log(
+            2,
+            &format!("Too many connections, turning away {}", remote_addr),
+        );
+    }
+    slot
+}
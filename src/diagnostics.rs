@@ -0,0 +1,113 @@
+use chrono::Utc;
+use serde_json::{json, Value};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Mutex, TryLockError};
+
+use crate::{
+    cache, in_flight, isolation, memory_size, nodehttp, queued_requests, LISTENERS, RESPONSE_MAP,
+    WASM,
+};
+
+static DIAGNOSTICS_FILE: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Appends [`dump_diagnostics`] snapshots to `path`, one JSON object per line. `None` (the
+/// default) prints them to stderr.
+pub fn set_diagnostics_file(path: Option<&str>) {
+    *DIAGNOSTICS_FILE.lock().unwrap() = path.map(PathBuf::from);
+}
+
+/// A snapshot of the server's state, for when the guest misbehaves:
+///
+/// - `pending`: the requests handed to the guest that it hasn't answered, oldest first, with
+///   their request line (and its method and path), `X-Request-Id` and age in milliseconds
+/// - `inFlight` (those same requests) and `activeRequests` (including responses being written)
+/// - `queuedRequests`: the requests waiting for the guest to take them, or in it, see
+///   `set_max_queued_requests`
+/// - `guest`: the size of its linear memory and, when profiling, the fuel it consumed; just
+///   `busy: true` if it's in the middle of a call, which a stuck guest always is
+/// - `listeners`: the ports being listened on
+/// - `instancePool`: the instances ready and in use, with `set_isolation_pool`
+/// - `responseCache`: its size, hits and misses, with `set_response_cache`
+pub fn diagnostics() -> Value {
+    // Never wait for the guest: the snapshot is wanted most when it's stuck
+    let guest = match WASM.try_lock() {
+        Ok(mut wasm) => match wasm.as_mut() {
+            Some((store, guest)) => json!({
+                "busy": false,
+                "memoryBytes": memory_size(store, guest),
+                // Stores start with all the fuel there is when profiling
+                "fuelConsumed": store.get_fuel().ok().map(|left| u64::MAX - left),
+            }),
+            None => Value::Null,
+        },
+        Err(TryLockError::WouldBlock) => json!({ "busy": true }),
+        Err(TryLockError::Poisoned(_)) => json!({ "busy": false, "poisoned": true }),
+    };
+
+    let mut listeners: Vec<u16> = LISTENERS.lock().unwrap().keys().copied().collect();
+    listeners.sort_unstable();
+
+    let mut snapshot = json!({
+        "time": Utc::now().to_rfc3339(),
+        "pending": pending(),
+        "inFlight": in_flight(),
+        "activeRequests": nodehttp::active_requests(),
+        "queuedRequests": queued_requests(),
+        "guest": guest,
+        "listeners": listeners,
+    });
+    if let Some((ready, assigned)) = isolation::status() {
+        snapshot["instancePool"] = json!({ "ready": ready, "inUse": assigned });
+    }
+    if let Some(stats) = cache::stats() {
+        snapshot["responseCache"] = stats;
+    }
+    snapshot
+}
+
+// The requests handed to the guest that it hasn't answered, oldest first, for diagnostics and
+// the admin API
+pub(crate) fn pending() -> Vec<Value> {
+    let mut pending: Vec<(usize, Value, u128)> = RESPONSE_MAP
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(id, response)| {
+            let age = response.age().as_millis();
+            let mut parts = response.request_line().split(' ');
+            let entry = json!({
+                "id": id,
+                "request": response.request_line(),
+                "method": parts.next().unwrap_or(""),
+                "path": parts.next().unwrap_or(""),
+                "requestId": response.request_id(),
+                "ageMs": age as u64,
+            });
+            (*id, entry, age)
+        })
+        .collect();
+    pending.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+    pending.into_iter().map(|(_, entry, _)| entry).collect()
+}
+
+/// Writes a [`diagnostics`] snapshot where [`set_diagnostics_file`] says. The server does so
+/// on `SIGUSR1`.
+pub fn dump_diagnostics() {
+    let snapshot = diagnostics();
+    let path = DIAGNOSTICS_FILE.lock().unwrap().clone();
+    match path {
+        Some(path) => {
+            let written = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .and_then(|mut file| writeln!(file, "{}", snapshot));
+            if let Err(err) = written {
+                eprintln!("Failed to write diagnostics to {}: {}", path.display(), err);
+            }
+        }
+        None => eprintln!("{:#}", snapshot),
+    }
+}
@@ -0,0 +1,61 @@
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::sync::Mutex;
+
+use crate::nodehttp::reason_phrase;
+use crate::template;
+
+// The pages answering the errors the server itself responds with
+struct Pages {
+    files: HashMap<u16, String>,
+    template: Option<String>,
+}
+
+static PAGES: Mutex<Option<Pages>> = Mutex::new(None);
+
+/// Answers the errors the server responds with itself, rather than the guest (e.g. `404` for
+/// a missing static file, `500` when the guest fails, `503` when overloaded, `400` for a
+/// request it can't read), with HTML pages instead of a line of text. `pages` are files for
+/// particular statuses; `template` is one for the rest, in which `{{ status }}` and
+/// `{{ reason }}` are filled in as with `http.endTemplate`. Statuses neither covers get a
+/// minimal built-in page. The files are read once, here. No pages and no template (the
+/// default) keep the plain text answers.
+pub fn set_error_pages(pages: &[(u16, &str)], template: Option<&str>) -> io::Result<()> {
+    if pages.is_empty() && template.is_none() {
+        *PAGES.lock().unwrap() = None;
+        return Ok(());
+    }
+    let read = |path: &str| {
+        fs::read_to_string(path)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path, err)))
+    };
+    let files = pages
+        .iter()
+        .map(|(status_code, path)| Ok((*status_code, read(path)?)))
+        .collect::<io::Result<_>>()?;
+    let template = template.map(read).transpose()?;
+    *PAGES.lock().unwrap() = Some(Pages { files, template });
+    Ok(())
+}
+
+// The content type and body answering `status_code`: `text` unless error pages are set
+pub(crate) fn render(status_code: u16, text: &str) -> (&'static str, String) {
+    let pages = PAGES.lock().unwrap();
+    let Some(pages) = pages.as_ref() else {
+        return ("text/plain", text.to_string());
+    };
+    let reason = reason_phrase(status_code);
+    let body = match (pages.files.get(&status_code), &pages.template) {
+        (Some(page), _) => page.clone(),
+        (None, Some(page)) => template::render(
+            page,
+            &json!({ "status": status_code, "reason": reason }),
+        ),
+        (None, None) => format!(
+            "<!DOCTYPE html>\n<title>{status_code} {reason}</title>\n<h1>{status_code} {reason}</h1>\n"
+        ),
+    };
+    ("text/html; charset=utf-8", body)
+}
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+
+// Peers whose `Forwarded` / `X-Forwarded-*` headers are believed
+static TRUSTED_PROXIES: Mutex<Vec<Cidr>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy)]
+struct Cidr {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    fn parse(s: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid trusted proxy {:?}: expected an IP or CIDR", s);
+        let (ip, prefix) = match s.split_once('/') {
+            Some((ip, prefix)) => (ip, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = ip.trim().parse().map_err(|_| invalid())?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Cidr {
+            network: network.to_canonical(),
+            prefix,
+        })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Believes the `Forwarded` and `X-Forwarded-For`/`-Proto`/`-Host` headers of requests from
+/// these peers, given as IPs or CIDR blocks (e.g. `10.0.0.0/8`). Those headers are ignored on
+/// requests from anyone else, who could otherwise claim any address.
+pub fn set_trusted_proxies(proxies: &[&str]) -> Result<(), String> {
+    let proxies = proxies
+        .iter()
+        .map(|proxy| Cidr::parse(proxy))
+        .collect::<Result<Vec<_>, _>>()?;
+    *TRUSTED_PROXIES.lock().unwrap() = proxies;
+    Ok(())
+}
+
+/// Who a request comes from, as far as trusted proxies in front of us tell.
+#[derive(Clone, Debug)]
+pub struct Client {
+    pub ip: IpAddr,
+    // `http` or `https`, as the client connected
+    pub scheme: String,
+    // The host the client asked for, if it said
+    pub host: Option<String>,
+}
+
+// One hop of a forwarding chain, as a proxy recorded it
+#[derive(Default)]
+struct Hop {
+    // `None` for `unknown` and obfuscated identifiers
+    ip: Option<IpAddr>,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+// The client behind `peer`. The chain of proxies is walked from the nearest hop outwards,
+// for as long as the hops are trusted; the first untrusted address is the client.
+pub(crate) fn client(peer: SocketAddr, tls: bool, headers: &HashMap<String, String>) -> Client {
+    let mut client = Client {
+        ip: peer.ip(),
+        scheme: if tls { "https" } else { "http" }.to_string(),
+        host: headers.get("host").cloned(),
+    };
+    let trusted = TRUSTED_PROXIES.lock().unwrap();
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|cidr| cidr.contains(ip));
+    if !is_trusted(peer.ip()) {
+        return client;
+    }
+    let hops = match headers.get("forwarded") {
+        Some(value) => forwarded(value),
+        None => x_forwarded(headers),
+    };
+    for hop in hops.into_iter().rev() {
+        if let Some(proto) = hop.proto {
+            client.scheme = proto;
+        }
+        if let Some(host) = hop.host {
+            client.host = Some(host);
+        }
+        // An unknown address ends the chain; the last trusted hop stands in for the client
+        let Some(ip) = hop.ip else {
+            break;
+        };
+        client.ip = ip;
+        if !is_trusted(ip) {
+            break;
+        }
+    }
+    client
+}
+
+// RFC 7239: `for=192.0.2.43;proto=https, for="[2001:db8::1]:4711"`
+fn forwarded(value: &str) -> Vec<Hop> {
+    let mut hops = Vec::new();
+    for element in split_unquoted(value, ',') {
+        let mut hop = Hop::default();
+        for pair in split_unquoted(element, ';') {
+            let Some((name, value)) = pair.split_once('=') else {
+                continue;
+            };
+            let value = value.trim().trim_matches('"');
+            match name.trim().to_ascii_lowercase().as_str() {
+                "for" => hop.ip = node_ip(value),
+                "proto" => hop.proto = Some(value.to_ascii_lowercase()),
+                "host" => hop.host = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        hops.push(hop);
+    }
+    hops
+}
+
+// The de facto headers. Proto and host are either one value for the whole chain, or one per
+// address in `X-Forwarded-For`.
+fn x_forwarded(headers: &HashMap<String, String>) -> Vec<Hop> {
+    let list = |name: &str| -> Vec<String> {
+        headers.get(name).map_or(Vec::new(), |value| {
+            value.split(',').map(|v| v.trim().to_string()).collect()
+        })
+    };
+    let addresses = list("x-forwarded-for");
+    let protos = list("x-forwarded-proto");
+    let hosts = list("x-forwarded-host");
+    let pick = |values: &[String], i: usize| -> Option<String> {
+        match values.len() {
+            1 => values.first().cloned(),
+            n if n == addresses.len() => values.get(i).cloned(),
+            _ => None,
+        }
+    };
+    if addresses.is_empty() {
+        // Only the scheme or host was forwarded, by the peer itself
+        return vec![Hop {
+            ip: None,
+            proto: protos.last().map(|proto| proto.to_ascii_lowercase()),
+            host: hosts.last().cloned(),
+        }];
+    }
+    addresses
+        .iter()
+        .enumerate()
+        .map(|(i, address)| Hop {
+            ip: node_ip(address),
+            proto: pick(&protos, i).map(|proto| proto.to_ascii_lowercase()),
+            host: pick(&hosts, i),
+        })
+        .collect()
+}
+
+// The address of a node identifier, with or without a port: `192.0.2.43:47011`,
+// `[2001:db8::1]:4711`, or a bare IPv6 address as `X-Forwarded-For` has them
+fn node_ip(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip.to_canonical());
+    }
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next()?.parse::<IpAddr>().ok();
+    }
+    let (ip, _port) = node.rsplit_once(':')?;
+    ip.parse::<IpAddr>().ok().map(|ip| ip.to_canonical())
+}
+
+// Splits on `separator` outside double quotes
+fn split_unquoted(s: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(s[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(s[start..].trim());
+    parts.retain(|part| !part.is_empty());
+    parts
+}
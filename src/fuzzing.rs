@@ -0,0 +1,73 @@
+//! Entry points for fuzzing, enabled by the `fuzzing` feature. The targets live in `fuzz/`:
+//!
+//! ```text
+//! cargo fuzz run request fuzz/corpus/request fuzz/seeds/request
+//! ```
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::nodehttp::{self, Request};
+
+/// Parses `data` as what a client sent on one connection: request after request, until the
+/// data runs out or a request is refused with the returned status code. Reads hand the parser
+/// at most `read_size` bytes at a time, so requests split across reads are parsed too.
+///
+/// Panics if the parser waits for more data than `data` holds, which on a real connection
+/// would be a hang.
+pub fn parse_requests(
+    data: &[u8],
+    read_size: usize,
+    strict_trailers: bool,
+) -> Vec<Result<Request, u16>> {
+    let stream = Trickle {
+        data: data.to_vec(),
+        read: 0,
+        read_size: read_size.max(1),
+    };
+    nodehttp::read_all(Box::new(stream), strict_trailers)
+}
+
+// A connection whose bytes are all there, handed out a few at a time, that ends after them
+struct Trickle {
+    data: Vec<u8>,
+    read: usize,
+    read_size: usize,
+}
+
+impl AsyncRead for Trickle {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let end = self
+            .data
+            .len()
+            .min(self.read + self.read_size.min(buf.remaining()));
+        buf.put_slice(&self.data[self.read..end]);
+        self.read = end;
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Nothing is written while a request is read
+impl AsyncWrite for Trickle {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
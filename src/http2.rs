@@ -0,0 +1,358 @@
+use bytes::Bytes;
+use h2::server::SendResponse;
+use h2::SendStream;
+use http::{HeaderMap, HeaderName, HeaderValue};
+use serde_json::json;
+use std::collections::HashMap;
+use std::future::poll_fn;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::error_page;
+use crate::forwarded;
+use crate::middleware;
+use crate::nodehttp::{
+    self, BodyReader, BoxedStream, ConnectionOptions, Peer, Request, RequestHandler, Response,
+    MAX_BODY_SIZE,
+};
+use crate::rate_limit::RateLimiter;
+use crate::{log, log_with};
+
+// How the connection preface of a client speaking HTTP/2 without TLS ("prior knowledge")
+// starts; up to here it parses as an HTTP/1.1 request head
+pub(crate) const PREFACE_HEAD: &[u8] = b"PRI * HTTP/2.0\r\n\r\n";
+
+static HTTP2: AtomicBool = AtomicBool::new(false);
+
+/// Serves HTTP/2 alongside HTTP/1.1: to TLS clients that offer `h2` over ALPN, and to
+/// plaintext clients that open with the HTTP/2 connection preface.
+pub fn set_http2(enabled: bool) {
+    HTTP2.store(enabled, Ordering::Relaxed);
+}
+
+pub(crate) fn enabled() -> bool {
+    HTTP2.load(Ordering::Relaxed)
+}
+
+// Headers that only mean something on an HTTP/1.1 connection, which HTTP/2 forbids
+fn is_connection_specific(name: &str) -> bool {
+    [
+        "connection",
+        "keep-alive",
+        "proxy-connection",
+        "transfer-encoding",
+        "upgrade",
+    ]
+    .iter()
+    .any(|header| name.eq_ignore_ascii_case(header))
+}
+
+// The sending half of one HTTP/2 stream
+pub(crate) struct Http2Response {
+    respond: SendResponse<Bytes>,
+    // Set once the head went out without ending the stream
+    body: Option<SendStream<Bytes>>,
+}
+
+impl Http2Response {
+    pub(crate) fn send_head(
+        &mut self,
+        status_code: u16,
+        headers: &[(String, String)],
+        has_body: bool,
+    ) -> io::Result<()> {
+        let mut response = http::Response::builder().status(status_code);
+        for (key, value) in headers {
+            if is_connection_specific(key) {
+                continue;
+            }
+            match (
+                HeaderName::from_bytes(key.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                (Ok(key), Ok(value)) => response = response.header(key, value),
+                _ => log(1, &format!("Dropped invalid header {:?}", key)),
+            }
+        }
+        let response = response.body(()).map_err(io::Error::other)?;
+        let body = self
+            .respond
+            .send_response(response, !has_body)
+            .map_err(io::Error::other)?;
+        self.body = has_body.then_some(body);
+        Ok(())
+    }
+
+    // Ready once the client reset the stream, or the connection is gone
+    pub(crate) fn poll_reset(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let reset = match self.body.as_mut() {
+            Some(body) => body.poll_reset(cx),
+            None => self.respond.poll_reset(cx),
+        };
+        reset.map(|_| ())
+    }
+
+    // Sends `data` as the client's flow control window allows
+    pub(crate) async fn send_data(&mut self, data: &[u8]) -> io::Result<()> {
+        let Some(body) = self.body.as_mut() else {
+            return Ok(());
+        };
+        let mut data = Bytes::copy_from_slice(data);
+        while !data.is_empty() {
+            body.reserve_capacity(data.len());
+            let granted = poll_fn(|cx| body.poll_capacity(cx))
+                .await
+                .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?
+                .map_err(io::Error::other)?;
+            let chunk = data.split_to(granted.min(data.len()));
+            body.send_data(chunk, false).map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+
+    // Ends the stream, with trailer fields if there are any
+    pub(crate) fn send_end(&mut self, trailers: &[(String, String)]) -> io::Result<()> {
+        let Some(mut body) = self.body.take() else {
+            return Ok(());
+        };
+        if trailers.is_empty() {
+            return body.send_data(Bytes::new(), true).map_err(io::Error::other);
+        }
+        let mut fields = HeaderMap::new();
+        for (key, value) in trailers {
+            match (
+                HeaderName::from_bytes(key.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                (Ok(key), Ok(value)) => {
+                    fields.append(key, value);
+                }
+                _ => log(1, &format!("Dropped invalid trailer {:?}", key)),
+            }
+        }
+        body.send_trailers(fields).map_err(io::Error::other)
+    }
+}
+
+// Serves the streams of one HTTP/2 connection, each as its own request
+pub(crate) async fn serve(
+    stream: BoxedStream,
+    peer: Peer,
+    rate_limit: Option<RateLimiter>,
+    options: ConnectionOptions,
+    handler: RequestHandler,
+) -> io::Result<()> {
+    let mut connection = h2::server::handshake(stream)
+        .await
+        .map_err(io::Error::other)?;
+    while let Some(accepted) = connection.accept().await {
+        let (request, respond) = accepted.map_err(io::Error::other)?;
+        let peer = peer.clone();
+        let rate_limit = rate_limit.clone();
+        tokio::spawn(async move {
+            if let Err(e) =
+                handle_stream(request, respond, &peer, rate_limit, options, handler).await
+            {
+                log_with(
+                    2,
+                    &format!("Stream from {} closed: {}", peer.addr, e),
+                    &[("remoteAddress", json!(peer.addr.ip().to_string()))],
+                );
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn handle_stream(
+    request: http::Request<h2::RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    peer: &Peer,
+    rate_limit: Option<RateLimiter>,
+    options: ConnectionOptions,
+    handler: RequestHandler,
+) -> io::Result<()> {
+    let (parts, mut recv) = request.into_parts();
+    if parts.headers.len() > options.max_headers {
+        return reject(&mut respond, 431, &[]);
+    }
+    let stream_body = options.stream_body;
+
+    // Repeated fields are folded into one, as HTTP/1.1 clients would send them
+    let mut headers: HashMap<String, String> = HashMap::new();
+    for (key, value) in &parts.headers {
+        let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+        let separator = if key == http::header::COOKIE {
+            "; "
+        } else {
+            ", "
+        };
+        headers
+            .entry(key.as_str().to_string())
+            .and_modify(|existing| {
+                existing.push_str(separator);
+                existing.push_str(&value);
+            })
+            .or_insert(value);
+    }
+    // HTTP/2 carries the host in the `:authority` pseudo-header
+    if let Some(authority) = parts.uri.authority() {
+        headers
+            .entry("host".to_string())
+            .or_insert_with(|| authority.to_string());
+    }
+
+    let client = forwarded::client(peer.addr, options.tls, &headers);
+    if let Some(Err(wait)) = rate_limit.as_ref().map(|l| l.check(client.ip)) {
+        let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+        return reject(
+            &mut respond,
+            429,
+            &[("retry-after", retry_after.to_string())],
+        );
+    }
+
+    let mut body = Vec::new();
+    let mut trailers = HashMap::new();
+    if !stream_body {
+        while let Some(data) = recv.data().await {
+            let data = data.map_err(io::Error::other)?;
+            let _ = recv.flow_control().release_capacity(data.len());
+            if body.len() + data.len() > MAX_BODY_SIZE {
+                return reject(&mut respond, 413, &[]);
+            }
+            body.extend_from_slice(&data);
+        }
+        if let Some(fields) = recv.trailers().await.map_err(io::Error::other)? {
+            let announced = nodehttp::announced_trailers(&headers, options.strict_trailers);
+            for (key, value) in &fields {
+                if announced
+                    .as_ref()
+                    .is_some_and(|names| !names.iter().any(|name| name == key.as_str()))
+                {
+                    return reject(&mut respond, 400, &[]);
+                }
+                let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+                trailers.insert(key.as_str().to_string(), value);
+            }
+        }
+    }
+
+    let path = parts
+        .uri
+        .path_and_query()
+        .map_or("/".to_string(), |path| path.to_string());
+    let id = nodehttp::request_id(&headers);
+    let request = Request {
+        method: parts.method.to_string(),
+        path,
+        version: "HTTP/2.0".to_string(),
+        headers,
+        body,
+        trailers,
+        id,
+        client_identity: peer.client_identity.clone(),
+        server_name: peer.server_name.clone(),
+        client,
+        connection_id: peer.connection_id,
+    };
+    let mut response = Response::http2(
+        &request,
+        Http2Response {
+            respond,
+            body: None,
+        },
+    );
+    // An empty body ends the stream with the head
+    if stream_body && !recv.is_end_stream() {
+        response = response.with_body(BodyReader::http2(recv));
+    }
+    middleware::dispatch(&request, response, handler)
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))
+}
+
+// Answers a stream with an empty response, or an error page if there are any (see
+// `set_error_pages`)
+fn reject(
+    respond: &mut SendResponse<Bytes>,
+    status_code: u16,
+    headers: &[(&str, String)],
+) -> io::Result<()> {
+    log(
+        2,
+        &format!(
+            "Rejected request: {} {}",
+            status_code,
+            nodehttp::reason_phrase(status_code)
+        ),
+    );
+    let (content_type, body) = error_page::render(status_code, "");
+    let mut response = http::Response::builder().status(status_code);
+    if !body.is_empty() {
+        response = response.header("content-type", content_type);
+    }
+    for (key, value) in headers {
+        response = response.header(*key, value);
+    }
+    let response = response.body(()).map_err(io::Error::other)?;
+    let mut stream = respond
+        .send_response(response, body.is_empty())
+        .map_err(io::Error::other)?;
+    if !body.is_empty() {
+        stream
+            .send_data(Bytes::from(body), true)
+            .map_err(io::Error::other)?;
+    }
+    Ok(())
+}
+
+// Replays bytes already read off a connection before reading the rest of it, so a plaintext
+// connection can be handed to HTTP/2 after its preface was read as an HTTP/1.1 request line
+pub(crate) struct Rewind {
+    prefix: Vec<u8>,
+    inner: BoxedStream,
+}
+
+impl Rewind {
+    pub(crate) fn new(prefix: Vec<u8>, inner: BoxedStream) -> Self {
+        Rewind { prefix, inner }
+    }
+}
+
+impl AsyncRead for Rewind {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let n = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix[..n]);
+            self.prefix.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Rewind {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
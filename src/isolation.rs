@@ -0,0 +1,183 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use wasmtime::Store;
+
+use crate::runtime::Loaded;
+use crate::{configure, log, Guest};
+
+static POOL_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+// An instance answering one request, shared by the tasks sending it events
+pub(crate) type Instance = Arc<Mutex<(Store<()>, Guest)>>;
+
+struct Pool {
+    loaded: Arc<Loaded>,
+    // Instances that ran `configure` and `_start`, waiting for a request
+    ready: Vec<(Store<()>, Guest)>,
+    // Instances being started to take their place
+    starting: usize,
+}
+
+static POOL: Mutex<Option<Pool>> = Mutex::new(None);
+
+lazy_static! {
+    // Instances by the id of the request they answer
+    static ref ASSIGNED: Mutex<HashMap<usize, Instance>> = Mutex::new(HashMap::new());
+}
+
+thread_local! {
+    // Set while a pooled instance runs `configure` and `_start` on this thread
+    static STARTING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Answers every request with a fresh instance of the guest, so nothing a request leaves
+/// behind in the guest's memory is seen by the next, and requests no longer wait for each
+/// other. `pool_size` instances of the compiled module are kept ready, each having run
+/// `configure` and `_start`; when they're all taken, one is started for the request. 0 (the
+/// default) answers every request with the one shared instance.
+///
+/// An instance gets the events of its request (`http.request`, `http.requestBody`,
+/// `http.requestBodyEnd`, `http.timeout`, `http.aborted`, and `socket.data`/`socket.close`
+/// once hijacked) and is dropped when the request is done; everything else goes to the shared
+/// instance. What pooled instances send while starting, such as `http.listen` or
+/// `route.register`, is ignored, as the shared instance has already done it. An instance that
+/// traps is dropped, and its request answered with 500. Takes effect the next time the guest
+/// is loaded.
+///
+/// A lighter alternative for guests that can clean up after themselves: a core module
+/// exporting `reset` has it called after each call in which it ended a response
+/// (`http.end` or `http.endTemplate`), to clear its per-request globals. That's cooperative,
+/// not enforced: whatever the guest doesn't clear is still seen by the next request.
+pub fn set_isolation_pool(pool_size: usize) {
+    POOL_SIZE.store(pool_size, Ordering::Relaxed);
+}
+
+// Whether requests get instances of their own
+pub(crate) fn enabled() -> bool {
+    POOL.lock().unwrap().is_some()
+}
+
+// Whether events the guest sends come from a pooled instance that's starting
+pub(crate) fn starting() -> bool {
+    STARTING.get()
+}
+
+// Starts pooling instances of a newly loaded guest, in place of the old one's
+pub(crate) fn install(loaded: Loaded) {
+    let mut pool = POOL.lock().unwrap();
+    if POOL_SIZE.load(Ordering::Relaxed) == 0 {
+        *pool = None;
+        return;
+    }
+    *pool = Some(Pool {
+        loaded: Arc::new(loaded),
+        ready: Vec::new(),
+        starting: 0,
+    });
+    drop(pool);
+    refill();
+}
+
+// Starts as many instances in the background as the pool is short of
+fn refill() {
+    let mut pool = POOL.lock().unwrap();
+    let Some(pool) = pool.as_mut() else {
+        return;
+    };
+    let missing = POOL_SIZE
+        .load(Ordering::Relaxed)
+        .saturating_sub(pool.ready.len() + pool.starting);
+    for _ in 0..missing {
+        pool.starting += 1;
+        let loaded = Arc::clone(&pool.loaded);
+        tokio::task::spawn_blocking(move || {
+            let started = start(&loaded);
+            let mut pool = POOL.lock().unwrap();
+            // Unless the guest was replaced meanwhile
+            let Some(pool) = pool
+                .as_mut()
+                .filter(|pool| Arc::ptr_eq(&pool.loaded, &loaded))
+            else {
+                return;
+            };
+            pool.starting -= 1;
+            match started {
+                Ok(instance) => pool.ready.push(instance),
+                Err(err) => log(1, &err),
+            }
+        });
+    }
+}
+
+// A new instance that ran `configure` and `_start`
+fn start(loaded: &Loaded) -> Result<(Store<()>, Guest), String> {
+    let (mut store, guest) = loaded.instantiate()?;
+    STARTING.set(true);
+    let started =
+        configure(&mut store, &guest).and_then(|()| guest.start(&mut store).unwrap_or(Ok(())));
+    STARTING.set(false);
+    started.map_err(|err| format!("Failed to start pooled instance: {}", err))?;
+    Ok((store, guest))
+}
+
+// Gives request `id` an instance of its own, if requests get one. It's the request's until the
+// returned assignment is dropped.
+pub(crate) async fn assign(id: usize) -> Result<Option<Assignment>, String> {
+    let taken = {
+        let mut pool = POOL.lock().unwrap();
+        let Some(pool) = pool.as_mut() else {
+            return Ok(None);
+        };
+        pool.ready.pop().ok_or_else(|| Arc::clone(&pool.loaded))
+    };
+    refill();
+    let instance = match taken {
+        Ok(instance) => instance,
+        // All taken: this request waits for one of its own, started off the async workers
+        Err(loaded) => {
+            log(
+                2,
+                &format!("Instance pool empty, starting one for request {}", id),
+            );
+            tokio::task::spawn_blocking(move || start(&loaded))
+                .await
+                .map_err(|err| format!("Failed to start instance: {}", err))??
+        }
+    };
+    Ok(Some(attach(id, Arc::new(Mutex::new(instance)))))
+}
+
+// Makes `instance` the one answering request `id`
+pub(crate) fn attach(id: usize, instance: Instance) -> Assignment {
+    ASSIGNED.lock().unwrap().insert(id, instance);
+    Assignment { id }
+}
+
+// The instance answering request `id`
+pub(crate) fn assigned(id: usize) -> Option<Instance> {
+    ASSIGNED.lock().unwrap().get(&id).cloned()
+}
+
+// Drops the instance of request `id` before the request is done, e.g. after it trapped
+pub(crate) fn discard(id: usize) {
+    ASSIGNED.lock().unwrap().remove(&id);
+}
+
+// Request `id`'s hold on its instance; held by its response, or by its socket once hijacked
+pub(crate) struct Assignment {
+    id: usize,
+}
+
+impl Drop for Assignment {
+    fn drop(&mut self) {
+        discard(self.id);
+    }
+}
+
+// Instances ready in the pool and answering requests, if requests get their own
+pub(crate) fn status() -> Option<(usize, usize)> {
+    let ready = POOL.lock().unwrap().as_ref()?.ready.len();
+    Some((ready, ASSIGNED.lock().unwrap().len()))
+}
@@ -0,0 +1,2513 @@
+mod access_log;
+mod admin;
+pub mod bench;
+mod cache;
+mod component;
+mod compression;
+mod config;
+mod connection;
+mod diagnostics;
+mod error_page;
+mod forwarded;
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod fuzzing;
+mod http2;
+mod isolation;
+mod middleware;
+mod multipart;
+mod nodehttp;
+#[cfg(feature = "otel")]
+mod otel;
+mod privileges;
+mod rate_limit;
+mod router;
+mod rpc;
+mod runtime;
+mod schema;
+mod socket;
+mod sse;
+mod static_file;
+mod streaming;
+mod template;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod tls;
+mod trace;
+mod warmup;
+
+use anyhow::anyhow;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{SecondsFormat, Utc};
+use nodehttp::BodyReader;
+use rate_limit::RateLimiter;
+
+use serde_json::json;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use wasmtime::*;
+
+pub use access_log::set_access_log;
+pub use admin::start_admin;
+pub use cache::set_response_cache;
+pub use compression::set_compression;
+pub use config::{Config, TlsConfig, TlsHost, WarmupRequest};
+pub use connection::{set_connection_events, set_max_connections, ConnectionOverflow};
+pub use diagnostics::{diagnostics, dump_diagnostics, set_diagnostics_file};
+pub use error_page::set_error_pages;
+pub use forwarded::set_trusted_proxies;
+pub use http2::set_http2;
+pub use isolation::set_isolation_pool;
+pub use middleware::{MiddlewareFuture, Next};
+pub use nodehttp::{set_content_length_threshold, set_server_header, IpStack, Request, Response};
+#[cfg(feature = "otel")]
+pub use otel::set_otlp_exporter;
+pub use privileges::set_run_as;
+pub use rpc::set_json_rpc;
+pub use runtime::{
+    flush_guest_output, set_compiler, set_module_cache, set_shutdown_timeout, Runtime,
+};
+pub use static_file::set_strong_etags;
+pub use tls::{set_tls, set_tls_hosts};
+pub use trace::set_trace_bodies;
+pub use warmup::set_warmup;
+pub use wasmtime::{Caller, OptLevel, Strategy, Val, ValType};
+
+static LOG_LEVEL: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_log_level(level: usize) {
+    LOG_LEVEL.store(level, Ordering::Relaxed);
+}
+
+/// How log lines are written, see [`set_log_format`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Just the message (the default)
+    Text,
+    /// One JSON object per line, for log shippers
+    Json,
+}
+
+static LOG_JSON: AtomicBool = AtomicBool::new(false);
+
+/// Writes log lines as text, or as JSON objects with `timestamp`, `level` (`info` for level 1,
+/// `debug` for 2, `trace` above), `message`, and `requestId` and `remoteAddress` when the
+/// message is about a request or connection.
+pub fn set_log_format(format: LogFormat) {
+    LOG_JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+pub(crate) fn log(level: usize, message: &str) {
+    log_with(level, message, &[]);
+}
+
+// Like `log`, with fields saying what the message is about; only JSON lines carry them
+pub(crate) fn log_with(level: usize, message: &str, fields: &[(&str, Value)]) {
+    if level > LOG_LEVEL.load(Ordering::Relaxed) {
+        return;
+    }
+    if !LOG_JSON.load(Ordering::Relaxed) {
+        println!("{}", message);
+        return;
+    }
+    let mut line = serde_json::Map::new();
+    line.insert(
+        "timestamp".to_string(),
+        json!(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)),
+    );
+    let level = match level {
+        0 | 1 => "info",
+        2 => "debug",
+        _ => "trace",
+    };
+    line.insert("level".to_string(), json!(level));
+    line.insert("message".to_string(), json!(message));
+    for (key, value) in fields {
+        line.insert(key.to_string(), value.clone());
+    }
+    println!("{}", Value::Object(line));
+}
+
+#[macro_use]
+extern crate lazy_static;
+
+lazy_static! {
+    static ref WASM: Mutex<Option<(Store<()>, Guest)>> = Mutex::new(None);
+    static ref RESPONSE_MAP: Arc<Mutex<HashMap<usize, Response>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    static ref NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    // Running (or starting) listeners by port, with the signal that stops each one
+    static ref LISTENERS: Mutex<HashMap<u16, oneshot::Sender<()>>> = Mutex::new(HashMap::new());
+    // While a reloaded guest starts up, the ports it listens on; those it no longer wants are
+    // closed once it's done
+    static ref RELISTENED: Mutex<Option<HashSet<u16>>> = Mutex::new(None);
+    static ref DEFAULT_CONTENT_TYPE: Mutex<String> =
+        Mutex::new("text/plain; charset=utf-8".to_string());
+    // Keys the tokens of `request_token`, fresh for every run
+    static ref TOKEN_KEYS: RandomState = RandomState::new();
+}
+
+static PORT_OVERRIDE: Mutex<Option<u16>> = Mutex::new(None);
+
+/// Listens on `port` at startup, whatever port the guest asks for.
+pub fn set_port(port: u16) {
+    *PORT_OVERRIDE.lock().unwrap() = Some(port);
+}
+
+pub(crate) fn port_override() -> Option<u16> {
+    *PORT_OVERRIDE.lock().unwrap()
+}
+
+static IP_STACK: Mutex<IpStack> = Mutex::new(IpStack::Dual);
+
+// Environment variables with this prefix are passed to the guest's `configure`
+const GUEST_ENV_PREFIX: &str = "MOCKET_";
+
+// What the runtime tells the guest about itself through `configure`
+fn runtime_config() -> Value {
+    let env: serde_json::Map<String, Value> = std::env::vars()
+        .filter(|(key, _)| key.starts_with(GUEST_ENV_PREFIX))
+        .map(|(key, value)| (key, Value::String(value)))
+        .collect();
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "port": port_override(),
+        "logLevel": LOG_LEVEL.load(Ordering::Relaxed),
+        "tls": tls::enabled(),
+        "http2": http2::enabled(),
+        "readyTimeout": ready_timeout().map(|timeout| timeout.as_secs_f64()),
+        "idleTimeout": idle_timeout().map(|timeout| timeout.as_secs_f64()),
+        "limits": {
+            "maxBodySize": nodehttp::MAX_BODY_SIZE,
+            "maxHeaderSize": nodehttp::MAX_HEADER_SIZE,
+            "maxResponseBody": match MAX_RESPONSE_BODY.load(Ordering::Relaxed) {
+                0 => Value::Null,
+                max => json!(max),
+            },
+        },
+        "env": env,
+    })
+}
+
+// An instantiated guest: a core module talking through the `__h` imports and `h_rd`/`h_re`
+// exports, or a component of the `mocket` world in `wit/mocket.wit`
+pub(crate) enum Guest {
+    Module(Instance),
+    Component(wasmtime::component::Instance),
+}
+
+impl Guest {
+    // Hands the guest one `[type, data]` event
+    fn receive(&self, store: &mut Store<()>, message: &str) -> Result<()> {
+        match self {
+            Guest::Module(instance) => {
+                write_message(store, instance, message);
+                h_re(store, instance)
+            }
+            Guest::Component(instance) => component::receive(store, instance, message),
+        }
+    }
+
+    // Runs the guest's `reset` export, if it has one, for it to clear what the requests it
+    // just answered left in its globals. This is cooperative: what's cleared is up to the
+    // guest, and nothing stops state from leaking if it doesn't, unlike with
+    // `set_isolation_pool`. Only core modules have one; `None` otherwise.
+    fn reset(&self, store: &mut Store<()>) -> Option<Result<()>> {
+        match self {
+            Guest::Module(instance) => {
+                let reset = instance
+                    .get_typed_func::<(), ()>(&mut *store, "reset")
+                    .ok()?;
+                Some(reset.call(&mut *store, ()))
+            }
+            Guest::Component(_) => None,
+        }
+    }
+
+    // Runs the guest's `_start` (a component's `start`); `None` if it has none
+    pub(crate) fn start(&self, store: &mut Store<()>) -> Option<Result<()>> {
+        match self {
+            Guest::Module(instance) => {
+                let start = instance
+                    .get_typed_func::<(), ()>(&mut *store, "_start")
+                    .ok()?;
+                Some(start.call(&mut *store, ()))
+            }
+            Guest::Component(instance) => component::start(store, instance),
+        }
+    }
+}
+
+// Calls the guest's `configure` export, if it has one, with the runtime's settings (see
+// `runtime_config`) as JSON. A core module reads them from `h_rd` before `_start` runs.
+pub(crate) fn configure(store: &mut Store<()>, guest: &Guest) -> Result<()> {
+    let configured = match guest {
+        Guest::Module(instance) => {
+            match instance.get_typed_func::<(), ()>(&mut *store, "configure") {
+                Ok(configure) => {
+                    write_message(store, instance, &runtime_config().to_string());
+                    configure.call(&mut *store, ())?;
+                    true
+                }
+                Err(_) => false,
+            }
+        }
+        Guest::Component(instance) => {
+            component::configure(store, instance, &runtime_config().to_string())?
+        }
+    };
+    if !configured {
+        log(2, "No 'configure' function found");
+    }
+    Ok(())
+}
+
+/// Chooses whether listeners accept IPv4, IPv6 or (the default) both.
+pub fn set_ip_stack(stack: IpStack) {
+    *IP_STACK.lock().unwrap() = stack;
+}
+
+static INHERITED_LISTENER: Mutex<Option<std::net::TcpListener>> = Mutex::new(None);
+
+/// Serves on an already bound, listening socket instead of binding a port, e.g. one passed
+/// in by systemd socket activation. Like [`set_port`], this listens at startup on the
+/// socket's port whatever port the guest asks for.
+#[cfg(unix)]
+pub fn set_listen_fd(fd: std::os::unix::io::RawFd) -> std::io::Result<()> {
+    use std::os::unix::io::FromRawFd;
+
+    let mut accepting: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: both pointers are valid for the size we pass; a bad fd only makes this fail
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            &mut accepting as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    if accepting == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("fd {} is not a listening socket", fd),
+        ));
+    }
+
+    // SAFETY: the fd is an open socket handed to us, and nothing else in the process uses it
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    set_listener(listener)
+}
+
+// Serves on `listener`, bound by someone else, at startup
+pub(crate) fn set_listener(listener: std::net::TcpListener) -> std::io::Result<()> {
+    let port = listener.local_addr()?.port();
+    set_port(port);
+    *INHERITED_LISTENER.lock().unwrap() = Some(listener);
+    Ok(())
+}
+
+// For error responses with no headers but their content type
+pub(crate) const NO_HEADERS: [(&str, &str); 0] = [];
+
+static REJECT_MALFORMED_JSON: AtomicBool = AtomicBool::new(false);
+
+/// Replies `400` to malformed JSON or form-data bodies instead of passing them on with
+/// `bodyParseError`.
+pub fn set_reject_malformed_json(reject: bool) {
+    REJECT_MALFORMED_JSON.store(reject, Ordering::Relaxed);
+}
+
+// The methods requests may have unless `set_allowed_methods` says otherwise
+const DEFAULT_METHODS: [&str; 9] = [
+    "GET", "POST", "PUT", "DELETE", "HEAD", "OPTIONS", "CONNECT", "TRACE", "PATCH",
+];
+
+static ALLOWED_METHODS: Mutex<Option<Vec<String>>> = Mutex::new(None);
+
+/// Answers requests with methods other than `methods` (case-sensitive, like methods are) with
+/// `405` and an `Allow` header listing them, before the guest sees them; methods beyond the
+/// standard ones may be let through too. `None` (the default) allows `GET`, `POST`, `PUT`,
+/// `DELETE`, `HEAD`, `OPTIONS`, `CONNECT`, `TRACE` and `PATCH`.
+pub fn set_allowed_methods(methods: Option<&[&str]>) -> Result<(), String> {
+    let methods = match methods {
+        Some([]) => return Err("No methods allowed".to_string()),
+        Some(methods) => methods,
+        None => {
+            *ALLOWED_METHODS.lock().unwrap() = None;
+            return Ok(());
+        }
+    };
+    if let Some(method) = methods
+        .iter()
+        .find(|method| !nodehttp::is_valid_header(method, ""))
+    {
+        return Err(format!("Invalid method {:?}", method));
+    }
+    *ALLOWED_METHODS.lock().unwrap() = Some(methods.iter().map(|m| m.to_string()).collect());
+    Ok(())
+}
+
+// Whether requests may have `method`, or else the `Allow` header listing those that may
+fn method_allowed(method: &str) -> Result<(), String> {
+    match ALLOWED_METHODS.lock().unwrap().as_deref() {
+        Some(allowed) if allowed.iter().any(|m| m == method) => Ok(()),
+        Some(allowed) => Err(allowed.join(", ")),
+        None if DEFAULT_METHODS.contains(&method) => Ok(()),
+        None => Err(DEFAULT_METHODS.join(", ")),
+    }
+}
+
+static METHOD_OVERRIDE: AtomicBool = AtomicBool::new(false);
+
+// What a `POST` may stand in for, see `set_method_override`
+const OVERRIDABLE_METHODS: [&str; 3] = ["PUT", "PATCH", "DELETE"];
+
+/// Lets `POST` requests stand in for `PUT`, `PATCH` or `DELETE`, for clients such as HTML
+/// forms that can only send `GET` and `POST`: the method named by an `X-HTTP-Method-Override`
+/// header, or else by a `_method` field of the query or of an
+/// `application/x-www-form-urlencoded` body, is the one the request is handled and routed
+/// with, and the guest gets the original as `originalMethod`. Overrides to other methods are
+/// ignored.
+pub fn set_method_override(enabled: bool) {
+    METHOD_OVERRIDE.store(enabled, Ordering::Relaxed);
+}
+
+// The method a `POST` stands in for, if it asks to and may, see `set_method_override`
+fn method_override(req: &Request) -> Option<String> {
+    if req.method != "POST" || !METHOD_OVERRIDE.load(Ordering::Relaxed) {
+        return None;
+    }
+    let field = |fields: &str| {
+        fields
+            .split('&')
+            .find_map(|field| field.strip_prefix("_method="))
+            .map(str::to_string)
+    };
+    let form = || {
+        let content_type = req.headers.get("content-type")?;
+        let media_type = content_type.split(';').next().unwrap_or("").trim();
+        if !media_type.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+            return None;
+        }
+        std::str::from_utf8(&req.body).ok().and_then(field)
+    };
+    let requested = req
+        .headers
+        .get("x-http-method-override")
+        .cloned()
+        .or_else(|| req.path.split_once('?').and_then(|(_, query)| field(query)))
+        .or_else(form)?;
+    let method = requested.trim().to_ascii_uppercase();
+    if !OVERRIDABLE_METHODS.contains(&method.as_str()) {
+        log(2, &format!("Ignored override of POST with {:?}", requested));
+        return None;
+    }
+    Some(method)
+}
+
+static MAX_QUEUED_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+static QUEUED_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Answers `503` with `Retry-After: 1` to requests arriving while `max` others are already
+/// waiting for the guest to take their `http.request` event or are in it, rather than queueing
+/// them behind the rest. 0 (the default) queues every request.
+pub fn set_max_queued_requests(max: usize) {
+    MAX_QUEUED_REQUESTS.store(max, Ordering::Relaxed);
+}
+
+static MAX_RESPONSE_BODY: AtomicUsize = AtomicUsize::new(0);
+static TRUNCATE_RESPONSES: AtomicBool = AtomicBool::new(false);
+
+/// Caps the body the guest may send in a response at `max_bytes`, so a runaway guest can't
+/// have the host hold an enormous one. A body over it in `http.end` is answered with `500`
+/// instead, or, if `truncate`, sent cut off at `max_bytes`; either is logged. Streamed with
+/// `http.write`, the bytes are counted as they come: past the limit the connection is closed
+/// mid-response (the guest gets `http.aborted`), or, if `truncate`, the rest is dropped and
+/// the response ends at the limit. Files sent from disk aren't counted. 0 (the default) is
+/// no limit.
+pub fn set_max_response_body(max_bytes: usize, truncate: bool) {
+    MAX_RESPONSE_BODY.store(max_bytes, Ordering::Relaxed);
+    TRUNCATE_RESPONSES.store(truncate, Ordering::Relaxed);
+}
+
+// How many of `len` more bytes of the body of response `id`, which has `sent` already, may
+// go out under `set_max_response_body`; `None` when the response has to fail instead
+pub(crate) fn response_body_room(id: usize, sent: usize, len: usize) -> Option<usize> {
+    let max = MAX_RESPONSE_BODY.load(Ordering::Relaxed);
+    if max == 0 || sent + len <= max {
+        return Some(len);
+    }
+    if !TRUNCATE_RESPONSES.load(Ordering::Relaxed) {
+        log(
+            1,
+            &format!(
+                "Response {} body exceeds the limit of {} bytes, failing it",
+                id, max
+            ),
+        );
+        return None;
+    }
+    log(
+        1,
+        &format!(
+            "Response {} body truncated at the limit of {} bytes",
+            id, max
+        ),
+    );
+    Some(max.saturating_sub(sent))
+}
+
+static RESPONSE_BODY_WARNING: AtomicUsize = AtomicUsize::new(0);
+
+// Oversized responses are logged at most this often, see `set_response_body_warning`
+const BODY_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+// When the last oversized response was logged, and how many have gone unlogged since
+static BODY_WARNINGS: Mutex<(Option<Instant>, usize)> = Mutex::new((None, 0));
+
+/// Logs a warning naming the request and the size when the guest answers with a body over
+/// `threshold_bytes` in `http.end`, which is often a sign of a bug, such as a handler sending
+/// a whole table back. The response goes out all the same; see `set_max_response_body` to
+/// stop it instead. At most one warning is logged every 10 seconds, and the next one says
+/// how many were left out meanwhile. 0 (the default) never warns.
+pub fn set_response_body_warning(threshold_bytes: usize) {
+    RESPONSE_BODY_WARNING.store(threshold_bytes, Ordering::Relaxed);
+}
+
+// Warns that `response` is getting a body of `len` bytes if that's over the threshold of
+// `set_response_body_warning`, unless another warning went out just before
+fn warn_response_body(response: &Response, len: usize) {
+    let threshold = RESPONSE_BODY_WARNING.load(Ordering::Relaxed);
+    if threshold == 0 || len <= threshold {
+        return;
+    }
+    let skipped = {
+        let mut warnings = BODY_WARNINGS.lock().unwrap();
+        let (last, skipped) = &mut *warnings;
+        if last.is_some_and(|last| last.elapsed() < BODY_WARNING_INTERVAL) {
+            *skipped += 1;
+            return;
+        }
+        *last = Some(Instant::now());
+        std::mem::take(skipped)
+    };
+    let mut message = format!(
+        "Large response body: {} bytes for {}, over the warning threshold of {} bytes",
+        len,
+        response.request_line(),
+        threshold
+    );
+    if skipped > 0 {
+        write!(message, " ({} more since the last warning)", skipped).unwrap();
+    }
+    response.log(1, &message);
+}
+
+/// How many requests are waiting for the guest to take their `http.request` event or are in it.
+pub fn queued_requests() -> usize {
+    QUEUED_REQUESTS.load(Ordering::Relaxed)
+}
+
+// A request's place in the guest's queue, given up when dropped
+struct Queued;
+
+impl Queued {
+    // A place in the queue, unless it's full
+    fn enter() -> Option<Queued> {
+        let max = MAX_QUEUED_REQUESTS.load(Ordering::Relaxed);
+        QUEUED_REQUESTS
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                (max == 0 || queued < max).then_some(queued + 1)
+            })
+            .ok()
+            .map(|_| Queued)
+    }
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        QUEUED_REQUESTS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+static PROFILE: AtomicBool = AtomicBool::new(false);
+
+/// Meters the guest with fuel and logs, at log level 2, the fuel each request consumed and the
+/// size of the guest's linear memory afterwards.
+pub fn set_profile(profile: bool) {
+    PROFILE.store(profile, Ordering::Relaxed);
+}
+
+pub(crate) fn profiling() -> bool {
+    PROFILE.load(Ordering::Relaxed)
+}
+
+static READY: AtomicBool = AtomicBool::new(true);
+static SENT_READY: AtomicBool = AtomicBool::new(false);
+static READY_TIMEOUT: Mutex<Option<Duration>> = Mutex::new(None);
+
+/// Holds off requests until the guest sends `runtime.ready`, answering them with `503` in the
+/// meantime. The process exits if the guest isn't ready within `timeout` of starting.
+pub fn set_ready_timeout(timeout: Duration) {
+    *READY_TIMEOUT.lock().unwrap() = Some(timeout);
+}
+
+pub(crate) fn ready_timeout() -> Option<Duration> {
+    *READY_TIMEOUT.lock().unwrap()
+}
+
+static IDLE_TIMEOUT: Mutex<Option<Duration>> = Mutex::new(None);
+
+/// Exits once no request has been in flight for `timeout`, e.g. so a scale-to-zero platform
+/// can stop the instance.
+pub fn set_idle_timeout(timeout: Duration) {
+    *IDLE_TIMEOUT.lock().unwrap() = Some(timeout);
+}
+
+pub(crate) fn idle_timeout() -> Option<Duration> {
+    *IDLE_TIMEOUT.lock().unwrap()
+}
+
+// Whether the guest sent `runtime.ready` since this was last called, e.g. during the
+// `_start` of a reloaded guest
+pub(crate) fn take_sent_ready() -> bool {
+    SENT_READY.swap(false, Ordering::SeqCst)
+}
+
+pub(crate) fn set_ready(ready: bool) {
+    READY.store(ready, Ordering::SeqCst);
+}
+
+pub(crate) fn is_ready() -> bool {
+    READY.load(Ordering::SeqCst)
+}
+
+/// Sets the `Content-Type` sent with string bodies when the guest doesn't provide one.
+pub fn set_default_content_type(content_type: &str) {
+    *DEFAULT_CONTENT_TYPE.lock().unwrap() = content_type.to_string();
+}
+
+fn h_rd<T>(store: &mut Store<T>, instance: &Instance, ch: i32) -> Result<()> {
+    let start_func = instance
+        .get_func(store.as_context_mut(), "h_rd")
+        .ok_or_else(|| anyhow!("h_rd function not found"))?;
+    start_func.call(store.as_context_mut(), &[wasmtime::Val::I32(ch)], &mut [])?;
+
+    Ok(())
+}
+
+fn h_re<T>(store: &mut Store<T>, instance: &Instance) -> Result<()> {
+    let start_func = instance
+        .get_func(store.as_context_mut(), "h_re")
+        .ok_or_else(|| anyhow!("h_re function not found"))?;
+    start_func.call(store.as_context_mut(), &[], &mut [])?;
+
+    Ok(())
+}
+
+// Feeds `message` to the guest's `h_rd` as UTF-16 code units, high byte first
+fn write_message<T>(store: &mut Store<T>, instance: &Instance, message: &str) {
+    for word in message.encode_utf16() {
+        let _ = h_rd(store, instance, (word >> 8) as i32);
+        let _ = h_rd(store, instance, (word as u8) as i32);
+    }
+}
+
+/// Delivers an `[event_type, data]` event to the guest: through its `h_rd`/`h_re` exports, or
+/// a component's `receive`.
+///
+/// Can be called from any task or blocking thread of the runtime, e.g. when a timer fires or
+/// a fetch completes. The store is only reached through its lock, so events are delivered one
+/// at a time, and whatever the guest answers (an `http.end` for a waiting request, say) is
+/// written from a task spawned on the runtime. It blocks, so async code goes through
+/// `spawn_blocking`; host functions run while the guest holds the lock and use `queue_event`.
+pub fn send_event(event_type: &str, data: Value) {
+    let mut wasm = WASM.lock().unwrap();
+    match wasm.as_mut() {
+        Some((store, guest)) => {
+            if let Err(err) = deliver(store, guest, event_type, data) {
+                log(1, &format!("Failed to deliver {}: {}", event_type, err));
+            }
+        }
+
+        _ => {
+            eprintln!("WASM not initialized");
+        }
+    }
+}
+
+// Sends an event about request `id` to the instance answering it, which is the shared one
+// unless requests get their own (see `set_isolation_pool`)
+pub(crate) fn send_request_event(id: usize, event_type: &str, data: Value) {
+    if !isolation::enabled() {
+        return send_event(event_type, data);
+    }
+    let Some(instance) = isolation::assigned(id) else {
+        log(
+            2,
+            &format!("Request {} is done, dropping {}", id, event_type),
+        );
+        return;
+    };
+    let mut instance = instance.lock().unwrap();
+    let (store, guest) = &mut *instance;
+    if let Err(err) = deliver(store, guest, event_type, data) {
+        log(1, &format!("Failed to deliver {}: {}", event_type, err));
+        // Whatever state the instance was left in, no one else gets to see it
+        isolation::discard(id);
+        rpc::cleanup(id);
+        if let Some(response) = RESPONSE_MAP.lock().unwrap().remove(&id) {
+            tokio::spawn(async move {
+                let _ = response
+                    .send_error(500, NO_HEADERS, "Internal Server Error\n")
+                    .await;
+            });
+        }
+    }
+}
+
+thread_local! {
+    // Set when the guest ends a response during the call running on this thread, for it to
+    // be reset after, see `Guest::reset`
+    static ENDED: Cell<bool> = const { Cell::new(false) };
+}
+
+// Hands `store`'s guest one event
+fn deliver(store: &mut Store<()>, guest: &Guest, event_type: &str, data: Value) -> Result<()> {
+    // Fuel is only metered when profiling; otherwise this is `None`
+    let fuel_before = store.get_fuel().ok();
+    let request_id = data[1]["id"].clone();
+    guest.receive(store, &json!([event_type, data]).to_string())?;
+    if ENDED.replace(false) {
+        if let Some(Err(err)) = guest.reset(store) {
+            log(1, &format!("Failed to execute 'reset': {}", err));
+        }
+    }
+
+    if let (Some(before), Ok(after), "http.request") = (fuel_before, store.get_fuel(), event_type) {
+        let mut message = format!("Request {}: consumed {} fuel", request_id, before - after);
+        if let Some(size) = memory_size(store, guest) {
+            write!(message, ", linear memory {} bytes", size).unwrap();
+        }
+        log(2, &message);
+    }
+    Ok(())
+}
+
+// The size of the guest's linear memory. It never grows back down, so this is the peak so
+// far. GC-based guests may not export any, and components keep theirs inside.
+pub(crate) fn memory_size(store: &mut Store<()>, guest: &Guest) -> Option<usize> {
+    match guest {
+        Guest::Module(instance) => {
+            let memory = instance
+                .exports(&mut *store)
+                .find_map(|export| export.into_memory())?;
+            Some(memory.data_size(&*store))
+        }
+        Guest::Component(_) => None,
+    }
+}
+
+// Sends an event once the guest is free to take it. Host functions run while the guest holds
+// the store, so anything they send back has to wait for the current call to return.
+pub(crate) fn queue_event(event_type: &str, data: Value) {
+    let event_type = event_type.to_string();
+    tokio::task::spawn_blocking(move || send_event(&event_type, data));
+}
+
+fn map_to_iter(map: serde_json::Map<String, Value>) -> impl Iterator<Item = (String, String)> {
+    map.into_iter().filter_map(|(key, value)| {
+        // Try to convert the value to a string reference
+        if let Value::String(s) = value {
+            Some((key, s)) // Return a tuple with (key, value) where both are strings
+        } else {
+            None // Ignore non-string values
+        }
+    })
+}
+
+// Takes a status message out of `[id, status, message, headers, ...]`, the order of Node's
+// `writeHead(status, statusMessage, headers)`, leaving `[id, status, headers, ...]`
+fn take_status_message(data: &Value) -> (Cow<'_, Value>, Option<String>) {
+    match data.as_array().map(Vec::as_slice) {
+        Some(
+            [Value::Number(_), Value::Number(_), Value::String(message), Value::Object(_), ..],
+        ) => {
+            let mut data = data.clone();
+            data.as_array_mut().unwrap().remove(2);
+            (Cow::Owned(data), Some(message.clone()))
+        }
+        _ => (Cow::Borrowed(data), None),
+    }
+}
+
+// Gives `response` the status message the guest asked for, if it's valid
+fn set_status_message(response: &mut Response, message: Option<String>) {
+    if let Some(message) = message {
+        if !response.set_status_message(&message) {
+            eprintln!("Invalid status message {:?}", message);
+        }
+    }
+}
+
+// The request body for the guest: parsed when the client sent JSON or form-data, a string
+// otherwise
+fn request_body(req: &Request, id: usize) -> Result<Value, String> {
+    let content_type = req.headers.get("content-type").map_or("", String::as_str);
+    let media_type = content_type.split(';').next().unwrap_or("").trim();
+    let is_json =
+        media_type.eq_ignore_ascii_case("application/json") || media_type.ends_with("+json");
+
+    if let Some(boundary) = multipart::boundary(content_type) {
+        let parts = multipart::parse(&req.body, &boundary)?;
+        Ok(multipart::to_json(parts, id))
+    } else if is_json && !req.body.is_empty() {
+        serde_json::from_slice(&req.body).map_err(|err| err.to_string())
+    } else {
+        Ok(Value::String(
+            String::from_utf8_lossy(&req.body).into_owned(),
+        ))
+    }
+}
+
+// The `Authorization` header for the guest: `{ scheme: "Basic", username, password }`,
+// `{ scheme: "Bearer", token }`, or `{ scheme, raw }` for anything else
+fn auth(authorization: &str) -> Value {
+    let (scheme, credentials) = authorization
+        .trim()
+        .split_once(' ')
+        .unwrap_or((authorization.trim(), ""));
+    let credentials = credentials.trim();
+    if scheme.eq_ignore_ascii_case("Basic") {
+        let decoded = STANDARD
+            .decode(credentials)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok());
+        if let Some((username, password)) = decoded.as_ref().and_then(|d| d.split_once(':')) {
+            return json!({
+                "scheme": "Basic",
+                "username": username,
+                "password": password,
+            });
+        }
+    } else if scheme.eq_ignore_ascii_case("Bearer") && !credentials.is_empty() {
+        return json!({
+            "scheme": "Bearer",
+            "token": credentials,
+        });
+    }
+    json!({
+        "scheme": scheme,
+        "raw": credentials,
+    })
+}
+
+// Per-listener settings from the `http.listen` event
+#[derive(Default)]
+pub(crate) struct ListenOptions {
+    rate_limit: Option<RateLimiter>,
+    max_requests_per_connection: Option<usize>,
+    keep_alive_timeout: Option<Duration>,
+    tcp_keepalive: Option<nodehttp::TcpKeepalive>,
+    max_headers: Option<usize>,
+    strict_trailers: bool,
+    stream_body: bool,
+}
+
+// Reads the options of `http.route`:
+// - `maxConcurrency` caps how many of the route's requests the guest handles at once; the
+//   rest wait, or get 503 once `maxQueue` are waiting
+// - `timeout` is how many milliseconds the guest has to answer before the client gets 504
+fn route_options(
+    options: &serde_json::Map<String, Value>,
+) -> Result<(Option<router::Limit>, Option<Duration>), String> {
+    let max_queue = match options.get("maxQueue") {
+        Some(max) => Some(max.as_u64().ok_or("invalid maxQueue")? as usize),
+        None => None,
+    };
+    let limit = match options.get("maxConcurrency") {
+        Some(max) => match max.as_u64() {
+            Some(max) if max > 0 => Some(router::Limit::new(max as usize, max_queue)),
+            _ => return Err("invalid maxConcurrency".to_string()),
+        },
+        None if max_queue.is_some() => return Err("maxQueue needs maxConcurrency".to_string()),
+        None => None,
+    };
+    let timeout = match options.get("timeout") {
+        Some(ms) => match ms.as_u64() {
+            Some(ms) if ms > 0 => Some(Duration::from_millis(ms)),
+            _ => return Err("invalid timeout".to_string()),
+        },
+        None => None,
+    };
+    Ok((limit, timeout))
+}
+
+// When a request must be answered by: the earlier of the client's `X-Request-Deadline`
+// (milliseconds it's willing to wait) and the route's `timeout`
+fn request_deadline(
+    headers: &HashMap<String, String>,
+    route_timeout: Option<Duration>,
+) -> Option<Instant> {
+    let client_timeout = headers
+        .get("x-request-deadline")
+        .and_then(|ms| ms.trim().parse::<u64>().ok())
+        .map(Duration::from_millis);
+    let timeout = match (client_timeout, route_timeout) {
+        (Some(client), Some(route)) => client.min(route),
+        (timeout, None) | (None, timeout) => timeout?,
+    };
+    Some(Instant::now() + timeout)
+}
+
+// The preconditions of a conditional write, for the guest to check against the resource:
+// `ifMatch` is `"*"` or the entity tags as sent (e.g. `"\"v2\""`), and `ifUnmodifiedSince`
+// milliseconds since the epoch. Like RFC 9110, an `If-Unmodified-Since` is ignored when there's
+// an `If-Match`, as is one that isn't a valid date.
+fn conditional(headers: &HashMap<String, String>) -> Option<Value> {
+    if let Some(if_match) = headers.get("if-match") {
+        let if_match = if if_match.trim() == "*" {
+            json!("*")
+        } else {
+            json!(entity_tags(if_match))
+        };
+        return Some(json!({ "ifMatch": if_match }));
+    }
+    let since = headers
+        .get("if-unmodified-since")
+        .and_then(|since| chrono::DateTime::parse_from_rfc2822(since.trim()).ok())?;
+    Some(json!({ "ifUnmodifiedSince": since.timestamp_millis() }))
+}
+
+// The entity tags in a list like `"a", W/"b"`. Tags may contain commas, so the list is split
+// on the quotes; anything malformed is left out.
+fn entity_tags(list: &str) -> Vec<String> {
+    let mut tags = Vec::new();
+    let mut rest = list.trim_start();
+    while !rest.is_empty() {
+        let (weak, tag) = match rest.strip_prefix("W/") {
+            Some(tag) => (true, tag),
+            None => (false, rest),
+        };
+        let end = match tag.strip_prefix('"').and_then(|opaque| opaque.find('"')) {
+            Some(end) => {
+                let end = end + if weak { 4 } else { 2 };
+                tags.push(rest[..end].to_string());
+                end
+            }
+            None => rest.find(',').unwrap_or(rest.len()),
+        };
+        rest = rest[end..].trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+    }
+    tags
+}
+
+// Answers request `id` with 412, as the guest asks with `http.preconditionFailed` when a
+// conditional write doesn't hold; `etag` is the resource's current one
+fn precondition_failed(id: usize, etag: Option<String>) -> bool {
+    let Some(response) = RESPONSE_MAP.lock().unwrap().remove(&id) else {
+        return false;
+    };
+    multipart::cleanup(id);
+    tokio::spawn(async move {
+        let headers = etag.map(|etag| ("ETag", etag));
+        let _ = response
+            .send_error(412, headers, "Precondition Failed\n")
+            .await;
+    });
+    true
+}
+
+// Answers JSON-RPC request `id` once the guest has answered every call in it, see
+// `set_json_rpc`
+fn answer_rpc(id: usize, answer: Value) -> bool {
+    let Some(response) = RESPONSE_MAP.lock().unwrap().remove(&id) else {
+        return false;
+    };
+    multipart::cleanup(id);
+    tokio::spawn(send_rpc_answer(response, id, answer));
+    true
+}
+
+// Sends the response envelopes in `answer`, or 204 when it's `Null`, all there is to send
+// being answers to notifications
+async fn send_rpc_answer(response: Response, id: usize, answer: Value) -> std::io::Result<()> {
+    if answer.is_null() {
+        trace::response(id, 204, b"");
+        return response.send_whole(204, Vec::new(), b"", Vec::new()).await;
+    }
+    let body = format!("{}\n", answer);
+    trace::response(id, 200, body.as_bytes());
+    let mut headers = vec![("Content-Type".to_string(), "application/json".to_string())];
+    let body = compression::apply(
+        response.request_header("accept-encoding"),
+        &mut headers,
+        body.into_bytes(),
+    );
+    response.send_whole(200, headers, &body, Vec::new()).await
+}
+
+// Answers request `id` with a redirect to `location`, as the guest asks with `http.redirect`. The
+// body is a short HTML page linking there, for clients that don't follow redirects.
+fn redirect(id: usize, status_code: u16, location: String) -> bool {
+    let Some(response) = RESPONSE_MAP.lock().unwrap().remove(&id) else {
+        return false;
+    };
+    multipart::cleanup(id);
+    tokio::spawn(async move {
+        let reason = nodehttp::reason_phrase(status_code);
+        let mut href = String::new();
+        template::escape_html(&location, &mut href);
+        let body = format!(
+            "<!DOCTYPE html>\n<title>{status_code} {reason}</title>\n<p>Redirecting to <a href=\"{href}\">{href}</a>.</p>\n"
+        );
+        let headers = vec![
+            ("Location".to_string(), location),
+            (
+                "Content-Type".to_string(),
+                "text/html; charset=utf-8".to_string(),
+            ),
+        ];
+        let _ = response
+            .send_whole(status_code, headers, body.as_bytes(), Vec::new())
+            .await;
+    });
+    true
+}
+
+// Answers request `id` with 503 when an operator gives up on it through the admin API, and
+// tells the guest with `http.aborted` so it can drop the work
+pub(crate) fn kill(id: usize) -> bool {
+    let Some(response) = RESPONSE_MAP.lock().unwrap().remove(&id) else {
+        return false;
+    };
+    response.log(1, &format!("Request {} killed through the admin API", id));
+    multipart::cleanup(id);
+    rpc::cleanup(id);
+    // The guest may be the one stuck; don't wait for it
+    tokio::task::spawn_blocking(move || send_request_event(id, "http.aborted", json!(id)));
+    tokio::spawn(async move {
+        let _ = response
+            .send_error(503, NO_HEADERS, "Service Unavailable\n")
+            .await;
+    });
+    true
+}
+
+// Answers request `id` with 504 if the guest hasn't by `deadline`, and tells the guest with
+// `http.timeout` so it can drop the work
+async fn enforce_deadline(id: usize, deadline: Instant) {
+    tokio::time::sleep_until(deadline.into()).await;
+    let Some(response) = RESPONSE_MAP.lock().unwrap().remove(&id) else {
+        return;
+    };
+    response.log(2, &format!("Request {} ran out of time", id));
+    multipart::cleanup(id);
+    rpc::cleanup(id);
+    let _ = tokio::task::spawn_blocking(move || send_request_event(id, "http.timeout", json!(id)))
+        .await;
+    let _ = response
+        .send_error(504, NO_HEADERS, "Gateway Timeout\n")
+        .await;
+}
+
+// Passes the body of request `id` to the guest as it arrives: `http.requestBody` with
+// `[id, { data, encoding? }]` for each chunk, then `http.requestBodyEnd` with `{ id }`, or
+// `{ id, error }` when the body was cut short
+async fn stream_request_body(id: usize, mut body: BodyReader) {
+    let result = loop {
+        match body.chunk().await {
+            Ok(Some(chunk)) => {
+                let event = json!([id, socket::encode(chunk)]);
+                let _ = tokio::task::spawn_blocking(move || {
+                    send_request_event(id, "http.requestBody", event)
+                })
+                .await;
+                // The rest of the body is of no use once the guest has answered
+                let answered =
+                    !RESPONSE_MAP.lock().unwrap().contains_key(&id) && !streaming::is_open(id);
+                if answered {
+                    break Err("response already sent".to_string());
+                }
+            }
+            Ok(None) => break Ok(()),
+            Err(err) => break Err(err.to_string()),
+        }
+    };
+    let event = match result {
+        Ok(()) => match body.trailers().filter(|trailers| !trailers.is_empty()) {
+            Some(trailers) => json!({ "id": id, "trailers": trailers }),
+            None => json!({ "id": id }),
+        },
+        Err(err) => {
+            log(
+                2,
+                &format!("Request body {} not read to the end: {}", id, err),
+            );
+            json!({ "id": id, "error": err })
+        }
+    };
+    let _ =
+        tokio::task::spawn_blocking(move || send_request_event(id, "http.requestBodyEnd", event))
+            .await;
+}
+
+// Reads the options of `http.listen`:
+// - `rateLimit: { rate, burst }`, where `rate` is requests per second per client IP and
+//   `burst` (default: `rate`) how many may come at once
+// - `maxRequestsPerConnection` (default: 100), after which a keep-alive connection is closed
+// - `keepAliveTimeout` (default: 5000), the milliseconds a keep-alive connection is kept open
+//   without a request
+// - `tcpKeepAlive: { idle, interval, count }` to probe connections silent for `idle`
+//   milliseconds at the TCP level, every `interval` milliseconds, dropping them after `count`
+//   unanswered probes; both default to the system's. Unlike `keepAliveTimeout` this keeps
+//   connections open, such as quiet event streams, as long as the peer is there.
+// - `maxHeaders` (default: 100), the most header fields a request may have before it gets 431
+// - `strictTrailers` (default: false), to answer 400 to requests with trailer fields their
+//   `Trailer` header didn't announce
+// - `streamBody` (default: false), to send `http.request` as soon as the head is read and
+//   the body after it in `http.requestBody` events, ended by `http.requestBodyEnd` (with
+//   the `trailers` of a chunked body that has them)
+fn listen_options(options: &serde_json::Map<String, Value>) -> Result<ListenOptions, String> {
+    let rate_limit = match options.get("rateLimit") {
+        Some(limit) => match (limit["rate"].as_f64(), &limit["burst"]) {
+            (Some(rate), Value::Null) if rate > 0.0 => Some(RateLimiter::new(rate, rate)),
+            (Some(rate), Value::Number(burst)) if rate > 0.0 => {
+                Some(RateLimiter::new(rate, burst.as_f64().unwrap_or(rate)))
+            }
+            _ => return Err("invalid rateLimit".to_string()),
+        },
+        None => None,
+    };
+    let max_requests_per_connection = match options.get("maxRequestsPerConnection") {
+        Some(max) => match max.as_u64() {
+            Some(max) if max > 0 => Some(max as usize),
+            _ => return Err("invalid maxRequestsPerConnection".to_string()),
+        },
+        None => None,
+    };
+    let keep_alive_timeout = match options.get("keepAliveTimeout") {
+        Some(ms) => match ms.as_u64() {
+            Some(ms) if ms > 0 => Some(Duration::from_millis(ms)),
+            _ => return Err("invalid keepAliveTimeout".to_string()),
+        },
+        None => None,
+    };
+    let tcp_keepalive = match options.get("tcpKeepAlive") {
+        Some(Value::Object(keepalive)) => {
+            // Probes are timed in whole seconds
+            let seconds = |name: &str| match keepalive.get(name) {
+                Some(ms) => match ms.as_u64() {
+                    Some(ms) if ms >= 1000 => Ok(Some(Duration::from_millis(ms))),
+                    _ => Err(format!("invalid tcpKeepAlive.{}", name)),
+                },
+                None => Ok(None),
+            };
+            let count = match keepalive.get("count") {
+                Some(count) => match count.as_u64() {
+                    Some(count @ 1..=255) => Some(count as u32),
+                    _ => return Err("invalid tcpKeepAlive.count".to_string()),
+                },
+                None => None,
+            };
+            Some(nodehttp::TcpKeepalive {
+                idle: seconds("idle")?.ok_or("tcpKeepAlive needs idle")?,
+                interval: seconds("interval")?,
+                count,
+            })
+        }
+        Some(_) => return Err("invalid tcpKeepAlive".to_string()),
+        None => None,
+    };
+    let max_headers = match options.get("maxHeaders") {
+        Some(max) => match max.as_u64() {
+            Some(max) if max > 0 => Some(max as usize),
+            _ => return Err("invalid maxHeaders".to_string()),
+        },
+        None => None,
+    };
+    let strict_trailers = match options.get("strictTrailers") {
+        Some(Value::Bool(enabled)) => *enabled,
+        Some(_) => return Err("invalid strictTrailers".to_string()),
+        None => false,
+    };
+    let stream_body = match options.get("streamBody") {
+        Some(Value::Bool(enabled)) => *enabled,
+        Some(_) => return Err("invalid streamBody".to_string()),
+        None => false,
+    };
+    Ok(ListenOptions {
+        rate_limit,
+        max_requests_per_connection,
+        keep_alive_timeout,
+        tcp_keepalive,
+        max_headers,
+        strict_trailers,
+        stream_body,
+    })
+}
+
+// Answers a request to one of the guest's listeners, or a warmup request, see `set_warmup`
+pub(crate) fn handle_request(req: &Request, mut res: Response) -> MiddlewareFuture {
+    res.log(
+        2,
+        &format!("Received request: {} {} ({})", req.method, req.path, req.id),
+    );
+    let overridden = method_override(req);
+    if let Some(method) = &overridden {
+        res.log(2, &format!("Handling POST {} as {}", req.path, method));
+    }
+    let method = overridden.clone().unwrap_or_else(|| req.method.clone());
+    let path = req.path.clone();
+    let headers = req.headers.clone();
+    let trailers = req.trailers.clone();
+    let request_id = req.id.clone();
+    let client = req.client.clone();
+    let connection_id = req.connection_id;
+    let auth = req.headers.get("authorization").map(|value| auth(value));
+    let client_identity = req
+        .client_identity
+        .as_ref()
+        .map(|identity| identity.to_json());
+    let server_name = req.server_name.clone();
+    let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+    trace::request(id, &req.headers, &req.body);
+    let mut body = request_body(req, id);
+    let raw_body = String::from_utf8_lossy(&req.body).into_owned();
+    Box::pin(async move {
+        #[cfg(feature = "otel")]
+        let trace_context = otel::start(&mut res, &method, &path, &client.ip.to_string());
+        let mut body_stream = res.take_body();
+        // Time spent waiting for a turn on the route counts against the budget
+        let deadline = request_deadline(&headers, router::timeout_for(&method, &path));
+
+        // The guest is still initializing; tell the client to come back shortly
+        if !is_ready() {
+            res.send_error(503, [("Retry-After", "1")], "Service Unavailable\n")
+                .await?;
+            return Ok(());
+        }
+
+        // Methods the server doesn't take at all never reach the guest, see
+        // `set_allowed_methods`
+        if let Err(allowed) = method_allowed(&method) {
+            res.log(2, &format!("Method `{}` not allowed", method));
+            res.send_error(405, [("Allow", allowed)], "Method Not Allowed\n")
+                .await?;
+            return Ok(());
+        }
+
+        // Methods the guest said it doesn't handle on this path never reach it
+        if let Some(allowed) = router::disallowed(&method, &path) {
+            if method == "OPTIONS" {
+                res.write_head(204, [("Allow", allowed)]).await?;
+                res.end("").await;
+            } else {
+                res.log(2, &format!("Method {} not allowed on {}", method, path));
+                res.send_error(405, [("Allow", allowed)], "Method Not Allowed\n")
+                    .await?;
+            }
+            return Ok(());
+        }
+
+        // Answer OPTIONS from the declared routes without bothering the guest
+        if method == "OPTIONS" && router::is_configured() {
+            let allowed = router::allowed_methods(&path);
+            if !allowed.is_empty() {
+                res.write_head(204, [("Allow", allowed.join(", "))]).await?;
+                res.end("").await;
+                return Ok(());
+            }
+        }
+
+        // Repeat what the guest already answered, while it's fresh. Identical requests
+        // arriving while the guest is at it wait for that answer rather than ask again.
+        let mut hit = cache::lookup(&res);
+        if hit.is_none() && matches!(method.as_str(), "GET" | "HEAD") {
+            match cache::join(&res) {
+                Some(cache::Flight::Lead(leader)) => res.hold(leader),
+                Some(follow) => {
+                    if follow.wait(deadline).await {
+                        hit = cache::lookup(&res);
+                    } else {
+                        res.log(
+                            2,
+                            &format!("Gave up waiting on an identical request: {}", path),
+                        );
+                    }
+                }
+                None => {}
+            }
+        }
+        if let Some(mut hit) = hit {
+            res.log(2, &format!("Answering {} {} from the cache", method, path));
+            let body = compression::apply(
+                res.request_header("accept-encoding"),
+                &mut hit.headers,
+                hit.body,
+            );
+            res.send_whole(hit.status_code, hit.headers, &body, Vec::new())
+                .await?;
+            return Ok(());
+        }
+        if matches!(method.as_str(), "POST" | "PUT" | "PATCH" | "DELETE") {
+            cache::invalidate(&res);
+        }
+
+        // Wait for a turn on routes the guest limited, or turn the request away
+        if let Some(limit) = router::limit_for(&method, &path) {
+            match limit.acquire().await {
+                Some(permit) => res.hold(permit),
+                None => {
+                    res.log(2, &format!("Route busy, rejected {} {}", method, path));
+                    res.send_error(503, [("Retry-After", "1")], "Service Unavailable\n")
+                        .await?;
+                    return Ok(());
+                }
+            }
+        }
+
+        // A HEAD request to a GET-only route is answered by the GET handler; the
+        // response drops the body and keeps its headers
+        let method = if method == "HEAD" && router::head_via_get(&path) {
+            res.log(2, &format!("Answering HEAD {} with its GET route", path));
+            "GET".to_string()
+        } else {
+            method
+        };
+        let mut request = json!({
+            "method": method,
+            "url": path,
+            "headers": headers,
+            "requestId": request_id,
+            "remoteAddress": client.ip.to_string(),
+            "protocol": client.scheme,
+        });
+        if let Some(host) = client.host {
+            request["host"] = Value::String(host);
+        }
+        if overridden.is_some() {
+            request["originalMethod"] = json!("POST");
+        }
+        if let Some(connection_id) = connection_id {
+            request["connectionId"] = json!(connection_id);
+        }
+        if let Some(auth) = auth {
+            request["auth"] = auth;
+        }
+        if let Some(client_identity) = client_identity {
+            request["clientCert"] = client_identity;
+        }
+        if let Some(server_name) = server_name {
+            request["serverName"] = Value::String(server_name);
+        }
+        if !trailers.is_empty() {
+            request["trailers"] = json!(trailers);
+        }
+        if let Some(conditional) = conditional(&headers) {
+            request["conditional"] = conditional;
+        }
+        if body_stream.is_some() {
+            request["bodyStream"] = Value::Bool(true);
+        }
+        #[cfg(feature = "otel")]
+        if let Some(context) = &trace_context {
+            request["traceparent"] = Value::String(context.traceparent());
+            if let Some(tracestate) = context.tracestate() {
+                request["tracestate"] = Value::String(tracestate.to_string());
+            }
+        }
+        if let Some(deadline) = deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                res.log(
+                    2,
+                    &format!("Out of time before reaching the guest: {}", path),
+                );
+                res.send_error(504, NO_HEADERS, "Gateway Timeout\n").await?;
+                return Ok(());
+            }
+            request["timeRemaining"] = json!(remaining.as_millis() as u64);
+        }
+        // The host takes JSON-RPC envelopes apart itself and hands the guest the calls in
+        // them, see `set_json_rpc`
+        let mut rpc_calls = None;
+        let events: Vec<(&str, Value)> = if rpc::is_endpoint(&path) {
+            if method != "POST" {
+                res.send_error(405, [("Allow", "POST")], "Method Not Allowed\n")
+                    .await?;
+                return Ok(());
+            }
+            let mut raw_body = raw_body.into_bytes();
+            if let Some(mut body_stream) = body_stream.take() {
+                while let Some(chunk) = body_stream.chunk().await? {
+                    raw_body.extend(chunk);
+                }
+            }
+            let (mut pending, calls) = rpc::parse(&raw_body);
+            if calls.is_empty() {
+                if let Some(answer) = pending.delivered() {
+                    send_rpc_answer(res, id, answer).await?;
+                    return Ok(());
+                }
+            }
+            rpc_calls = Some(pending);
+            let context = json!({ "id": id, "token": request_token(id) });
+            calls
+                .into_iter()
+                .map(|mut call| {
+                    call["request"] = request.clone();
+                    ("rpc.call", json!([call, context]))
+                })
+                .collect()
+        } else {
+            // Streamed bodies aren't here to check; the guest reads them itself
+            let schema = match body_stream {
+                Some(_) => None,
+                None => schema::find(&method, &path, &headers),
+            };
+            if let Some(schema) = schema {
+                let (checked, errors) = tokio::task::spawn_blocking(move || {
+                    let errors = schema::check(&schema, &body);
+                    (body, errors)
+                })
+                .await?;
+                body = checked;
+                if let Some(errors) = errors {
+                    res.log(
+                        2,
+                        &format!("Request body doesn't match its schema: {}", path),
+                    );
+                    let body = json!({ "error": "Invalid request body", "errors": errors });
+                    let headers =
+                        vec![("Content-Type".to_string(), "application/json".to_string())];
+                    res.send_whole(400, headers, format!("{}\n", body).as_bytes(), Vec::new())
+                        .await?;
+                    return Ok(());
+                }
+            }
+            match body {
+                Ok(body) => request["body"] = body,
+                Err(err) if REJECT_MALFORMED_JSON.load(Ordering::Relaxed) => {
+                    res.log(2, &format!("Malformed request body: {}", err));
+                    let text = format!("Malformed request body: {}\n", err);
+                    res.send_error(400, NO_HEADERS, &text).await?;
+                    return Ok(());
+                }
+                Err(_) => {
+                    request["body"] = Value::String(raw_body);
+                    request["bodyParseError"] = Value::Bool(true);
+                }
+            }
+
+            let data = json!([
+                request,
+                {
+                    "id": id,
+                    "token": request_token(id),
+                }
+            ]);
+            vec![("http.request", data)]
+        };
+
+        // Turn the request away rather than add to a backlog the guest can't keep up with
+        let Some(queued) = Queued::enter() else {
+            res.log(
+                2,
+                &format!("Guest queue full, rejected {} {}", method, path),
+            );
+            res.send_error(503, [("Retry-After", "1")], "Service Unavailable\n")
+                .await?;
+            return Ok(());
+        };
+        match isolation::assign(id).await {
+            Ok(Some(assignment)) => res.hold(assignment),
+            Ok(None) => {}
+            Err(err) => {
+                res.log(1, &err);
+                res.send_error(500, NO_HEADERS, "Internal Server Error\n")
+                    .await?;
+                return Ok(());
+            }
+        }
+        let answers_rpc = rpc_calls.is_some();
+        if let Some(pending) = rpc_calls {
+            rpc::begin(id, pending);
+        }
+        // Maps the ID to the response before the guest gets a chance to answer
+        RESPONSE_MAP.lock().unwrap().insert(id, res);
+        // Wait for the guest off the async workers, so requests arriving meanwhile
+        // still get read, and either queue up behind this one or are turned away
+        // The guest's handler gets a span of its own under the request's
+        #[cfg(feature = "otel")]
+        let mut dispatch = trace_context.map(|context| (context, otel::now_nanos()));
+        let _ = tokio::task::spawn_blocking(move || {
+            for (event_type, data) in events {
+                send_request_event(id, event_type, data);
+                #[cfg(feature = "otel")]
+                if let Some((context, start)) = &mut dispatch {
+                    context.child(event_type, *start);
+                    *start = otel::now_nanos();
+                }
+            }
+            drop(queued);
+        })
+        .await;
+        if answers_rpc {
+            if let Some(answer) = rpc::delivered(id) {
+                answer_rpc(id, answer);
+            }
+        }
+        if let Some(body_stream) = body_stream {
+            tokio::spawn(stream_request_body(id, body_stream));
+        }
+        if let Some(deadline) = deadline {
+            tokio::spawn(enforce_deadline(id, deadline));
+        }
+        Ok(())
+    })
+}
+
+pub(crate) fn listen(port: u16, options: ListenOptions) {
+    let (stop, stopped) = oneshot::channel();
+    let mut listeners = LISTENERS.lock().unwrap();
+    if let Some(relistened) = RELISTENED.lock().unwrap().as_mut() {
+        relistened.insert(port);
+        // Keep serving on the socket we have rather than rebinding it
+        if listeners.contains_key(&port) {
+            log(2, &format!("Still listening on port {}", port));
+            return;
+        }
+    }
+    if listeners.contains_key(&port) {
+        eprintln!("Already listening on port {}", port);
+        queue_event(
+            "error",
+            json!({
+                "event": "http.listen",
+                "message": format!("already listening on port {}", port),
+            }),
+        );
+        return;
+    }
+    listeners.insert(port, stop);
+    drop(listeners);
+    log(1, &format!("Listening on port {}", port));
+
+    let server = nodehttp::create_server(handle_request);
+
+    let server = server.ip_stack(*IP_STACK.lock().unwrap());
+    let server = match tls::acceptor() {
+        Some(acceptor) => server.tls(acceptor),
+        None => server,
+    };
+    let inherited = INHERITED_LISTENER
+        .lock()
+        .unwrap()
+        .take_if(|listener| listener.local_addr().is_ok_and(|addr| addr.port() == port));
+    let server = match inherited {
+        Some(listener) => server.listener(listener),
+        None => server,
+    };
+    let server = match options.rate_limit {
+        Some(limiter) => server.rate_limit(limiter),
+        None => server,
+    };
+    let server = match options.max_requests_per_connection {
+        Some(max) => server.max_requests_per_connection(max),
+        None => server,
+    };
+    let server = match options.keep_alive_timeout {
+        Some(timeout) => server.keep_alive_timeout(timeout),
+        None => server,
+    };
+    let server = match options.tcp_keepalive {
+        Some(keepalive) => server.tcp_keepalive(keepalive),
+        None => server,
+    };
+    let server = match options.max_headers {
+        Some(max) => server.max_headers(max),
+        None => server,
+    };
+    let server = server
+        .strict_trailers(options.strict_trailers)
+        .stream_body(options.stream_body);
+
+    // 让服务器监听 3000 端口
+    tokio::spawn(async move {
+        tokio::select! {
+            // Only binding fails; once listening, the server runs until it's closed
+            result = server.listen(port, || {}) => {
+                if let Err(err) = result {
+                    eprintln!("Failed to listen on port {}: {}", port, err);
+                    LISTENERS.lock().unwrap().remove(&port);
+                    queue_event(
+                        "http.listenError",
+                        json!({
+                            "port": port,
+                            "message": err.to_string(),
+                        }),
+                    );
+                }
+            }
+            // Dropping the server future closes the listening socket; connections already
+            // accepted run to completion on their own tasks
+            _ = stopped => log(1, &format!("Stopped listening on port {}", port)),
+        }
+    });
+}
+
+// Whether there's a listener running (or starting) on `port`
+pub(crate) fn is_listening(port: u16) -> bool {
+    LISTENERS.lock().unwrap().contains_key(&port)
+}
+
+// Starts tracking which ports a reloaded guest listens on, returning those listening now
+pub(crate) fn begin_relisten() -> HashSet<u16> {
+    *RELISTENED.lock().unwrap() = Some(HashSet::new());
+    LISTENERS.lock().unwrap().keys().copied().collect()
+}
+
+// Closes the listeners only a reloaded guest that failed to start asked for, keeping those
+// of the guest still running, which were `listening`
+pub(crate) fn abort_relisten(listening: &HashSet<u16>) {
+    let Some(relistened) = RELISTENED.lock().unwrap().take() else {
+        return;
+    };
+    for port in relistened.difference(listening) {
+        close(*port);
+    }
+}
+
+// Closes the listeners the reloaded guest didn't ask for again
+pub(crate) fn end_relisten() {
+    let Some(relistened) = RELISTENED.lock().unwrap().take() else {
+        return;
+    };
+    let stale: Vec<u16> = LISTENERS
+        .lock()
+        .unwrap()
+        .keys()
+        .filter(|port| !relistened.contains(port))
+        .copied()
+        .collect();
+    for port in stale {
+        close(port);
+    }
+}
+
+// The requests handed to the guest that it hasn't answered yet
+pub(crate) fn in_flight() -> usize {
+    RESPONSE_MAP.lock().unwrap().len()
+}
+
+// How often unanswered requests are checked for clients that went away
+const DISCONNECT_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+// Drops the requests whose clients left before the guest answered, telling the guest with
+// `http.aborted` so it can stop working on them
+pub(crate) async fn watch_disconnects() {
+    let mut check = tokio::time::interval(DISCONNECT_CHECK_INTERVAL);
+    loop {
+        check.tick().await;
+        let gone: Vec<usize> = {
+            let mut response_map = RESPONSE_MAP.lock().unwrap();
+            let gone: Vec<usize> = response_map
+                .iter_mut()
+                .filter_map(|(id, response)| response.is_closed().then_some(*id))
+                .collect();
+            for id in &gone {
+                response_map.remove(id);
+            }
+            gone
+        };
+        for id in gone {
+            multipart::cleanup(id);
+            rpc::cleanup(id);
+            notify_aborted(id).await;
+        }
+    }
+}
+
+// Tells the guest the client of request `id` is gone
+pub(crate) async fn notify_aborted(id: usize) {
+    log(2, &format!("Client of request {} went away", id));
+    let _ = tokio::task::spawn_blocking(move || send_request_event(id, "http.aborted", json!(id)))
+        .await;
+}
+
+// Answers every request the guest hasn't with 503, e.g. when it's replaced
+pub(crate) fn abandon_in_flight() {
+    let responses: Vec<Response> = RESPONSE_MAP
+        .lock()
+        .unwrap()
+        .drain()
+        .map(|(id, r)| {
+            rpc::cleanup(id);
+            r
+        })
+        .collect();
+    for response in responses {
+        response.log(
+            1,
+            &format!("Abandoned unanswered request: {}", response.request_line()),
+        );
+        tokio::spawn(async move {
+            let _ = response
+                .send_error(503, NO_HEADERS, "Service Unavailable\n")
+                .await;
+        });
+    }
+}
+
+// Stops the listener on `port`, returning whether there was one
+pub(crate) fn close(port: u16) -> bool {
+    match LISTENERS.lock().unwrap().remove(&port) {
+        Some(stop) => {
+            let _ = stop.send(());
+            true
+        }
+        None => false,
+    }
+}
+
+// The token issued with request `id` in `http.request`, which can't be guessed from the id
+fn request_token(id: usize) -> String {
+    format!("{:016x}", TOKEN_KEYS.hash_one(id))
+}
+
+// Events may name a request by the `{ id, token }` it came with in place of its id; swaps in
+// the id once the token checks out, whether it's the event's data or the first element of it
+fn resolve_context(data: &Value) -> Result<Cow<'_, Value>, String> {
+    let context_id = |value: &Value| -> Result<Option<usize>, String> {
+        let Value::Object(context) = value else {
+            return Ok(None);
+        };
+        let (Some(id), Some(token), 2) = (
+            context.get("id").and_then(Value::as_u64),
+            context.get("token"),
+            context.len(),
+        ) else {
+            return Ok(None);
+        };
+        let id = id as usize;
+        match token.as_str() {
+            Some(token) if token == request_token(id) => Ok(Some(id)),
+            _ => Err(format!("token doesn't match request {}", id)),
+        }
+    };
+
+    if let Some(id) = context_id(data)? {
+        return Ok(Cow::Owned(json!(id)));
+    }
+    if let Some(first) = data.as_array().and_then(|vec| vec.first()) {
+        if let Some(id) = context_id(first)? {
+            let mut data = data.clone();
+            data[0] = json!(id);
+            return Ok(Cow::Owned(data));
+        }
+    }
+    Ok(Cow::Borrowed(data))
+}
+
+// Stops every listener, e.g. to shut down
+pub(crate) fn close_all() {
+    for (_, stop) in LISTENERS.lock().unwrap().drain() {
+        let _ = stop.send(());
+    }
+}
+
+// The type and data of an event, which the guest sends as `[type, data]`, or what's wrong
+// with its shape
+fn event_parts(event: &Value) -> Result<(&str, &Value), String> {
+    let Value::Array(parts) = event else {
+        return Err(format!(
+            "expected a [type, data] array, got {}",
+            schema::type_name(event)
+        ));
+    };
+    match parts.as_slice() {
+        [] => Err("expected a [type, data] array, got an empty one".to_string()),
+        [Value::String(event_type), data] => Ok((event_type, data)),
+        [Value::String(event_type)] => Err(format!("{} is missing its data", event_type)),
+        [Value::String(event_type), _, extra @ ..] => Err(format!(
+            "{} has {} element(s) after its data, expected [type, data]",
+            event_type,
+            extra.len()
+        )),
+        [event_type, ..] => Err(format!(
+            "expected the event type to be a string, got {}",
+            schema::type_name(event_type)
+        )),
+    }
+}
+
+// What `http.end` asked to send
+enum ResponseBody {
+    Text(String),
+    // `{ _T: "file", path }`: a file for the host to read and send
+    File(String),
+}
+
+// Function to handle the parsed JSON object
+pub(crate) fn handle_receive(json_value: Value) -> std::io::Result<()> {
+    log(1, &format!("Received JSON: {}", json_value));
+
+    let (t, handle_data) = match event_parts(&json_value) {
+        Ok(parts) => parts,
+        Err(err) => {
+            eprintln!("Invalid event: {}", err);
+            let event_type = json_value.get(0).and_then(Value::as_str);
+            queue_event("error", json!({ "message": err, "type": event_type }));
+            return Ok(());
+        }
+    };
+    let handle_data = match resolve_context(handle_data) {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("Rejected {}: {}", t, err);
+            return Ok(());
+        }
+    };
+    let handle_data: &Value = &handle_data;
+    match t {
+        // Either a port or `{ port, ...options }`, see `listen_options`
+        "http.listen" => {
+            let (port, options) = match handle_data {
+                Value::Object(options) => (
+                    options.get("port").and_then(Value::as_f64),
+                    listen_options(options),
+                ),
+                port => (port.as_f64(), Ok(ListenOptions::default())),
+            };
+            let options = match options {
+                Ok(options) => options,
+                Err(err) => {
+                    eprintln!("Invalid http.listen options: {}", err);
+                    return Ok(());
+                }
+            };
+            let has_options = matches!(handle_data, Value::Object(options) if options.keys().any(|key| key != "port"));
+            match port {
+                Some(port) => match port_override() {
+                    // Started at the latest once `_start` returns, with the options of the
+                    // guest's `http.listen` if it came first
+                    Some(override_port) => {
+                        if override_port != port as u16 {
+                            eprintln!(
+                                "Ignoring http.listen on port {}: serving on port {} as configured",
+                                port, override_port
+                            );
+                        }
+                        if !is_listening(override_port) {
+                            listen(override_port, options);
+                        } else if has_options {
+                            eprintln!(
+                                "Ignoring http.listen options: already listening on port {}",
+                                override_port
+                            );
+                        }
+                        Ok(())
+                    }
+                    None => {
+                        listen(port as u16, options);
+                        Ok(())
+                    }
+                },
+                _ => {
+                    eprintln!("Invalid port value");
+                    Ok(())
+                }
+            }
+        }
+        // The guest finished initializing; see `set_ready_timeout`
+        "runtime.ready" => {
+            SENT_READY.store(true, Ordering::SeqCst);
+            if !is_ready() {
+                set_ready(true);
+                log(1, "Guest is ready");
+            }
+            Ok(())
+        }
+        // Exits once the requests in flight are done; with an exit code, or `{ code }`
+        "runtime.shutdown" => {
+            let code = match handle_data {
+                Value::Null => Some(0),
+                Value::Object(options) => options.get("code").map_or(Some(0), Value::as_i64),
+                code => code.as_i64(),
+            };
+            match code.and_then(|code| i32::try_from(code).ok()) {
+                Some(code) => runtime::shutdown(code),
+                None => eprintln!("Invalid runtime.shutdown exit code"),
+            }
+            Ok(())
+        }
+        // `id` or `[id, etag]`, with the resource's current ETag
+        "http.preconditionFailed" => {
+            let (id, etag) = match handle_data {
+                Value::Number(id) => (id.as_f64(), None),
+                Value::Array(vec) => match vec.as_slice() {
+                    [Value::Number(id), Value::String(etag)] => (id.as_f64(), Some(etag.clone())),
+                    _ => (None, None),
+                },
+                _ => (None, None),
+            };
+            match id {
+                Some(id) => {
+                    if !precondition_failed(id as usize, etag) {
+                        eprintln!("Invalid response id");
+                    }
+                }
+                None => eprintln!("Invalid http.preconditionFailed data"),
+            }
+            Ok(())
+        }
+        // `[id, status, location]`: redirects with 301, 302 or 303, which let clients turn a
+        // POST into a GET (303 always does), or 307 or 308, which keep the method and body
+        "http.redirect" => {
+            let redirect_to = match handle_data.as_array().map(Vec::as_slice) {
+                Some([Value::Number(id), Value::Number(status_code), Value::String(location)])
+                    if !location.is_empty() && nodehttp::is_valid_header("Location", location) =>
+                {
+                    match (id.as_u64(), status_code.as_u64()) {
+                        (Some(id), Some(status_code @ (301 | 302 | 303 | 307 | 308))) => {
+                            Some((id as usize, status_code as u16, location.clone()))
+                        }
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+            match redirect_to {
+                Some((id, status_code, location)) => {
+                    if !redirect(id, status_code, location) {
+                        eprintln!("Invalid response id");
+                    }
+                }
+                None => eprintln!("Invalid http.redirect data"),
+            }
+            Ok(())
+        }
+        "http.close" => match handle_data.as_f64() {
+            Some(port) => {
+                if !close(port as u16) {
+                    eprintln!("Not listening on port {}", port);
+                }
+                Ok(())
+            }
+            _ => {
+                eprintln!("Invalid port value");
+                Ok(())
+            }
+        },
+        // `[method, pattern, schema]`: JSON bodies of requests to the route (`ALL` for any
+        // method) that don't match the schema get a 400 listing what's wrong, and never
+        // reach the guest; see `schema::register`
+        "schema.register" => match handle_data.as_array().map(Vec::as_slice) {
+            Some([Value::String(method), Value::String(pattern), schema]) => {
+                if let Err(err) = schema::register(method, pattern, schema.clone()) {
+                    eprintln!("Invalid schema.register data: {}", err);
+                }
+                Ok(())
+            }
+            _ => {
+                eprintln!("Invalid schema.register data");
+                Ok(())
+            }
+        },
+        "http.route" => {
+            if let Value::Array(vec) = handle_data {
+                match vec.as_slice() {
+                    [Value::String(method), Value::String(path)] => {
+                        router::register(method, path, None, None);
+                        Ok(())
+                    }
+                    [Value::String(method), Value::String(path), Value::Object(options)] => {
+                        match route_options(options) {
+                            Ok((limit, timeout)) => router::register(method, path, limit, timeout),
+                            Err(err) => eprintln!("Invalid http.route options: {}", err),
+                        }
+                        Ok(())
+                    }
+                    _ => {
+                        eprintln!("Invalid http.route data");
+                        Ok(())
+                    }
+                }
+            } else {
+                println!("Expected an array.");
+                Ok(())
+            }
+        }
+        // `{ "/users": ["GET", "POST"], "/users/:id": ["GET", "PUT", "DELETE"] }`, replacing
+        // any earlier map; other methods on these paths get 405, see `router::disallowed`
+        "route.methods" => {
+            let methods: Option<Vec<(String, Vec<String>)>> =
+                handle_data.as_object().and_then(|map| {
+                    map.iter()
+                        .map(|(pattern, methods)| {
+                            let methods = methods
+                                .as_array()?
+                                .iter()
+                                .map(|method| method.as_str().map(str::to_string))
+                                .collect::<Option<Vec<_>>>()?;
+                            Some((pattern.clone(), methods))
+                        })
+                        .collect()
+                });
+            match methods {
+                Some(methods) => router::set_methods(methods),
+                None => eprintln!("Invalid route.methods data"),
+            }
+            Ok(())
+        }
+        // Replies with an `http.routeStats` event listing the load on each limited route
+        "http.routeStats" => {
+            queue_event("http.routeStats", router::stats());
+            Ok(())
+        }
+        // The response cache's size, hits and misses, see `set_response_cache`
+        "http.cacheStats" => {
+            queue_event("http.cacheStats", cache::stats().unwrap_or(Value::Null));
+            Ok(())
+        }
+        // `[id, index, result]`, or `[id, index, { code, message?, data? }]`: the guest's
+        // answer to call `index` of JSON-RPC request `id`, see `set_json_rpc`
+        "rpc.result" | "rpc.error" => match handle_data.as_array().map(Vec::as_slice) {
+            Some([Value::Number(id), Value::Number(index), outcome]) => {
+                let id = id.as_f64().unwrap_or(0f64) as usize;
+                let index = index.as_f64().unwrap_or(0f64) as usize;
+                let outcome = match t {
+                    "rpc.result" => Ok(outcome.clone()),
+                    _ => Err(outcome),
+                };
+                match rpc::reply(id, index, outcome) {
+                    Ok(Some(answer)) => {
+                        ENDED.set(true);
+                        if !answer_rpc(id, answer) {
+                            eprintln!("Invalid response id");
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(err) => eprintln!("Invalid {} data: {}", t, err),
+                }
+                Ok(())
+            }
+            _ => {
+                eprintln!("Invalid {} data", t);
+                Ok(())
+            }
+        },
+        // `[id, status, headers]`, or `[id, status, message, headers]` with a status
+        // message as with Node's `writeHead`; starts a response whose body follows in
+        // `http.write`s
+        "http.writeHead" => {
+            let (data, status_message) = take_status_message(handle_data);
+            match data.as_array().map(Vec::as_slice) {
+                Some([Value::Number(id), Value::Number(status_code), rest @ ..])
+                    if rest.len() <= 1 =>
+                {
+                    let index = id.as_f64().unwrap_or(0f64) as usize;
+                    let status_code = status_code.as_f64().unwrap_or(500f64) as u16;
+                    let mut headers: Vec<(String, String)> = match rest.first() {
+                        Some(Value::Object(headers)) => map_to_iter(headers.clone()).collect(),
+                        _ => Vec::new(),
+                    };
+                    if !headers
+                        .iter()
+                        .any(|(key, _)| key.eq_ignore_ascii_case("Content-Type"))
+                    {
+                        let content_type = DEFAULT_CONTENT_TYPE.lock().unwrap().clone();
+                        headers.push(("Content-Type".to_string(), content_type));
+                    }
+                    match RESPONSE_MAP.lock().unwrap().remove(&index) {
+                        Some(mut response) => {
+                            set_status_message(&mut response, status_message);
+                            streaming::start(index, response, status_code, headers)
+                        }
+                        None => eprintln!("Invalid response id"),
+                    }
+                    Ok(())
+                }
+                _ => {
+                    eprintln!("Invalid http.writeHead data");
+                    Ok(())
+                }
+            }
+        }
+        // `[id, data]` where data is a string or `{ data, encoding: "base64" }`
+        "http.write" => match handle_data.as_array().map(Vec::as_slice) {
+            Some([Value::Number(id), data]) => {
+                let id = id.as_f64().unwrap_or(0f64) as usize;
+                match socket::decode(data).map(|data| streaming::write(id, data)) {
+                    Some(true) => {}
+                    Some(false) => eprintln!("No response stream with id {}", id),
+                    None => eprintln!("Invalid http.write data"),
+                }
+                Ok(())
+            }
+            _ => {
+                eprintln!("Invalid http.write data");
+                Ok(())
+            }
+        },
+        "http.cork" | "http.uncork" | "http.flush" => match handle_data.as_f64() {
+            Some(id) => {
+                let open = match t {
+                    "http.cork" => streaming::cork(id as usize),
+                    "http.uncork" => streaming::uncork(id as usize),
+                    _ => streaming::flush(id as usize),
+                };
+                if !open {
+                    eprintln!("No response stream with id {}", id);
+                }
+                Ok(())
+            }
+            _ => {
+                eprintln!("Invalid {} data", t);
+                Ok(())
+            }
+        },
+        "http.end" => {
+            ENDED.set(true);
+            // A status message may follow the status, as with `http.writeHead`
+            let (handle_data, status_message) = take_status_message(handle_data);
+            if let Value::Array(vec) = &*handle_data {
+                match vec.as_slice() {
+                    // Ends a response started with `http.writeHead`: `[id]`, `[id, data]`
+                    // or `[id, data, trailers]`
+                    [Value::Number(id), rest @ ..]
+                        if rest.len() <= 2
+                            && streaming::is_open(id.as_f64().unwrap_or(0f64) as usize) =>
+                    {
+                        let index = id.as_f64().unwrap_or(0f64) as usize;
+                        let body = match rest.first() {
+                            Some(data) => match socket::decode(data) {
+                                Some(body) => body,
+                                None => {
+                                    eprintln!("Invalid http.end data");
+                                    return Ok(());
+                                }
+                            },
+                            None => Vec::new(),
+                        };
+                        let trailers = match rest.get(1) {
+                            Some(Value::Object(trailers)) => {
+                                map_to_iter(trailers.clone()).collect()
+                            }
+                            _ => Vec::new(),
+                        };
+                        if !streaming::end(index, body, trailers) {
+                            eprintln!("No response stream with id {}", index);
+                        }
+                        Ok(())
+                    }
+                    // An optional fifth element holds trailer fields sent after the body
+                    [Value::Number(id), Value::Number(status_code), Value::Object(headers), body, rest @ ..]
+                        if rest.len() <= 1 =>
+                    {
+                        let index = id.as_f64().unwrap_or(0f64) as usize;
+                        log(3, format!("index: {}", index).as_str());
+                        let mut response_map = RESPONSE_MAP.lock().unwrap();
+                        let response = response_map.remove(&index);
+                        match response {
+                            Some(mut response) => {
+                                set_status_message(&mut response, status_message);
+                                // 如果是string则直接发送，如果是json object则strinify
+                                let (body, content_type) = match body {
+                                    Value::String(s) => (
+                                        ResponseBody::Text(s.clone()),
+                                        DEFAULT_CONTENT_TYPE.lock().unwrap().clone(),
+                                    ),
+                                    Value::Object(o) if o.get("_T") == Some(&json!("file")) => {
+                                        match o.get("path").and_then(Value::as_str) {
+                                            Some(path) => (
+                                                ResponseBody::File(path.to_string()),
+                                                "application/octet-stream".to_string(),
+                                            ),
+                                            None => {
+                                                eprintln!("Invalid file body");
+                                                return Ok(());
+                                            }
+                                        }
+                                    }
+                                    Value::Object(o) => (
+                                        ResponseBody::Text(serde_json::to_string(o).unwrap()),
+                                        "application/json".to_string(),
+                                    ),
+                                    _ => {
+                                        eprintln!("Invalid body type");
+                                        return Ok(());
+                                    }
+                                };
+                                // Held to `set_max_response_body` before it's copied
+                                // any further. A cut off body isn't worth caching.
+                                let mut truncated = false;
+                                let body = match body {
+                                    ResponseBody::Text(mut text) => {
+                                        warn_response_body(&response, text.len());
+                                        match response_body_room(index, 0, text.len()) {
+                                            Some(room) => {
+                                                truncated = room < text.len();
+                                                let mut end = room.min(text.len());
+                                                while !text.is_char_boundary(end) {
+                                                    end -= 1;
+                                                }
+                                                text.truncate(end);
+                                                ResponseBody::Text(text)
+                                            }
+                                            None => {
+                                                tokio::spawn(async move {
+                                                    multipart::cleanup(index);
+                                                    response
+                                                        .send_error(
+                                                            500,
+                                                            NO_HEADERS,
+                                                            "Internal Server Error\n",
+                                                        )
+                                                        .await
+                                                });
+                                                return Ok(());
+                                            }
+                                        }
+                                    }
+                                    body => body,
+                                };
+                                let status_code = status_code.as_f64().unwrap_or(500f64) as u16;
+                                let mut headers: Vec<(String, String)> =
+                                    map_to_iter(headers.clone()).collect();
+
+                                // 204 and 304 end with the headers, so they can't have a body,
+                                // nor framing headers announcing one
+                                let body = if matches!(status_code, 204 | 304) {
+                                    if !matches!(&body, ResponseBody::Text(text) if text.is_empty())
+                                    {
+                                        log(
+                                            1,
+                                            &format!(
+                                                "Dropped the body of a {} response",
+                                                status_code
+                                            ),
+                                        );
+                                    }
+                                    headers.retain(|(key, _)| {
+                                        !key.eq_ignore_ascii_case("Content-Length")
+                                            && !key.eq_ignore_ascii_case("Transfer-Encoding")
+                                    });
+                                    ResponseBody::Text(String::new())
+                                } else {
+                                    // The guest's own Content-Type always wins
+                                    if !headers
+                                        .iter()
+                                        .any(|(key, _)| key.eq_ignore_ascii_case("Content-Type"))
+                                    {
+                                        headers.push(("Content-Type".to_string(), content_type));
+                                    }
+                                    body
+                                };
+
+                                let trailers: Vec<(String, String)> = match rest.first() {
+                                    Some(Value::Object(trailers)) => {
+                                        map_to_iter(trailers.clone()).collect()
+                                    }
+                                    _ => Vec::new(),
+                                };
+                                if !trailers.is_empty()
+                                    && !headers
+                                        .iter()
+                                        .any(|(key, _)| key.eq_ignore_ascii_case("Trailer"))
+                                {
+                                    let names: Vec<&str> =
+                                        trailers.iter().map(|(key, _)| key.as_str()).collect();
+                                    headers.push(("Trailer".to_string(), names.join(", ")));
+                                }
+
+                                tokio::spawn(async move {
+                                    let mut headers = headers;
+                                    match body {
+                                        ResponseBody::Text(body) => {
+                                            trace::response(index, status_code, body.as_bytes());
+                                            if trailers.is_empty() && !truncated {
+                                                cache::store(
+                                                    &response,
+                                                    status_code,
+                                                    &headers,
+                                                    body.as_bytes(),
+                                                );
+                                            }
+                                            let body = compression::apply(
+                                                response.request_header("accept-encoding"),
+                                                &mut headers,
+                                                body.into_bytes(),
+                                            );
+                                            response
+                                                .send_whole(status_code, headers, &body, trailers)
+                                                .await?;
+                                        }
+                                        ResponseBody::File(path) => {
+                                            let description = format!("(file {})", path);
+                                            trace::response(
+                                                index,
+                                                status_code,
+                                                description.as_bytes(),
+                                            );
+                                            static_file::send(
+                                                response,
+                                                status_code,
+                                                headers,
+                                                &path,
+                                            )
+                                            .await?;
+                                        }
+                                    }
+                                    multipart::cleanup(index);
+                                    std::io::Result::Ok(())
+                                });
+                                Ok(())
+                            }
+                            _ => {
+                                eprintln!("Invalid response id");
+                                Ok(())
+                            }
+                        }
+                    }
+                    _ => {
+                        eprintln!("Invalid http.end data");
+                        Ok(())
+                    }
+                }
+            } else {
+                println!("Expected an array.");
+                Ok(())
+            }
+        }
+        // `[id, status, headers, template, values]`, with optional trailers and status
+        // message like `http.end`; the body is the template filled in from `values`, see
+        // `template::render`
+        "http.endTemplate" => {
+            ENDED.set(true);
+            let (data, status_message) = take_status_message(handle_data);
+            match data.as_array().map(Vec::as_slice) {
+                Some(
+                    [id @ Value::Number(_), status_code @ Value::Number(_), Value::Object(headers), Value::String(template), values, rest @ ..],
+                ) if rest.len() <= 1 => {
+                    let body = template::render(template, values);
+                    let mut headers = headers.clone();
+                    if !headers
+                        .keys()
+                        .any(|key| key.eq_ignore_ascii_case("Content-Type"))
+                    {
+                        headers.insert(
+                            "Content-Type".to_string(),
+                            json!("text/html; charset=utf-8"),
+                        );
+                    }
+                    let mut data = vec![
+                        id.clone(),
+                        status_code.clone(),
+                        Value::Object(headers),
+                        Value::String(body),
+                    ];
+                    data.extend(rest.iter().cloned());
+                    if let Some(message) = status_message {
+                        data.insert(2, Value::String(message));
+                    }
+                    handle_receive(json!(["http.end", data]))
+                }
+                _ => {
+                    eprintln!("Invalid http.endTemplate data");
+                    Ok(())
+                }
+            }
+        }
+        // Takes over the connection of request `id` for a custom protocol
+        "socket.hijack" => match handle_data.as_f64() {
+            Some(id) => {
+                match RESPONSE_MAP.lock().unwrap().remove(&(id as usize)) {
+                    Some(response) => socket::hijack(id as usize, response),
+                    None => eprintln!("Invalid response id"),
+                }
+                Ok(())
+            }
+            _ => {
+                eprintln!("Invalid socket.hijack data");
+                Ok(())
+            }
+        },
+        // `[id, data]` where data is a string or `{ data, encoding: "base64" }`
+        "socket.write" => match handle_data.as_array().map(Vec::as_slice) {
+            Some([Value::Number(id), data]) => {
+                match socket::write(id.as_f64().unwrap_or(0f64) as usize, data) {
+                    Ok(true) => {}
+                    Ok(false) => eprintln!("No socket with id {}", id),
+                    Err(err) => eprintln!("Invalid socket.write data: {}", err),
+                }
+                Ok(())
+            }
+            _ => {
+                eprintln!("Invalid socket.write data");
+                Ok(())
+            }
+        },
+        "socket.close" => match handle_data.as_f64() {
+            Some(id) => {
+                if !socket::close(id as usize) {
+                    eprintln!("No socket with id {}", id);
+                }
+                Ok(())
+            }
+            _ => {
+                eprintln!("Invalid socket.close data");
+                Ok(())
+            }
+        },
+        // `[id]` or `[id, headers]`; answers request `id` with an event stream
+        "sse.start" => match handle_data.as_array().map(Vec::as_slice) {
+            Some([Value::Number(id), rest @ ..]) if rest.len() <= 1 => {
+                let index = id.as_f64().unwrap_or(0f64) as usize;
+                let headers = match rest.first() {
+                    Some(Value::Object(headers)) => map_to_iter(headers.clone()).collect(),
+                    _ => Vec::new(),
+                };
+                match RESPONSE_MAP.lock().unwrap().remove(&index) {
+                    Some(response) => sse::start(index, response, headers),
+                    None => eprintln!("Invalid response id"),
+                }
+                Ok(())
+            }
+            _ => {
+                eprintln!("Invalid sse.start data");
+                Ok(())
+            }
+        },
+        "sse.send" => match handle_data.as_array().map(Vec::as_slice) {
+            Some([Value::Number(id), event]) => {
+                if !sse::send(id.as_f64().unwrap_or(0f64) as usize, event) {
+                    eprintln!("No event stream with id {}", id);
+                }
+                Ok(())
+            }
+            _ => {
+                eprintln!("Invalid sse.send data");
+                Ok(())
+            }
+        },
+        "sse.close" => match handle_data.as_f64() {
+            Some(id) => {
+                if !sse::close(id as usize) {
+                    eprintln!("No event stream with id {}", id);
+                }
+                Ok(())
+            }
+            _ => {
+                eprintln!("Invalid sse.close data");
+                Ok(())
+            }
+        },
+        _ => {
+            println!("Unknown method `{}`", t);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fuzzing::parse_requests;
+
+    // A guest that sends every event it gets straight back
+    const ECHO_GUEST: &str = r#"
+        (module
+          (import "__h" "h_sd" (func $send (param i32)))
+          (import "__h" "h_se" (func $end))
+          (memory 16)
+          (global $len (mut i32) (i32.const 0))
+          (func (export "h_rd") (param $byte i32)
+            (i32.store8 (global.get $len) (local.get $byte))
+            (global.set $len (i32.add (global.get $len) (i32.const 1))))
+          (func (export "h_re") (local $i i32)
+            (block $done
+              (loop $next
+                (br_if $done (i32.ge_u (local.get $i) (global.get $len)))
+                (call $send
+                  (i32.or
+                    (i32.shl (i32.load8_u (local.get $i)) (i32.const 8))
+                    (i32.load8_u (i32.add (local.get $i) (i32.const 1)))))
+                (local.set $i (i32.add (local.get $i) (i32.const 2)))
+                (br $next)))
+            (global.set $len (i32.const 0))
+            (call $end)))
+    "#;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn responses_can_come_from_any_task() {
+        let path = std::env::temp_dir().join(format!("mocketd-echo-{}.wat", std::process::id()));
+        std::fs::write(&path, ECHO_GUEST).unwrap();
+        Runtime::new(path.to_str().unwrap()).start();
+
+        // Once the guest has the request, it's answered from a task of its own, as a timer or
+        // a fetch completing would answer it
+        let answer = tokio::spawn(async {
+            let id = loop {
+                if let Some(id) = RESPONSE_MAP.lock().unwrap().keys().next() {
+                    break *id;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            };
+            let data = json!([id, 200, {}, "answered from a task"]);
+            tokio::task::spawn_blocking(move || send_event("http.end", data))
+                .await
+                .unwrap();
+        });
+        let raw = nodehttp::create_server(handle_request)
+            .serve_local(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        answer.await.unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let response = String::from_utf8_lossy(&raw);
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+        assert!(
+            response.ends_with("\r\n\r\nanswered from a task"),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn json_patch_bodies_reach_the_guest_intact() {
+        let body = json!([
+            { "op": "replace", "path": "/name", "value": "café" },
+            { "op": "add", "path": "/tags/-", "value": { "nested": [1, 2.5, null] } },
+        ]);
+        let text = body.to_string();
+        let data = format!(
+            "PATCH /items/1 HTTP/1.1\r\nContent-Type: application/json-patch+json\r\n\
+            Content-Length: {}\r\n\r\n{}",
+            text.len(),
+            text
+        );
+        let parsed = parse_requests(data.as_bytes(), data.len(), false);
+        let [Ok(request)] = parsed.as_slice() else {
+            panic!("request not parsed");
+        };
+        assert_eq!(request_body(request, 0), Ok(body));
+    }
+}
@@ -1,185 +1,539 @@
-mod nodehttp;
+use clap::ArgMatches;
+use mocketd::bench::BenchOptions;
+use mocketd::{
+    Config, ConnectionOverflow, IpStack, LogFormat, OptLevel, Runtime, Strategy, TlsConfig, TlsHost,
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use std::{env, process};
 
-// use nodehttp::Request;
-// use nodehttp::Response;
+fn main() {
+    let matches = clap::Command::new("Mocket Runtime")
+        .version(env!("CARGO_PKG_VERSION"))
+        .long_version(concat!(
+            env!("CARGO_PKG_VERSION"),
+            "\ncommit: ",
+            env!("MOCKETD_GIT_HASH"),
+            "\nwasmtime: ",
+            env!("MOCKETD_WASMTIME_VERSION"),
+        ))
+        .author("oboard <oboard@outlook.com>")
+        .about("a WebAssembly runtime for Mocket")
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        .subcommand(bench_command())
+        .arg(
+            clap::Arg::new("wasm_file")
+                .help("Path to the WebAssembly file")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            clap::Arg::new("config")
+                .short('c')
+                .long("config")
+                .help("Loads options from a JSON file; command-line flags take precedence"),
+        )
+        .arg(
+            clap::Arg::new("log_level")
+                .short('l')
+                .long("log")
+                .help("Sets the log level (0: no logs, 1: minimal logs, 2: verbose logs)"),
+        )
+        .arg(
+            clap::Arg::new("log_format")
+                .long("log-format")
+                .value_parser(["text", "json"])
+                .help("Writes logs as plain text or as one JSON object per line (default: text)"),
+        )
+        .arg(
+            clap::Arg::new("port")
+                .short('p')
+                .long("port")
+                .value_parser(clap::value_parser!(u16))
+                .help("Listens on this port regardless of the port the guest asks for"),
+        )
+        .arg(
+            clap::Arg::new("fd")
+                .long("fd")
+                .value_parser(clap::value_parser!(i32))
+                .conflicts_with("port")
+                .help("Serves on this already listening socket (default: the first systemd LISTEN_FDS socket, if any)"),
+        )
+        .arg(
+            clap::Arg::new("ip_stack")
+                .long("ip-stack")
+                .value_parser(["v4", "v6", "dual"])
+                .help("Accepts connections over IPv4, IPv6 or both (default: dual)"),
+        )
+        .arg(
+            clap::Arg::new("tls_cert")
+                .long("tls-cert")
+                .requires("tls_key")
+                .help("Serves HTTPS with this PEM certificate chain"),
+        )
+        .arg(
+            clap::Arg::new("tls_key")
+                .long("tls-key")
+                .requires("tls_cert")
+                .help("PEM private key for --tls-cert"),
+        )
+        .arg(
+            clap::Arg::new("tls_client_ca")
+                .long("tls-client-ca")
+                .requires("tls_cert")
+                .help("Requires client certificates signed by a CA in this PEM bundle (mutual TLS)"),
+        )
+        .arg(
+            clap::Arg::new("tls_host")
+                .long("tls-host")
+                .value_name("HOST=CERT,KEY")
+                .action(clap::ArgAction::Append)
+                .requires("tls_cert")
+                .help("Serves clients asking for HOST (with SNI) this certificate chain and key instead; HOST may be *.domain"),
+        )
+        .arg(
+            clap::Arg::new("http2")
+                .long("http2")
+                .action(clap::ArgAction::SetTrue)
+                .help("Also serves HTTP/2, negotiated over TLS or with prior knowledge over plain TCP"),
+        )
+        .arg(
+            clap::Arg::new("connection_events")
+                .long("connection-events")
+                .action(clap::ArgAction::SetTrue)
+                .help("Sends the guest connection.open and connection.close events"),
+        )
+        .arg(
+            clap::Arg::new("access_log")
+                .long("access-log")
+                .num_args(0..=1)
+                .default_missing_value("-")
+                .help("Writes Common Log Format access logs to a file (default: stdout)"),
+        )
+        .arg(
+            clap::Arg::new("content_length_threshold")
+                .long("content-length-threshold")
+                .value_name("BYTES")
+                .value_parser(clap::value_parser!(usize))
+                .help("Sends bodies up to this size with a Content-Length rather than chunked (default: 65536)"),
+        )
+        .arg(
+            clap::Arg::new("error_page")
+                .long("error-page")
+                .value_name("STATUS=FILE")
+                .action(clap::ArgAction::Append)
+                .value_parser(error_page)
+                .help("Answers the errors the server itself responds with STATUS with this HTML file"),
+        )
+        .arg(
+            clap::Arg::new("error_template")
+                .long("error-template")
+                .value_name("FILE")
+                .help("Answers the server's own errors with this HTML template, filling in {{ status }} and {{ reason }}"),
+        )
+        .arg(
+            clap::Arg::new("response_cache")
+                .long("response-cache")
+                .value_name("BYTES")
+                .value_parser(clap::value_parser!(usize))
+                .help("Keeps up to this much of the responses the guest marks cacheable, answering repeat GETs without it (default: 0, off)"),
+        )
+        .arg(
+            clap::Arg::new("diagnostics_file")
+                .long("diagnostics-file")
+                .help("Appends the diagnostics dumped on SIGUSR1 to this file (default: stderr)"),
+        )
+        .arg(
+            clap::Arg::new("default_content_type")
+                .long("default-content-type")
+                .help("Content-Type for responses that don't set one (default: text/plain; charset=utf-8)"),
+        )
+        .arg(
+            clap::Arg::new("server_header")
+                .long("server-header")
+                .help("Server header sent on responses that don't set one (default: Mocket/<version>)"),
+        )
+        .arg(
+            clap::Arg::new("no_server_header")
+                .long("no-server-header")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with("server_header")
+                .help("Leaves the Server header out of responses"),
+        )
+        .arg(
+            clap::Arg::new("compress")
+                .long("compress")
+                .value_delimiter(',')
+                .help("Compresses responses with these encodings, in order of preference (gzip, deflate, br)"),
+        )
+        .arg(
+            clap::Arg::new("compression_level")
+                .long("compression-level")
+                .value_parser(clap::value_parser!(u32))
+                .help("Compression level, 0-9 for gzip and deflate or 0-11 for br"),
+        )
+        .arg(
+            clap::Arg::new("strong_etags")
+                .long("strong-etags")
+                .action(clap::ArgAction::SetTrue)
+                .help("Derives static file ETags from their contents instead of size and modification time"),
+        )
+        .arg(
+            clap::Arg::new("reject_malformed_json")
+                .long("reject-malformed-json")
+                .action(clap::ArgAction::SetTrue)
+                .help("Replies 400 to malformed JSON or form-data request bodies instead of forwarding them"),
+        )
+        .arg(
+            clap::Arg::new("trace_bodies")
+                .long("trace-bodies")
+                .num_args(0..=1)
+                .default_missing_value("1024")
+                .value_parser(clap::value_parser!(usize))
+                .help("Logs request and response bodies, cut off after this many bytes (default: 1024)"),
+        )
+        .arg(
+            clap::Arg::new("redact")
+                .long("redact")
+                .value_delimiter(',')
+                .help("Header and JSON field names hidden by --trace-bodies (default: authorization,cookie,password)"),
+        )
+        .arg(
+            clap::Arg::new("trusted_proxies")
+                .long("trusted-proxies")
+                .value_delimiter(',')
+                .help("IPs or CIDR blocks of proxies whose Forwarded and X-Forwarded-* headers are believed"),
+        )
+        .arg(
+            clap::Arg::new("threads")
+                .long("threads")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Number of worker threads (default: one per core; 1 runs everything on the main thread)"),
+        )
+        .arg(
+            clap::Arg::new("ready_timeout")
+                .long("ready-timeout")
+                .value_parser(clap::value_parser!(u64))
+                .help("Answers 503 until the guest sends runtime.ready, exiting if it takes longer than this many seconds"),
+        )
+        .arg(
+            clap::Arg::new("idle_timeout")
+                .long("idle-timeout")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Exits after this many seconds without a request in flight"),
+        )
+        .arg(
+            clap::Arg::new("shutdown_timeout")
+                .long("shutdown-timeout")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64))
+                .help("How long a graceful shutdown waits for responses in flight before abandoning them and exiting (default: 30)"),
+        )
+        .arg(
+            clap::Arg::new("module_cache")
+                .long("module-cache")
+                .help("Keeps compiled modules in this directory so later starts skip compiling them"),
+        )
+        .arg(
+            clap::Arg::new("compiler")
+                .long("compiler")
+                .value_parser(["auto", "cranelift", "winch"])
+                .help("Compiles with the optimizing Cranelift or the much faster baseline Winch, for slower code (default: auto, which is cranelift)"),
+        )
+        .arg(
+            clap::Arg::new("opt_level")
+                .long("opt-level")
+                .value_parser(["none", "speed", "speed-and-size"])
+                .help("How hard Cranelift optimizes; none compiles faster for slower code (default: speed)"),
+        )
+        .arg(
+            clap::Arg::new("no_parallel_compilation")
+                .long("no-parallel-compilation")
+                .action(clap::ArgAction::SetTrue)
+                .help("Compiles on one core, for a slower start without a burst of CPU and memory"),
+        )
+        .arg(
+            clap::Arg::new("isolation_pool")
+                .long("isolation-pool")
+                .value_parser(clap::value_parser!(usize))
+                .help("Answers each request with a fresh guest instance, keeping this many ready (0: one shared instance)"),
+        )
+        .arg(
+            clap::Arg::new("user")
+                .long("user")
+                .help("Switches to this user, by name or uid, once the port is bound (Unix only)"),
+        )
+        .arg(
+            clap::Arg::new("group")
+                .long("group")
+                .help("Switches to this group, by name or gid, once the port is bound (default: the user's; Unix only)"),
+        )
+        .arg(
+            clap::Arg::new("allow_methods")
+                .long("allow-methods")
+                .value_name("METHODS")
+                .value_delimiter(',')
+                .help("Answers 405 to requests with other methods than these (default: GET, POST, PUT, DELETE, HEAD, OPTIONS, CONNECT, TRACE, PATCH)"),
+        )
+        .arg(
+            clap::Arg::new("method_override")
+                .long("method-override")
+                .action(clap::ArgAction::SetTrue)
+                .help("Handles POST requests as the PUT, PATCH or DELETE named by an X-HTTP-Method-Override header or a _method query or form field"),
+        )
+        .arg(
+            clap::Arg::new("json_rpc")
+                .long("json-rpc")
+                .action(clap::ArgAction::SetTrue)
+                .help("Takes JSON-RPC 2.0 POSTs to --json-rpc-path apart, handing the guest each call as an rpc.call event"),
+        )
+        .arg(
+            clap::Arg::new("json_rpc_path")
+                .long("json-rpc-path")
+                .value_name("PATH")
+                .help("Where --json-rpc serves JSON-RPC (default: /rpc)"),
+        )
+        .arg(
+            clap::Arg::new("max_queued_requests")
+                .long("max-queued-requests")
+                .value_parser(clap::value_parser!(usize))
+                .help("Answers 503 to requests arriving while this many wait for the guest (default: 0, unlimited)"),
+        )
+        .arg(
+            clap::Arg::new("max_connections")
+                .long("max-connections")
+                .value_parser(clap::value_parser!(usize))
+                .help("Serves at most this many connections at once (default: 0, unlimited)"),
+        )
+        .arg(
+            clap::Arg::new("connection_overflow")
+                .long("connection-overflow")
+                .value_parser(["reject", "queue", "block"])
+                .help("Past --max-connections, answers 503 and closes, waits --connection-queue-timeout for a free slot, or stops accepting and leaves connections in the OS backlog (default: reject)"),
+        )
+        .arg(
+            clap::Arg::new("connection_queue_timeout")
+                .long("connection-queue-timeout")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64))
+                .help("How long --connection-overflow queue keeps a connection waiting before answering 503 (default: 5)"),
+        )
+        .arg(
+            clap::Arg::new("max_response_body")
+                .long("max-response-body")
+                .value_name("BYTES")
+                .value_parser(clap::value_parser!(usize))
+                .help("Answers 500 rather than send a response body from the guest over this size (default: 0, unlimited)"),
+        )
+        .arg(
+            clap::Arg::new("response_body_warning")
+                .long("response-body-warning")
+                .value_name("BYTES")
+                .value_parser(clap::value_parser!(usize))
+                .help("Logs a warning, at most every 10 seconds, when the guest sends a response body over this size (default: 0, never)"),
+        )
+        .arg(
+            clap::Arg::new("truncate_responses")
+                .long("truncate-responses")
+                .action(clap::ArgAction::SetTrue)
+                .help("Cuts response bodies over --max-response-body off at the limit instead of failing them"),
+        )
+        .arg(
+            clap::Arg::new("warmup")
+                .long("warmup")
+                .action(clap::ArgAction::SetTrue)
+                .help("Sends the config file's warmupRequests through the guest, discarding the responses, before accepting connections"),
+        )
+        .arg(
+            clap::Arg::new("admin")
+                .long("admin")
+                .value_name("[ADDRESS:]PORT")
+                .help("Serves the admin API, listing and killing requests the guest hasn't answered, here (default address: 127.0.0.1)"),
+        )
+        .arg(
+            clap::Arg::new("admin_token")
+                .long("admin-token")
+                .value_name("TOKEN")
+                .help("The bearer token admin API requests need; prefer adminToken in --config, out of the process list"),
+        )
+        .arg(
+            clap::Arg::new("otlp_endpoint")
+                .long("otlp-endpoint")
+                .value_name("URL")
+                .help("Traces requests, exporting the spans with OTLP over HTTP to this collector, e.g. http://localhost:4318 (needs the otel feature)"),
+        )
+        .arg(
+            clap::Arg::new("otel_service_name")
+                .long("otel-service-name")
+                .value_name("NAME")
+                .help("The service.name of the exported spans (default: mocketd)"),
+        )
+        .arg(
+            clap::Arg::new("profile")
+                .long("profile")
+                .action(clap::ArgAction::SetTrue)
+                .help("Logs the fuel and memory each request uses in the guest (with --log 2)"),
+        )
+        .get_matches();
 
-use anyhow::anyhow;
-use nodehttp::Response;
+    if let Some(("bench", matches)) = matches.subcommand() {
+        bench(matches);
+    }
 
-use serde_json::json;
-use serde_json::Value;
-use std::collections::HashMap;
-use std::fs;
-use std::process;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
-use wasmtime::*;
+    let wasm_path = matches.get_one::<String>("wasm_file").unwrap();
 
-static LOG_LEVEL: AtomicUsize = AtomicUsize::new(0);
+    let config = load_config(&matches).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
 
-fn set_log_level(level: usize) {
-    LOG_LEVEL.store(level, Ordering::Relaxed);
-}
+    let log_level = config.log.unwrap_or(0);
 
-fn log(level: usize, message: &str) {
-    if level <= LOG_LEVEL.load(Ordering::Relaxed) {
-        println!("{}", message);
+    // Set log level (this is just an example, adapt to your logging needs); JSON logs stay
+    // JSON only
+    if config.log_format.as_deref() != Some("json") {
+        match log_level {
+            0 => println!("Log level: 0 (No logs)"),
+            1 => println!("Log level: 1 (Minimal logs)"),
+            2 => println!("Log level: 2 (Verbose logs)"),
+            _ => println!("Unknown log level: {}", log_level),
+        }
     }
-}
 
-static mut WASM_STORE: Option<Store<()>> = None;
-static mut WASM_INSTANCE: Option<Instance> = None;
+    if let Err(err) = apply(&config) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
 
-#[macro_use]
-extern crate lazy_static;
+    if let Some(port) = config.port {
+        mocketd::set_port(port);
+    }
 
-lazy_static! {
-    static ref RESPONSE_MAP: Arc<Mutex<HashMap<usize, Response>>> =
-        Arc::new(Mutex::new(HashMap::new()));
-    static ref NEXT_ID: AtomicUsize = AtomicUsize::new(0);
-}
+    if let Some(fd) = config.fd {
+        #[cfg(unix)]
+        if let Err(err) = mocketd::set_listen_fd(fd) {
+            eprintln!("Failed to use fd {}: {}", fd, err);
+            process::exit(1);
+        }
+        #[cfg(not(unix))]
+        {
+            eprintln!("Listening on fd {} is only supported on Unix", fd);
+            process::exit(1);
+        }
+    }
 
-// Define the function to initialize WASM and return an instance and store
-fn init_wasm(wasm_path: &str) -> (Store<()>, Instance) {
-    let engine = Engine::default();
-    let mut store = Store::new(&engine, ());
-    let mut linker = Linker::new(&engine);
-
-    // Define function types
-    let buffer = Arc::new(Mutex::new(Vec::new()));
-    let h_sd_ty = FuncType::new(&engine, vec![ValType::I32], vec![]);
-    let h_se_ty = FuncType::new(&engine, vec![], vec![]);
-    let print_char_ty = FuncType::new(&engine, vec![ValType::I32], vec![]);
-
-    // Define h_sd function
-    let buffer_for_h_sd = Arc::clone(&buffer);
-    linker
-        .func_new("__h", "h_sd", h_sd_ty, move |_, params: &[Val], _| {
-            if let [Val::I32(ch)] = params {
-                buffer_for_h_sd.lock().unwrap().push(*ch as u16);
-            }
-            Ok(())
-        })
-        .unwrap();
-
-    // Define h_se function
-    let buffer_for_h_se = Arc::clone(&buffer);
-    linker
-        .func_new("__h", "h_se", h_se_ty, move |_, _, _| {
-            let mut data = buffer_for_h_se.lock().unwrap();
-            if !data.is_empty() {
-                if let Ok(utf8_string) = String::from_utf16(&data) {
-                    let clean_string = utf8_string.replace("\0", "");
-                    log(1, &format!("Received JSON RAW: {}", clean_string));
-                    if let Ok(json_value) = serde_json::from_str::<Value>(&clean_string) {
-                        log(1, &format!("Received JSON Parse: {}", json_value));
-                        // tokio::spawn(async move {
-                        handle_receive(json_value);
-                        // });
-                    } else {
-                        eprintln!("Failed to parse JSON.");
-                        println!("{}", clean_string);
-                    }
-                }
-                // Clear the buffer after processing
-                data.clear();
-            }
-            Ok(())
-        })
-        .unwrap();
-
-    // Define `spectest::print_char` function
-    let print_buffer = Arc::new(Mutex::new(Vec::new()));
-    linker
-        .func_new(
-            "spectest",
-            "print_char",
-            print_char_ty,
-            move |_, params: &[Val], _| {
-                if let [Val::I32(ch)] = params {
-                    let mut buffer = print_buffer.lock().unwrap();
-                    if *ch == '\n' as i32 {
-                        println!("{}", String::from_utf16(&buffer).unwrap());
-                        buffer.clear();
-                    } else if *ch != '\r' as i32 {
-                        buffer.push(*ch as u16);
-                    }
-                }
-                Ok(())
-            },
-        )
-        .unwrap();
+    match config.ip_stack.as_deref() {
+        Some("v4") => mocketd::set_ip_stack(IpStack::V4),
+        Some("v6") => mocketd::set_ip_stack(IpStack::V6),
+        Some("dual") | None => mocketd::set_ip_stack(IpStack::Dual),
+        Some(stack) => {
+            eprintln!("Unknown ipStack {:?}: expected v4, v6 or dual", stack);
+            process::exit(1);
+        }
+    }
 
-    // Load and compile WASM module
-    let wasm_bytes = fs::read(wasm_path).unwrap_or_else(|err| {
-        eprintln!("Failed to read file {}: {}", wasm_path, err);
-        process::exit(1);
-    });
-    let module = Module::new(&engine, &wasm_bytes).unwrap_or_else(|err| {
-        eprintln!("Failed to create module: {}", err);
+    if let Err(err) = mocketd::set_run_as(config.user.as_deref(), config.group.as_deref()) {
+        eprintln!("{}", err);
         process::exit(1);
-    });
-
-    // Instantiate the WASM module
-    let instance = linker
-        .instantiate(&mut store, &module)
-        .unwrap_or_else(|err| {
-            eprintln!("Failed to instantiate module: {}", err);
-            process::exit(1);
-        });
+    }
 
-    (store, instance)
-}
+    if let Some(secs) = config.ready_timeout {
+        mocketd::set_ready_timeout(Duration::from_secs(secs));
+    }
 
-fn h_rd<T>(store: &mut Store<T>, instance: &Instance, ch: i32) -> Result<()> {
-    let start_func = instance
-        .get_func(store.as_context_mut(), "h_rd")
-        .ok_or_else(|| anyhow!("h_rd function not found"))?;
-    start_func.call(store.as_context_mut(), &[wasmtime::Val::I32(ch)], &mut [])?;
+    if let Some(secs) = config.idle_timeout {
+        mocketd::set_idle_timeout(Duration::from_secs(secs));
+    }
 
-    Ok(())
-}
+    if config.warmup == Some(true) {
+        let warmed = match &config.warmup_requests {
+            Some(requests) if !requests.is_empty() => mocketd::set_warmup(requests),
+            _ => Err("Warming up needs warmupRequests in the config file".to_string()),
+        };
+        if let Err(err) = warmed {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
 
-fn h_re<T>(store: &mut Store<T>, instance: &Instance) -> Result<()> {
-    let start_func = instance
-        .get_func(store.as_context_mut(), "h_re")
-        .ok_or_else(|| anyhow!("h_re function not found"))?;
-    start_func.call(store.as_context_mut(), &[], &mut [])?;
+    if let Some(tls) = &config.tls {
+        let hosts: Vec<(&str, &str, &str)> = tls
+            .hosts
+            .iter()
+            .flatten()
+            .map(|(host, TlsHost { cert, key })| (host.as_str(), cert.as_str(), key.as_str()))
+            .collect();
+        let loaded = mocketd::set_tls(&tls.cert, &tls.key, tls.client_ca.as_deref())
+            .and_then(|()| mocketd::set_tls_hosts(&hosts));
+        if let Err(err) = loaded {
+            eprintln!("Failed to load TLS configuration: {}", err);
+            process::exit(1);
+        }
+    }
 
-    Ok(())
-}
+    // The guest runs on a single store behind a lock, so extra threads only help with I/O
+    // (TLS, reading requests, writing responses), unless requests get instances of their own
+    // with --isolation-pool
+    let runtime = match config.threads {
+        Some(1) => tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build(),
+        Some(threads) if threads > 1 => tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(threads)
+            .enable_all()
+            .build(),
+        _ => tokio::runtime::Runtime::new(),
+    }
+    .unwrap_or_else(|err| {
+        eprintln!("Failed to start the async runtime: {}", err);
+        process::exit(1);
+    });
 
-fn send_event(event_type: &str, data: Value) {
-    let store = unsafe { WASM_STORE.as_mut() };
-    let instance = unsafe { WASM_INSTANCE.as_ref() };
-    match (store, instance) {
-        (Some(store), Some(instance)) => {
-            let json = json!([event_type, data]).to_string();
-            let utf16: Vec<u16> = json.encode_utf16().collect();
-            let mut uint8array = Vec::with_capacity(utf16.len() * 2);
-            for &word in utf16.iter() {
-                uint8array.push((word >> 8) as u8);
-                uint8array.push(word as u8);
+    runtime.block_on(async {
+        // Initialize WASM and run the guest
+        // Bound before the guest's ports, while root (if we were) is still at hand
+        if let Some(address) = &config.admin {
+            let started = admin_address(address).and_then(|address| {
+                let token = config.admin_token.as_deref().unwrap_or("");
+                mocketd::start_admin(address, token)
+                    .map_err(|err| format!("Failed to serve the admin API on {}: {}", address, err))
+            });
+            if let Err(err) = started {
+                eprintln!("{}", err);
+                process::exit(1);
             }
-            for &byte in uint8array.iter() {
-                let _ = h_rd(store, instance, byte as i32);
-            }
-            let _ = h_re(store, instance);
         }
 
-        _ => {
-            eprintln!("WASM not initialized");
-            return;
+        let mocket = Runtime::new(wasm_path.as_str());
+        mocket.start();
+
+        // Serve till ctrl c is pressed, reloading on SIGHUP and dumping diagnostics on SIGUSR1
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let mut hangup = signal(SignalKind::hangup()).unwrap();
+            let mut user1 = signal(SignalKind::user_defined1()).unwrap();
+            loop {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => break,
+                    _ = hangup.recv() => reload(&matches, &config, &mocket).await,
+                    _ = user1.recv() => mocketd::dump_diagnostics(),
+                }
+            }
         }
-    }
+        #[cfg(not(unix))]
+        tokio::signal::ctrl_c().await.unwrap();
+        mocketd::flush_guest_output();
+        process::exit(0);
+    })
 }
 
-#[tokio::main]
-async fn main() {
-    let matches = clap::Command::new("Mocket Runtime")
-        .version("1.0")
-        .author("oboard <oboard@outlook.com>")
-        .about("a WebAssembly runtime for Mocket")
+fn bench_command() -> clap::Command {
+    clap::Command::new("bench")
+        .about("Serves a module in-process and measures its throughput and latency under load")
         .arg(
             clap::Arg::new("wasm_file")
                 .help("Path to the WebAssembly file")
@@ -187,237 +541,543 @@ async fn main() {
                 .index(1),
         )
         .arg(
-            clap::Arg::new("log_level")
-                .short('l')
-                .long("log")
-                .help("Sets the log level (0: no logs, 1: minimal logs, 2: verbose logs)"),
+            clap::Arg::new("config")
+                .short('c')
+                .long("config")
+                .help("Loads options from a JSON file, to measure them"),
         )
-        .get_matches();
+        .arg(
+            clap::Arg::new("connections")
+                .long("connections")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Concurrent connections, each with one request at a time (default: 10)"),
+        )
+        .arg(
+            clap::Arg::new("duration")
+                .long("duration")
+                .value_parser(clap::value_parser!(f64))
+                .help("Seconds to run for (default: 10, or until --requests)"),
+        )
+        .arg(
+            clap::Arg::new("requests")
+                .long("requests")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Stops after this many requests"),
+        )
+        .arg(
+            clap::Arg::new("rate")
+                .long("rate")
+                .value_parser(clap::value_parser!(f64))
+                .help("Requests per second across all connections (default: as fast as possible)"),
+        )
+        .arg(
+            clap::Arg::new("method")
+                .long("method")
+                .help("Request method (default: GET, or POST with --payload)"),
+        )
+        .arg(
+            clap::Arg::new("path")
+                .long("path")
+                .default_value("/")
+                .help("Request path"),
+        )
+        .arg(
+            clap::Arg::new("payload")
+                .long("payload")
+                .value_parser(clap::value_parser!(usize))
+                .help("Bytes of request body (default: 0)"),
+        )
+        .arg(
+            clap::Arg::new("keep_alive")
+                .long("keep-alive")
+                .value_parser(["on", "off", "both"])
+                .default_value("both")
+                .help("Reuses connections, opens one per request, or runs both to compare them"),
+        )
+}
 
+// Runs `mocketd bench` and exits
+fn bench(matches: &ArgMatches) -> ! {
     let wasm_path = matches.get_one::<String>("wasm_file").unwrap();
-    let log_level = (*matches
-        .get_one::<String>("log_level")
-        .unwrap_or(&"0".to_string()))
-    .parse::<usize>()
-    .unwrap_or(0);
-
-    // Set log level (this is just an example, adapt to your logging needs)
-    match log_level {
-        0 => println!("Log level: 0 (No logs)"),
-        1 => println!("Log level: 1 (Minimal logs)"),
-        2 => println!("Log level: 2 (Verbose logs)"),
-        _ => println!("Unknown log level: {}", log_level),
-    }
-
-    set_log_level(log_level);
-
-    // Initialize WASM and get store and instance
-    let (store, instance) = init_wasm(&wasm_path);
-    unsafe {
-        WASM_STORE = Some(store);
-        WASM_INSTANCE = Some(instance);
-    }
-    // Optionally call '_start' if it exists
-    let instance = unsafe { WASM_INSTANCE.as_ref().unwrap() };
-    let mut store: Store<()> = unsafe { WASM_STORE.take().unwrap() };
-    if let Ok(start) = instance.get_typed_func::<(), ()>(&mut store, "_start") {
-        if let Err(err) = start.call(&mut store, ()) {
-            log(1, &format!("Failed to execute '_start': {}", err));
+
+    let config = match matches.get_one::<String>("config") {
+        Some(path) => Config::load(path).unwrap_or_else(|err| {
+            eprintln!("Failed to load config {}: {}", path, err);
             process::exit(1);
-        }
-    } else {
-        log(2, &format!("No '_start' function found in {}", wasm_path));
+        }),
+        None => Config::default(),
+    };
+    if let Err(err) = apply(&config) {
+        eprintln!("{}", err);
+        process::exit(1);
     }
 
-    unsafe {
-        WASM_STORE = Some(store);
-        WASM_INSTANCE = Some(*instance);
+    let payload = matches.get_one::<usize>("payload").copied().unwrap_or(0);
+    let method = match matches.get_one::<String>("method") {
+        Some(method) => method.to_ascii_uppercase(),
+        None if payload > 0 => "POST".to_string(),
+        None => "GET".to_string(),
+    };
+    let requests = matches.get_one::<u64>("requests").copied();
+    let duration = match matches.get_one::<f64>("duration") {
+        Some(secs) if *secs > 0.0 => Some(Duration::from_secs_f64(*secs)),
+        Some(_) => {
+            eprintln!("--duration must be positive");
+            process::exit(1);
+        }
+        None if requests.is_some() => None,
+        None => Some(Duration::from_secs(10)),
+    };
+    let rate = matches.get_one::<f64>("rate").copied();
+    if rate.is_some_and(|rate| rate <= 0.0) {
+        eprintln!("--rate must be positive");
+        process::exit(1);
     }
+    let options = BenchOptions {
+        connections: matches
+            .get_one::<u64>("connections")
+            .map_or(10, |n| *n as usize),
+        duration,
+        requests,
+        rate,
+        method,
+        path: matches.get_one::<String>("path").unwrap().clone(),
+        payload,
+        keep_alive: match matches.get_one::<String>("keep_alive").unwrap().as_str() {
+            "on" => Some(true),
+            "off" => Some(false),
+            _ => None,
+        },
+    };
 
-    // keep the main thread alive till ctrl c is pressed
-    tokio::signal::ctrl_c().await.unwrap();
+    let runtime = tokio::runtime::Runtime::new().unwrap_or_else(|err| {
+        eprintln!("Failed to start the async runtime: {}", err);
+        process::exit(1);
+    });
+    if let Err(err) = runtime.block_on(mocketd::bench::run(wasm_path, &options)) {
+        eprintln!("Benchmark failed: {}", err);
+        process::exit(1);
+    }
     process::exit(0);
 }
 
-fn map_to_iter(
-    map: serde_json::Map<String, Value>,
-) -> impl IntoIterator<Item = (impl AsRef<str>, impl AsRef<str>)> {
-    map.into_iter().filter_map(|(key, value)| {
-        // Try to convert the value to a string reference
-        if let Value::String(s) = value {
-            Some((key, s)) // Return a tuple with (key, value) where both are &str
-        } else {
-            None // Ignore non-string values
+// The config file, if any, with command-line flags applied on top
+fn load_config(matches: &ArgMatches) -> Result<Config, String> {
+    let mut config = match matches.get_one::<String>("config") {
+        Some(path) => {
+            Config::load(path).map_err(|err| format!("Failed to load config {}: {}", path, err))?
         }
-    })
-}
+        None => Config::default(),
+    };
 
-// Function to handle the parsed JSON object
-fn handle_receive(json_value: Value) -> std::io::Result<()> {
-    log(1, &format!("Received JSON: {}", json_value));
-
-    fn listen(port: u16) {
-        log(1, &format!("Listening on port {}", port));
-
-        let server = nodehttp::create_server(|req, mut res| {
-            log(2, &format!("Received request: {} {}", req.method, req.path));
-            // Box::pin(async move {
-            //     // 设置响应头
-            //     res.write_head(200, HashMap::from([("Content-Type", "text/plain")]))
-            //         .await?;
-
-            //     // 向客户端发送响应内容
-            //     res.end("Hello, World!\n").await?;
-            //     Ok(())
-            // })
-            Box::pin(async move {
-                // if [
-                //     "GET", "POST", "PUT", "DELETE", "HEAD", "OPTIONS", "CONNECT", "TRACE", "PATCH",
-                // ]
-                // .contains(&(req.method.as_str()))
-                // {
-                //     let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
-                //     let data = json!([
-                //         {
-                //             "method": req.method,
-                //             "url": req.path,
-                //         },
-                //         {
-                //             "id": id,
-                //         }
-                //     ]);
-                //     // log(1, &format!("{}", data));
-                //     send_event("http.request", data);
-
-                //     // 存储 ID 和响应的映射
-                //     let mut response_map = RESPONSE_MAP.lock().unwrap();
-                //     response_map.insert(id, res);
-                //     Ok(())
-                // } else {
-                // log(2, &format!("Invalid method `{}`", req.method));
-                // 设置响应头
-                res.write_head(200, HashMap::from([("Content-Type", "text/plain")]))
-                    .await?;
-
-                // 向客户端发送响应内容
-                res.end("Hello, World!\n").await;
-                Ok(())
-                // }
-            })
+    // Command-line flags override the config file
+    if let Some(log_level) = matches.get_one::<String>("log_level") {
+        config.log = Some(log_level.parse::<usize>().unwrap_or(0));
+    }
+    if let Some(format) = matches.get_one::<String>("log_format") {
+        config.log_format = Some(format.clone());
+    }
+    if let Some(port) = matches.get_one::<u16>("port") {
+        config.port = Some(*port);
+        config.fd = None;
+    }
+    if let Some(fd) = matches.get_one::<i32>("fd") {
+        config.fd = Some(*fd);
+        config.port = None;
+    }
+    if let Some(stack) = matches.get_one::<String>("ip_stack") {
+        config.ip_stack = Some(stack.clone());
+    }
+    // systemd socket activation passes sockets from fd 3 on
+    if config.port.is_none() && config.fd.is_none() {
+        let for_us = env::var("LISTEN_PID").is_ok_and(|pid| pid == process::id().to_string());
+        let count = env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|n| n.parse::<i32>().ok());
+        if for_us && count.is_some_and(|n| n >= 1) {
+            config.fd = Some(3);
+        }
+    }
+    if let (Some(cert), Some(key)) = (
+        matches.get_one::<String>("tls_cert"),
+        matches.get_one::<String>("tls_key"),
+    ) {
+        let hosts = match matches.get_many::<String>("tls_host") {
+            Some(hosts) => Some(hosts.map(|host| tls_host(host)).collect::<Result<_, _>>()?),
+            None => None,
+        };
+        config.tls = Some(TlsConfig {
+            cert: cert.clone(),
+            key: key.clone(),
+            client_ca: matches.get_one::<String>("tls_client_ca").cloned(),
+            hosts,
         });
+    }
+    if matches.get_flag("http2") {
+        config.http2 = Some(true);
+    }
+    if matches.get_flag("connection_events") {
+        config.connection_events = Some(true);
+    }
+    if let Some(path) = matches.get_one::<String>("access_log") {
+        config.access_log = Some(path.clone());
+    }
+    if let Some(max_len) = matches.get_one::<usize>("content_length_threshold") {
+        config.content_length_threshold = Some(*max_len);
+    }
+    if let Some(pages) = matches.get_many::<(u16, String)>("error_page") {
+        config
+            .error_pages
+            .get_or_insert_with(HashMap::new)
+            .extend(pages.cloned());
+    }
+    if let Some(path) = matches.get_one::<String>("error_template") {
+        config.error_template = Some(path.clone());
+    }
+    if let Some(max_bytes) = matches.get_one::<usize>("response_cache") {
+        config.response_cache = Some(*max_bytes);
+    }
+    if let Some(path) = matches.get_one::<String>("diagnostics_file") {
+        config.diagnostics_file = Some(path.clone());
+    }
+    if let Some(content_type) = matches.get_one::<String>("default_content_type") {
+        config.default_content_type = Some(content_type.clone());
+    }
+    if let Some(server) = matches.get_one::<String>("server_header") {
+        config.server_header = Some(server.clone());
+    }
+    if matches.get_flag("no_server_header") {
+        config.server_header = Some(String::new());
+    }
+    if let Some(encodings) = matches.get_many::<String>("compress") {
+        config.compress = Some(encodings.cloned().collect());
+    }
+    if let Some(level) = matches.get_one::<u32>("compression_level") {
+        config.compression_level = Some(*level);
+    }
+    if matches.get_flag("strong_etags") {
+        config.strong_etags = Some(true);
+    }
+    if matches.get_flag("reject_malformed_json") {
+        config.reject_malformed_json = Some(true);
+    }
+    if let Some(proxies) = matches.get_many::<String>("trusted_proxies") {
+        config.trusted_proxies = Some(proxies.cloned().collect());
+    }
+    if let Some(threads) = matches.get_one::<u64>("threads") {
+        config.threads = Some(*threads as usize);
+    }
+    if let Some(max_len) = matches.get_one::<usize>("trace_bodies") {
+        config.trace_bodies = Some(*max_len);
+    }
+    if let Some(names) = matches.get_many::<String>("redact") {
+        config.redact = Some(names.cloned().collect());
+    }
+    if let Some(secs) = matches.get_one::<u64>("ready_timeout") {
+        config.ready_timeout = Some(*secs);
+    }
+    if let Some(secs) = matches.get_one::<u64>("idle_timeout") {
+        config.idle_timeout = Some(*secs);
+    }
+    if let Some(secs) = matches.get_one::<u64>("shutdown_timeout") {
+        config.shutdown_timeout = Some(*secs);
+    }
+    if matches.get_flag("profile") {
+        config.profile = Some(true);
+    }
+    if let Some(dir) = matches.get_one::<String>("module_cache") {
+        config.module_cache = Some(dir.clone());
+    }
+    if let Some(compiler) = matches.get_one::<String>("compiler") {
+        config.compiler = Some(compiler.clone());
+    }
+    if let Some(level) = matches.get_one::<String>("opt_level") {
+        config.opt_level = Some(level.clone());
+    }
+    if matches.get_flag("no_parallel_compilation") {
+        config.parallel_compilation = Some(false);
+    }
+    if let Some(pool_size) = matches.get_one::<usize>("isolation_pool") {
+        config.isolation_pool = Some(*pool_size);
+    }
+    if let Some(methods) = matches.get_many::<String>("allow_methods") {
+        config.allowed_methods = Some(methods.cloned().collect());
+    }
+    if matches.get_flag("method_override") {
+        config.method_override = Some(true);
+    }
+    if matches.get_flag("json_rpc") {
+        config.json_rpc = Some(true);
+    }
+    if let Some(path) = matches.get_one::<String>("json_rpc_path") {
+        config.json_rpc_path = Some(path.clone());
+    }
+    if let Some(max) = matches.get_one::<usize>("max_queued_requests") {
+        config.max_queued_requests = Some(*max);
+    }
+    if let Some(max) = matches.get_one::<usize>("max_connections") {
+        config.max_connections = Some(*max);
+    }
+    if let Some(overflow) = matches.get_one::<String>("connection_overflow") {
+        config.connection_overflow = Some(overflow.clone());
+    }
+    if let Some(secs) = matches.get_one::<u64>("connection_queue_timeout") {
+        config.connection_queue_timeout = Some(*secs);
+    }
+    if let Some(max_bytes) = matches.get_one::<usize>("max_response_body") {
+        config.max_response_body = Some(*max_bytes);
+    }
+    if let Some(threshold) = matches.get_one::<usize>("response_body_warning") {
+        config.response_body_warning = Some(*threshold);
+    }
+    if matches.get_flag("truncate_responses") {
+        config.truncate_responses = Some(true);
+    }
+    if matches.get_flag("warmup") {
+        config.warmup = Some(true);
+    }
+    if let Some(user) = matches.get_one::<String>("user") {
+        config.user = Some(user.clone());
+    }
+    if let Some(group) = matches.get_one::<String>("group") {
+        config.group = Some(group.clone());
+    }
+    if let Some(address) = matches.get_one::<String>("admin") {
+        config.admin = Some(address.clone());
+    }
+    if let Some(token) = matches.get_one::<String>("admin_token") {
+        config.admin_token = Some(token.clone());
+    }
+    if let Some(endpoint) = matches.get_one::<String>("otlp_endpoint") {
+        config.otlp_endpoint = Some(endpoint.clone());
+    }
+    if let Some(name) = matches.get_one::<String>("otel_service_name") {
+        config.otel_service_name = Some(name.clone());
+    }
 
-        // 让服务器监听 3000 端口
-        tokio::spawn(async move { server.listen(port, || {}).await });
-    }
-
-    let handle_type = json_value[0].as_str();
-    let handle_data = &json_value[1];
-    match handle_type {
-        Some(t) => match t {
-            "http.listen" => {
-                let port = handle_data.as_f64();
-                match port {
-                    Some(port) => {
-                        listen(port as u16);
-                        Ok(())
-                    }
-                    _ => {
-                        eprintln!("Invalid port value");
-                        Ok(())
-                    }
-                }
-            }
-            // "http.writeHead" => {
-            //     if let Value::Array(vec) = handle_data {
-            //         match vec.as_slice() {
-            //             [Value::Number(id), Value::Number(status_code), Value::Object(headers)] => {
-            //                 let index = id.as_f64().unwrap_or(0f64) as usize;
-            //                 let response = unsafe { RESPONSE_STACK.get_mut(index) };
-            //                 let status_code = status_code.as_f64().unwrap_or(500f64) as u16;
-            //                 // let headers = headers;
-            //                 match response {
-            //                     Some(response) => {
-            //                         response
-            //                             .write_head(
-            //                                 status_code,
-            //                                 HashMap::from([("Content-Type", "text/plain")]),
-            //                             )
-            //                             .await?;
-            //                     }
-            //                     None => {
-            //                         eprintln!("Invalid response id");
-            //                         return Ok(());
-            //                     }
-            //                 }
-
-            //                 Ok(())
-            //             }
-            //             _ => {
-            //                 eprintln!("Invalid http.writeHead data");
-            //                 Ok(())
-            //             }
-            //         }
-            //     } else {
-            //         println!("Expected an array.");
-            //         Ok(())
-            //     }
-            // }
-            "http.end" => {
-                if let Value::Array(vec) = handle_data {
-                    match vec.as_slice() {
-                        [Value::Number(id), Value::Number(status_code), Value::Object(headers), body] =>
-                        {
-                            let index = id.as_f64().unwrap_or(0f64) as usize;
-                            let headers = headers;
-                            log(3, format!("index: {}", index).as_str());
-                            let mut response_map = RESPONSE_MAP.lock().unwrap();
-                            let response = response_map.remove(&index);
-                            match response {
-                                Some(mut response) => {
-                                    let _ = response.write_head(
-                                        status_code.as_f64().unwrap_or(500f64) as u16,
-                                        map_to_iter(headers.clone()),
-                                    );
-
-                                    // 如果是string则直接发送，如果是json object则strinify
-                                    match body {
-                                        Value::String(s) => {
-                                            let _ = response.end(s);
-                                        }
-                                        Value::Object(o) => {
-                                            let json_string = serde_json::to_string(o).unwrap();
-                                            let _ = response.end(&json_string);
-                                        }
-                                        _ => {
-                                            eprintln!("Invalid body type");
-                                        }
-                                    }
-                                    Ok(())
-                                }
-                                _ => {
-                                    eprintln!("Invalid response id");
-                                    Ok(())
-                                }
-                            }
-                        }
-                        _ => {
-                            eprintln!("Invalid http.end data");
-                            Ok(())
-                        }
-                    }
-                } else {
-                    println!("Expected an array.");
-                    Ok(())
-                }
-            }
-            _ => {
-                println!("Unknown method `{}`", t);
-                Ok(())
-            }
-        },
-        _ => {
-            println!("Invalid handle type");
-            Ok(())
+    Ok(config)
+}
+
+// Parses `--tls-host HOST=CERT,KEY`
+fn tls_host(value: &str) -> Result<(String, TlsHost), String> {
+    let invalid = || format!("Invalid --tls-host {:?}: expected HOST=CERT,KEY", value);
+    let (name, paths) = value.split_once('=').ok_or_else(invalid)?;
+    let (cert, key) = paths.split_once(',').ok_or_else(invalid)?;
+    if name.is_empty() || cert.is_empty() || key.is_empty() {
+        return Err(invalid());
+    }
+    let host = TlsHost {
+        cert: cert.to_string(),
+        key: key.to_string(),
+    };
+    Ok((name.to_string(), host))
+}
+
+// Parses `--admin`/`admin`: a port on the loopback interface, or an address and port
+fn admin_address(value: &str) -> Result<SocketAddr, String> {
+    match value.parse::<u16>() {
+        Ok(port) => Ok(SocketAddr::from(([127, 0, 0, 1], port))),
+        Err(_) => value
+            .parse()
+            .map_err(|_| format!("Invalid admin address {:?}: expected [ADDRESS:]PORT", value)),
+    }
+}
+
+// Parses `--error-page STATUS=FILE`
+fn error_page(value: &str) -> Result<(u16, String), String> {
+    let invalid = || format!("expected STATUS=FILE, got {:?}", value);
+    let (status, path) = value.split_once('=').ok_or_else(invalid)?;
+    let status = status.parse::<u16>().map_err(|_| invalid())?;
+    if !(400..600).contains(&status) || path.is_empty() {
+        return Err(invalid());
+    }
+    Ok((status, path.to_string()))
+}
+
+// Applies the settings that can change while running; the rest only take effect at startup
+fn apply(config: &Config) -> Result<(), String> {
+    if let Some(encodings) = &config.compress {
+        let encodings: Vec<&str> = encodings.iter().map(String::as_str).collect();
+        mocketd::set_compression(&encodings, config.compression_level)
+            .map_err(|err| format!("Invalid compression configuration: {}", err))?;
+    }
+
+    if let Some(path) = &config.access_log {
+        mocketd::set_access_log(path)
+            .map_err(|err| format!("Failed to open access log {}: {}", path, err))?;
+    }
+
+    mocketd::set_diagnostics_file(config.diagnostics_file.as_deref());
+
+    if let Some(max_len) = config.content_length_threshold {
+        mocketd::set_content_length_threshold(max_len);
+    }
+
+    mocketd::set_response_cache(config.response_cache.unwrap_or(0));
+
+    let pages: Vec<(u16, &str)> = config
+        .error_pages
+        .iter()
+        .flatten()
+        .map(|(status, path)| (*status, path.as_str()))
+        .collect();
+    mocketd::set_error_pages(&pages, config.error_template.as_deref())
+        .map_err(|err| format!("Failed to load error page {}", err))?;
+
+    mocketd::set_log_level(config.log.unwrap_or(0));
+    match config.log_format.as_deref() {
+        Some("text") | None => mocketd::set_log_format(LogFormat::Text),
+        Some("json") => mocketd::set_log_format(LogFormat::Json),
+        Some(format) => {
+            return Err(format!(
+                "Unknown logFormat {:?}: expected text or json",
+                format
+            ))
+        }
+    }
+
+    if let Some(content_type) = &config.default_content_type {
+        mocketd::set_default_content_type(content_type);
+    }
+
+    // An empty serverHeader leaves the header out
+    mocketd::set_server_header(config.server_header.as_deref())?;
+
+    mocketd::set_http2(config.http2.unwrap_or(false));
+
+    mocketd::set_connection_events(config.connection_events.unwrap_or(false));
+
+    mocketd::set_strong_etags(config.strong_etags.unwrap_or(false));
+
+    let proxies = config.trusted_proxies.as_deref().unwrap_or_default();
+    let proxies: Vec<&str> = proxies.iter().map(String::as_str).collect();
+    mocketd::set_trusted_proxies(&proxies)?;
+
+    if let Some(max_len) = config.trace_bodies {
+        let redact = match &config.redact {
+            Some(names) => names.iter().map(String::as_str).collect(),
+            None => vec!["authorization", "cookie", "password"],
+        };
+        mocketd::set_trace_bodies(max_len, &redact);
+    }
+
+    mocketd::set_profile(config.profile.unwrap_or(false));
+
+    mocketd::set_module_cache(config.module_cache.as_deref());
+
+    let strategy = match config.compiler.as_deref() {
+        Some("auto") | None => Strategy::Auto,
+        Some("cranelift") => Strategy::Cranelift,
+        Some("winch") => Strategy::Winch,
+        Some(compiler) => {
+            return Err(format!(
+                "Unknown compiler {:?}: expected auto, cranelift or winch",
+                compiler
+            ))
+        }
+    };
+    let opt_level = match config.opt_level.as_deref() {
+        Some("speed") | None => OptLevel::Speed,
+        Some("none") => OptLevel::None,
+        Some("speed-and-size") => OptLevel::SpeedAndSize,
+        Some(level) => {
+            return Err(format!(
+                "Unknown optLevel {:?}: expected none, speed or speed-and-size",
+                level
+            ))
         }
+    };
+    mocketd::set_compiler(
+        strategy,
+        opt_level,
+        config.parallel_compilation.unwrap_or(true),
+    )?;
+
+    mocketd::set_isolation_pool(config.isolation_pool.unwrap_or(0));
+
+    mocketd::set_max_queued_requests(config.max_queued_requests.unwrap_or(0));
+
+    let overflow = match config.connection_overflow.as_deref() {
+        Some("reject") | None => ConnectionOverflow::Reject,
+        Some("queue") => ConnectionOverflow::Queue(Duration::from_secs(
+            config.connection_queue_timeout.unwrap_or(5),
+        )),
+        Some("block") => ConnectionOverflow::Block,
+        Some(overflow) => {
+            return Err(format!(
+                "Unknown connectionOverflow {:?}: expected reject, queue or block",
+                overflow
+            ))
+        }
+    };
+    mocketd::set_max_connections(config.max_connections.unwrap_or(0), overflow);
+
+    mocketd::set_max_response_body(
+        config.max_response_body.unwrap_or(0),
+        config.truncate_responses.unwrap_or(false),
+    );
+
+    mocketd::set_response_body_warning(config.response_body_warning.unwrap_or(0));
+
+    mocketd::set_reject_malformed_json(config.reject_malformed_json.unwrap_or(false));
+
+    mocketd::set_method_override(config.method_override.unwrap_or(false));
+
+    let rpc_path = config.json_rpc_path.as_deref().unwrap_or("/rpc");
+    mocketd::set_json_rpc((config.json_rpc == Some(true)).then_some(rpc_path))?;
+
+    mocketd::set_shutdown_timeout(config.shutdown_timeout.map(Duration::from_secs));
+
+    let methods: Option<Vec<&str>> = config
+        .allowed_methods
+        .as_ref()
+        .map(|methods| methods.iter().map(String::as_str).collect());
+    mocketd::set_allowed_methods(methods.as_deref())?;
+
+    #[cfg(feature = "otel")]
+    mocketd::set_otlp_exporter(
+        config.otlp_endpoint.as_deref(),
+        config.otel_service_name.as_deref(),
+    )?;
+    #[cfg(not(feature = "otel"))]
+    if config.otlp_endpoint.is_some() {
+        return Err(
+            "OTLP export isn't available in this build; enable the otel feature".to_string(),
+        );
+    }
+
+    Ok(())
+}
+
+// Re-reads the config and reloads the guest; `running` is the config the server started with
+#[cfg(unix)]
+async fn reload(matches: &ArgMatches, running: &Config, mocket: &Runtime) {
+    println!("Reloading on SIGHUP");
+    let config = match load_config(matches) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Reload failed, keeping the current configuration: {}", err);
+            return;
+        }
+    };
+    if config.port != running.port
+        || config.fd != running.fd
+        || config.ip_stack != running.ip_stack
+        || config.tls != running.tls
+        || config.threads != running.threads
+        || config.admin != running.admin
+        || config.admin_token != running.admin_token
+    {
+        eprintln!(
+            "Changes to port, fd, ipStack, tls, threads and admin take effect after a restart"
+        );
+    }
+    if let Err(err) = apply(&config) {
+        eprintln!("Reload failed: {}", err);
+        return;
+    }
+    match mocket.reload().await {
+        Ok(()) => println!("Reloaded configuration and module"),
+        Err(err) => eprintln!("Reload failed, still running the previous guest: {}", err),
     }
 }
@@ -0,0 +1,60 @@
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use crate::nodehttp::{Request, RequestHandler, Response};
+
+/// What a middleware, and the guest handler at the end of the chain, resolve to.
+pub type MiddlewareFuture = Pin<Box<dyn Future<Output = Result<(), Box<dyn Error>>> + Send>>;
+
+pub(crate) type Middleware = dyn Fn(Request, Response, Next) -> MiddlewareFuture + Send + Sync;
+
+// The chain of the running `Runtime`, outermost first
+static CHAIN: Mutex<Option<Arc<[Arc<Middleware>]>>> = Mutex::new(None);
+
+pub(crate) fn install(chain: &[Arc<Middleware>]) {
+    *CHAIN.lock().unwrap() = (!chain.is_empty()).then(|| chain.into());
+}
+
+/// The rest of the chain after a middleware: the middleware registered after it, then the
+/// guest.
+pub struct Next {
+    chain: Arc<[Arc<Middleware>]>,
+    index: usize,
+    handler: RequestHandler,
+}
+
+impl Next {
+    /// Passes the request on, possibly changed. A middleware that answers the request itself
+    /// doesn't call this.
+    pub fn run(self, request: Request, response: Response) -> MiddlewareFuture {
+        match self.chain.get(self.index).cloned() {
+            Some(middleware) => {
+                let next = Next {
+                    index: self.index + 1,
+                    ..self
+                };
+                middleware(request, response, next)
+            }
+            None => (self.handler)(&request, response),
+        }
+    }
+}
+
+// Hands a request to the middleware chain, which ends with `handler`
+pub(crate) fn dispatch(
+    request: &Request,
+    response: Response,
+    handler: RequestHandler,
+) -> MiddlewareFuture {
+    let Some(chain) = CHAIN.lock().unwrap().clone() else {
+        return handler(request, response);
+    };
+    let next = Next {
+        chain,
+        index: 0,
+        handler,
+    };
+    next.run(request.clone(), response)
+}
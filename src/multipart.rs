@@ -0,0 +1,201 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::{json, Value};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::hash::BuildHasher;
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::{env, process};
+
+use crate::log;
+
+// Parts larger than this are written to a temp file instead of being inlined in the event
+const INLINE_PART_LIMIT: usize = 1024 * 1024;
+
+lazy_static! {
+    // Temp files backing each request's large parts, removed once the response ends
+    static ref UPLOADS: Mutex<HashMap<usize, Vec<PathBuf>>> = Mutex::new(HashMap::new());
+    // Keys the names of temp files, so other users of the temp directory can't guess them
+    static ref NAME_KEYS: RandomState = RandomState::new();
+}
+static NEXT_NAME: AtomicU64 = AtomicU64::new(0);
+
+// One part of a multipart/form-data body
+pub(crate) struct Part {
+    pub name: Option<String>,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub data: Vec<u8>,
+}
+
+// The boundary of a `multipart/form-data` content type, if that's what it is
+pub(crate) fn boundary(content_type: &str) -> Option<String> {
+    let mut params = split_params(content_type).into_iter();
+    let media_type = params.next()?;
+    if !media_type.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params.find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case("boundary")
+            .then(|| unquote(value.trim()).to_string())
+    })
+}
+
+// Splits a multipart body on `boundary` into its parts
+pub(crate) fn parse(body: &[u8], boundary: &str) -> Result<Vec<Part>, String> {
+    let delimiter = format!("--{boundary}").into_bytes();
+    let close_delimiter = format!("\r\n--{boundary}").into_bytes();
+
+    let mut pos = find(body, &delimiter, 0).ok_or("missing boundary")? + delimiter.len();
+    let mut parts = Vec::new();
+    loop {
+        if body[pos..].starts_with(b"--") {
+            return Ok(parts);
+        }
+        if !body[pos..].starts_with(b"\r\n") {
+            return Err("malformed boundary line".to_string());
+        }
+        pos += 2;
+
+        let header_end = find(body, b"\r\n\r\n", pos).ok_or("unterminated part headers")?;
+        let mut headers = HashMap::new();
+        for line in String::from_utf8_lossy(&body[pos..header_end]).split("\r\n") {
+            if let Some((key, value)) = line.split_once(':') {
+                headers.insert(key.trim().to_ascii_lowercase(), value.trim().to_string());
+            }
+        }
+
+        let data_start = header_end + 4;
+        let data_end = find(body, &close_delimiter, data_start).ok_or("unterminated part")?;
+
+        let mut part = Part {
+            name: None,
+            filename: None,
+            content_type: headers.remove("content-type"),
+            data: body[data_start..data_end].to_vec(),
+        };
+        if let Some(disposition) = headers.get("content-disposition") {
+            for param in split_params(disposition).into_iter().skip(1) {
+                match param.split_once('=') {
+                    Some((key, value)) if key.trim().eq_ignore_ascii_case("name") => {
+                        part.name = Some(unquote(value.trim()).to_string());
+                    }
+                    Some((key, value)) if key.trim().eq_ignore_ascii_case("filename") => {
+                        part.filename = Some(unquote(value.trim()).to_string());
+                    }
+                    _ => {}
+                }
+            }
+        }
+        parts.push(part);
+
+        pos = data_end + close_delimiter.len();
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|pos| pos + from)
+}
+
+// Splits a header value on `;`, leaving separators inside quoted strings alone
+fn split_params(value: &str) -> Vec<&str> {
+    let mut params = Vec::new();
+    let mut in_quotes = false;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => {
+                params.push(value[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    params.push(value[start..].trim());
+    params
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+// Describes the parts for the guest. Text parts are inlined as strings, binary ones as
+// base64, and parts over `INLINE_PART_LIMIT` are spilled to a temp file owned by `id`.
+pub(crate) fn to_json(parts: Vec<Part>, id: usize) -> Value {
+    let mut values = Vec::with_capacity(parts.len());
+    for part in parts {
+        let mut value = json!({
+            "name": part.name,
+            "filename": part.filename,
+            "contentType": part.content_type,
+            "size": part.data.len(),
+        });
+        if part.data.len() > INLINE_PART_LIMIT {
+            match spill(&part.data) {
+                Ok(path) => {
+                    value["path"] = json!(path);
+                    UPLOADS.lock().unwrap().entry(id).or_default().push(path);
+                }
+                Err(err) => {
+                    log(
+                        1,
+                        &format!("Failed to write upload to a temp file: {}", err),
+                    );
+                    value["error"] = json!(err.to_string());
+                }
+            }
+        } else {
+            match String::from_utf8(part.data) {
+                Ok(text) => value["data"] = json!(text),
+                Err(err) => {
+                    value["data"] = json!(STANDARD.encode(err.into_bytes()));
+                    value["encoding"] = json!("base64");
+                }
+            }
+        }
+        values.push(value);
+    }
+    Value::Array(values)
+}
+
+// Writes `data` to a new temp file only we can read, under a name no one can guess, and
+// never through a file that's already there (such as a planted symlink)
+fn spill(data: &[u8]) -> io::Result<PathBuf> {
+    let name = NAME_KEYS.hash_one((process::id(), NEXT_NAME.fetch_add(1, Ordering::Relaxed)));
+    let path = env::temp_dir().join(format!("mocket-upload-{:016x}", name));
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&path)?;
+    if let Err(err) = file.write_all(data) {
+        let _ = fs::remove_file(&path);
+        return Err(err);
+    }
+    Ok(path)
+}
+
+// Removes the temp files of a finished request
+pub(crate) fn cleanup(id: usize) {
+    if let Some(paths) = UPLOADS.lock().unwrap().remove(&id) {
+        for path in paths {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
@@ -1,119 +1,2144 @@
 use chrono::Utc;
+use serde_json::json;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::Write;
 use std::future::Future;
 use std::io;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::pin::Pin;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio_rustls::TlsAcceptor;
 
 // Define a type alias for the request handler function
 // FIXME: AsyncMut
-type RequestHandler =
+pub(crate) type RequestHandler =
     fn(&Request, Response) -> Pin<Box<dyn Future<Output = Result<(), Box<dyn Error>>> + Send>>;
 
+use crate::connection::ConnectionOverflow;
+use crate::error_page;
+use crate::forwarded::{self, Client};
+use crate::http2::{self, Http2Response, Rewind};
+use crate::privileges;
+use crate::rate_limit::RateLimiter;
+use crate::tls::{self, ClientIdentity};
+use crate::{access_log, connection, log, log_with, middleware, warmup};
+
+// Who is on the other end of a connection, as known before its first request
+#[derive(Clone)]
+pub(crate) struct Peer {
+    pub(crate) addr: SocketAddr,
+    // Set when the client presented a verified certificate (mutual TLS)
+    pub(crate) client_identity: Option<ClientIdentity>,
+    // Set when the client asked for a host with a certificate of its own, see `set_tls_hosts`
+    pub(crate) server_name: Option<String>,
+    // Set when the guest gets connection events
+    pub(crate) connection_id: Option<u64>,
+}
+
+// A client connection, plain TCP or TLS
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+pub type BoxedStream = Box<dyn Stream>;
+
+// The `Date` of responses with the second it's for, kept current by `tick_date` so
+// responses don't each format their own
+static DATE: RwLock<Option<(i64, Arc<String>)>> = RwLock::new(None);
+static DATE_TICKING: Once = Once::new();
+
+// How long an idle connection waits for its next request, unless the listener says otherwise
+const DEFAULT_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+// How long a request gets to arrive, head and body, once its first byte has; like Node's
+// `requestTimeout`, a slower one gets 408
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+pub(crate) const MAX_HEADER_SIZE: usize = 8192;
+// Requests with more header fields than this get 431, unless the listener says otherwise
+const DEFAULT_MAX_HEADERS: usize = 100;
+pub(crate) const MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+// Keep-alive connections are closed after this many requests, so clients reconnect
+const DEFAULT_MAX_REQUESTS_PER_CONNECTION: usize = 100;
+// How often rate limiters forget idle clients
+const RATE_LIMIT_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+// Incoming request ids longer than this are replaced rather than propagated
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(0);
+
+const DEFAULT_SERVER_HEADER: &str = concat!("Mocket/", env!("CARGO_PKG_VERSION"));
+
+lazy_static! {
+    // Sent as `Server` on every response that doesn't set its own
+    static ref SERVER_HEADER: Mutex<Option<String>> =
+        Mutex::new(Some(DEFAULT_SERVER_HEADER.to_string()));
+}
+
+/// Sets the `Server` header of responses: `None` for the default `Mocket/<version>`, an empty
+/// string to leave it out.
+pub fn set_server_header(value: Option<&str>) -> Result<(), String> {
+    let value = match value {
+        Some("") => None,
+        Some(value) if !is_valid_header("Server", value) => {
+            return Err(format!("invalid Server header {:?}", value));
+        }
+        Some(value) => Some(value.to_string()),
+        None => Some(DEFAULT_SERVER_HEADER.to_string()),
+    };
+    *SERVER_HEADER.lock().unwrap() = value;
+    Ok(())
+}
+
+// Bodies up to this size sent in one go get a Content-Length, see `set_content_length_threshold`
+const DEFAULT_CONTENT_LENGTH_THRESHOLD: usize = 64 * 1024;
+
+static CONTENT_LENGTH_THRESHOLD: AtomicUsize = AtomicUsize::new(DEFAULT_CONTENT_LENGTH_THRESHOLD);
+
+/// Frames responses whose whole body is known up front (`http.end`) with a `Content-Length`
+/// when the body is at most `max_len` bytes (default: 64 KiB), and chunked when it's larger.
+/// Streamed responses are always chunked.
+///
+/// A response may choose for itself: a `Transfer-Encoding: chunked` header gets it chunked, a
+/// `Content-Length` header (whatever its value) gets it the length of the body actually sent.
+/// Responses with trailers are chunked regardless.
+pub fn set_content_length_threshold(max_len: usize) {
+    CONTENT_LENGTH_THRESHOLD.store(max_len, Ordering::Relaxed);
+}
+
+lazy_static! {
+    // Distinguishes ids generated by this process from those of earlier runs
+    static ref REQUEST_ID_PREFIX: String = format!("{:x}", Utc::now().timestamp_millis());
+}
+
+// The client's `X-Request-Id` when it's safe to echo back, otherwise a fresh one
+pub(crate) fn request_id(headers: &HashMap<String, String>) -> String {
+    match headers.get("x-request-id") {
+        Some(id)
+            if !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id.bytes().all(|b| b.is_ascii_graphic()) =>
+        {
+            id.clone()
+        }
+        _ => {
+            let n = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+            format!("{}-{:x}", *REQUEST_ID_PREFIX, n)
+        }
+    }
+}
+
+// Read buffers are pooled so busy servers don't allocate one per connection
+const READ_SIZE: usize = 4096;
+// How much of a sized body goes into each HTTP/2 DATA frame batch
+const SIZED_CHUNK_SIZE: usize = 64 * 1024;
+const BUFFER_POOL_SIZE: usize = 256;
+// Buffers that grew past this (e.g. for a large body) are freed rather than kept around
+const MAX_POOLED_CAPACITY: usize = 64 * 1024;
+
+static BUFFER_POOL: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+// A connection's read buffer, handed back to the pool when the connection ends
+struct PooledBuffer(Vec<u8>);
+
+impl PooledBuffer {
+    fn take() -> Self {
+        let buffer = BUFFER_POOL.lock().unwrap().pop();
+        PooledBuffer(buffer.unwrap_or_else(|| Vec::with_capacity(READ_SIZE)))
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let mut buffer = std::mem::take(&mut self.0);
+        if buffer.capacity() >= READ_SIZE && buffer.capacity() <= MAX_POOLED_CAPACITY {
+            buffer.clear();
+            let mut pool = BUFFER_POOL.lock().unwrap();
+            if pool.len() < BUFFER_POOL_SIZE {
+                pool.push(buffer);
+            }
+        }
+    }
+}
+
+static ACTIVE_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    // When the last request finished, or when the server started
+    static ref LAST_ACTIVE: Mutex<Instant> = Mutex::new(Instant::now());
+}
+
+// Counts a request as in flight until its response is complete, or dropped unanswered
+struct Activity {
+    started: Instant,
+}
+
+impl Activity {
+    fn start() -> Self {
+        ACTIVE_REQUESTS.fetch_add(1, Ordering::SeqCst);
+        Activity {
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Drop for Activity {
+    fn drop(&mut self) {
+        *LAST_ACTIVE.lock().unwrap() = Instant::now();
+        ACTIVE_REQUESTS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Requests whose responses aren't complete, including those being written
+pub(crate) fn active_requests() -> usize {
+    ACTIVE_REQUESTS.load(Ordering::SeqCst)
+}
+
+// How long no request has been in flight, or `None` while one is
+pub(crate) fn idle_for() -> Option<Duration> {
+    let last_active = LAST_ACTIVE.lock().unwrap();
+    (ACTIVE_REQUESTS.load(Ordering::SeqCst) == 0).then(|| last_active.elapsed())
+}
+
+// Why no request could be read off a connection
+enum ReadError {
+    // The client closed the connection between requests
+    Closed,
+    // The request was unacceptable; reply with this status and close
+    Status(u16),
+    Io(io::Error),
+}
+
+// What a streamed body's reader makes of a chunked body it can't read
+impl From<ReadError> for io::Error {
+    fn from(err: ReadError) -> Self {
+        match err {
+            ReadError::Closed => io::ErrorKind::UnexpectedEof.into(),
+            ReadError::Status(413) => {
+                io::Error::new(io::ErrorKind::InvalidData, "request body too large")
+            }
+            ReadError::Status(_) => {
+                io::Error::new(io::ErrorKind::InvalidData, "malformed chunked body")
+            }
+            ReadError::Io(err) => err,
+        }
+    }
+}
+
+// What's left of a request's body on the connection, for listeners that stream bodies
+enum Unread {
+    // This many bytes, which is none for bodies that were read whole
+    Length(usize),
+    // A chunked body, and the trailer fields it may end with (see `announced_trailers`)
+    Chunked(Option<Vec<String>>),
+}
+
+impl From<io::Error> for ReadError {
+    fn from(err: io::Error) -> Self {
+        ReadError::Io(err)
+    }
+}
+
+// The canonical reason phrase for a status code
+pub fn reason_phrase(status_code: u16) -> &'static str {
+    match status_code {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
+
+// Whether a header can be written as is: the name must be a token and the value free of
+// control characters, which could otherwise end the header (or the whole response) early
+pub(crate) fn is_valid_header(name: &str, value: &str) -> bool {
+    let is_tchar = |b: u8| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b);
+    !name.is_empty()
+        && name.bytes().all(is_tchar)
+        && value.bytes().all(|b| b == b'\t' || !b.is_ascii_control())
+}
+
+#[derive(Clone)]
 pub struct Request {
     pub method: String,
     pub path: String,
+    pub version: String,
+    // Header names are lowercased, like Node's `req.headers`
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+    // Fields sent after a chunked (or HTTP/2) body, names lowercased
+    pub trailers: HashMap<String, String>,
+    // From the client's `X-Request-Id` or generated, and echoed on the response
+    pub id: String,
+    // Set when the client presented a verified certificate (mutual TLS)
+    pub client_identity: Option<ClientIdentity>,
+    // The host the client asked for with SNI, when it has a certificate of its own, see
+    // `set_tls_hosts`
+    pub server_name: Option<String>,
+    // The client's address and how it reached us, see `set_trusted_proxies`
+    pub client: Client,
+    // Set when the guest gets connection events, see `set_connection_events`
+    pub connection_id: Option<u64>,
+}
+
+impl Request {
+    // Whether the client is willing to send another request on this connection
+    fn keep_alive(&self) -> bool {
+        let has_token = |token: &str| {
+            self.headers.get("connection").is_some_and(|value| {
+                value
+                    .split(',')
+                    .any(|t| t.trim().eq_ignore_ascii_case(token))
+            })
+        };
+        !has_token("close") && (self.version == "HTTP/1.1" || has_token("keep-alive"))
+    }
 }
 
 pub struct Response {
-    stream: TcpStream,
+    // The client the access log names, which may be behind a trusted proxy
+    client_ip: IpAddr,
+    request_line: String,
+    status_code: u16,
+    request_id: String,
+    // Of the request this answers, for content negotiation and conditional responses
+    request_headers: HashMap<String, String>,
+    transport: Transport,
+    // Answers a HEAD request: the head is what a GET would get, and the body is never sent
+    head: bool,
+    // The body is framed by Content-Length rather than chunked, see `send_sized`
+    sized: bool,
+    activity: Activity,
+    // Released along with the response, e.g. a route's concurrency permit
+    held: Vec<Box<dyn Send>>,
+    // The request body, when it's streamed rather than read up front
+    body: Option<BodyReader>,
+    // The request body comes off the same HTTP/1.1 stream, so it can't be watched for the
+    // client leaving, see `poll_closed`
+    reads_body: bool,
+    // Run on the status and headers as the head is written, see `on_head`
+    head_hooks: Vec<HeadHook>,
+    // In place of the canonical reason phrase, see `set_status_message`
+    status_message: Option<String>,
+}
+
+type HeadHook = Box<dyn FnOnce(&mut u16, &mut Vec<(String, String)>) + Send>;
+
+// Where a response is written
+enum Transport {
+    Http1 {
+        stream: BoxedStream,
+        // How long the connection then waits for another request; `None` closes it
+        keep_alive: Option<Duration>,
+        // Bytes the client sent after this request, e.g. pipelined requests
+        read_ahead: Vec<u8>,
+        // Hands the stream back to the connection loop once the response is complete
+        done: Option<oneshot::Sender<(BoxedStream, Vec<u8>)>>,
+    },
+    // One stream of an HTTP/2 connection
+    Http2(Http2Response),
+}
+
+// A request body read as it arrives, for listeners that stream bodies to the guest
+pub struct BodyReader {
+    source: BodySource,
+}
+
+enum BodySource {
+    Http1 {
+        stream: Shared,
+        // Body bytes that were read along with the head
+        prefix: Vec<u8>,
+        remaining: usize,
+        // Tells the connection loop the body was read to the end, so the connection can
+        // take another request
+        finished: Option<oneshot::Sender<Vec<u8>>>,
+    },
+    Http1Chunked {
+        stream: BoxedStream,
+        // Bytes read off the connection but not decoded yet, which may run past the body
+        buffer: Vec<u8>,
+        // What's still to come of the chunk being read
+        left: usize,
+        read: usize,
+        announced: Option<Vec<String>>,
+        trailers: HashMap<String, String>,
+        // Like `Http1`'s, handing back what was read past the body
+        finished: Option<oneshot::Sender<Vec<u8>>>,
+    },
+    Http2 {
+        recv: h2::RecvStream,
+        read: usize,
+    },
+}
+
+impl BodyReader {
+    pub(crate) fn http2(recv: h2::RecvStream) -> Self {
+        BodyReader {
+            source: BodySource::Http2 { recv, read: 0 },
+        }
+    }
+
+    // The next piece of the body as it came off the connection, or `None` at its end
+    pub async fn chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match &mut self.source {
+            BodySource::Http1 {
+                stream,
+                prefix,
+                remaining,
+                finished,
+            } => {
+                if !prefix.is_empty() {
+                    return Ok(Some(std::mem::take(prefix)));
+                }
+                if *remaining == 0 {
+                    if let Some(finished) = finished.take() {
+                        let _ = finished.send(Vec::new());
+                    }
+                    return Ok(None);
+                }
+                // Never past the body, which would eat into the next request
+                let mut chunk = vec![0; READ_SIZE.min(*remaining)];
+                let n = stream.read(&mut chunk).await?;
+                if n == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                chunk.truncate(n);
+                *remaining -= n;
+                Ok(Some(chunk))
+            }
+            BodySource::Http1Chunked {
+                stream,
+                buffer,
+                left,
+                read,
+                announced,
+                trailers,
+                finished,
+            } => {
+                if finished.is_none() {
+                    return Ok(None);
+                }
+                if *left == 0 {
+                    *left = read_chunk_size(stream, buffer, *read).await?;
+                    if *left == 0 {
+                        *trailers = read_trailers(stream, buffer, announced.as_deref()).await?;
+                        if let Some(finished) = finished.take() {
+                            let _ = finished.send(std::mem::take(buffer));
+                        }
+                        return Ok(None);
+                    }
+                }
+                if buffer.is_empty() {
+                    buffer.reserve(READ_SIZE);
+                    if stream.read_buf(buffer).await? == 0 {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                }
+                let n = (*left).min(buffer.len());
+                let chunk = buffer.drain(..n).collect();
+                *left -= n;
+                *read += n;
+                if *left == 0 {
+                    read_chunk_end(stream, buffer).await?;
+                }
+                Ok(Some(chunk))
+            }
+            BodySource::Http2 { recv, read } => {
+                let Some(data) = recv.data().await else {
+                    return Ok(None);
+                };
+                let data = data.map_err(io::Error::other)?;
+                let _ = recv.flow_control().release_capacity(data.len());
+                // HTTP/2 bodies needn't announce their length, so the limit is checked here
+                *read += data.len();
+                if *read > MAX_BODY_SIZE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "request body too large",
+                    ));
+                }
+                Ok(Some(data.to_vec()))
+            }
+        }
+    }
+
+    // The trailer fields of a chunked body, once it's been read to the end
+    pub fn trailers(&self) -> Option<&HashMap<String, String>> {
+        match &self.source {
+            BodySource::Http1Chunked {
+                trailers, finished, ..
+            } if finished.is_none() => Some(trailers),
+            _ => None,
+        }
+    }
+}
+
+// A connection that a streamed request body is read from while its response is written, like
+// the halves of `tokio::io::split`; the lock is only held for a single poll
+#[derive(Clone)]
+struct Shared(Arc<Mutex<BoxedStream>>);
+
+impl AsyncRead for Shared {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Shared {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_shutdown(cx)
+    }
 }
 
 impl Response {
+    pub(crate) fn http2(request: &Request, stream: Http2Response) -> Self {
+        Response {
+            client_ip: request.client.ip,
+            request_line: format!("{} {} {}", request.method, request.path, request.version),
+            status_code: 200,
+            request_id: request.id.clone(),
+            request_headers: request.headers.clone(),
+            transport: Transport::Http2(stream),
+            head: request.method == "HEAD",
+            sized: false,
+            activity: Activity::start(),
+            held: Vec::new(),
+            body: None,
+            reads_body: false,
+            head_hooks: Vec::new(),
+            status_message: None,
+        }
+    }
+
+    // Hands the request body to whoever answers, to be read as it arrives
+    pub(crate) fn with_body(mut self, body: BodyReader) -> Self {
+        self.body = Some(body);
+        self
+    }
+
+    // The request body, if it's being streamed; dropping it unread closes an HTTP/1.1
+    // connection once the response is done
+    pub fn take_body(&mut self) -> Option<BodyReader> {
+        self.body.take()
+    }
+
+    // 1xx, 204 and 304 responses end with their headers
+    fn has_body(&self) -> bool {
+        !matches!(self.status_code, 100..=199 | 204 | 304)
+    }
+
+    // Whether body bytes go out at all, which they never do in answer to HEAD
+    fn sends_body(&self) -> bool {
+        self.has_body() && !self.head
+    }
+
+    pub fn is_head(&self) -> bool {
+        self.head
+    }
+
+    // Resolves once the client is gone: the connection was closed, or the HTTP/2 stream
+    // reset. Bytes the client sends meanwhile, e.g. a pipelined request, are kept for later.
+    pub async fn closed(&mut self) {
+        std::future::poll_fn(|cx| self.poll_closed(cx)).await
+    }
+
+    // The request line this answers, e.g. `GET /users HTTP/1.1`
+    pub(crate) fn request_line(&self) -> &str {
+        &self.request_line
+    }
+
+    pub(crate) fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
+    // How long ago the request came in
+    pub(crate) fn age(&self) -> Duration {
+        self.activity.started.elapsed()
+    }
+
+    // Whether the client is known to be gone, without waiting
+    pub(crate) fn is_closed(&mut self) -> bool {
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+        self.poll_closed(&mut cx).is_ready()
+    }
+
+    fn poll_closed(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        match &mut self.transport {
+            // Reading is the only way to notice, and a streamed body is read elsewhere
+            Transport::Http1 { .. } if self.reads_body => Poll::Pending,
+            Transport::Http1 {
+                stream, read_ahead, ..
+            } => loop {
+                // Past this, the client can wait for its answer before it sends more
+                if read_ahead.len() >= MAX_HEADER_SIZE {
+                    return Poll::Pending;
+                }
+                let mut chunk = [0; 1024];
+                let mut chunk = ReadBuf::new(&mut chunk);
+                match Pin::new(&mut *stream).poll_read(cx, &mut chunk) {
+                    Poll::Ready(Ok(())) if chunk.filled().is_empty() => return Poll::Ready(()),
+                    Poll::Ready(Ok(())) => read_ahead.extend_from_slice(chunk.filled()),
+                    Poll::Ready(Err(_)) => return Poll::Ready(()),
+                    Poll::Pending => return Poll::Pending,
+                }
+            },
+            Transport::Http2(stream) => stream.poll_reset(cx),
+        }
+    }
+
+    // Lets `hook` change the status and headers when the head is written, e.g. for middleware
+    // to add headers to whatever the guest answers. Hooks added later run first.
+    pub fn on_head(
+        &mut self,
+        hook: impl FnOnce(&mut u16, &mut Vec<(String, String)>) + Send + 'static,
+    ) {
+        self.head_hooks.push(Box::new(hook));
+    }
+
+    // Sends `message` on the HTTP/1.1 status line in place of the canonical reason phrase,
+    // like Node's `statusMessage`; HTTP/2 has no reason phrases. Returns false, leaving the
+    // canonical one, for a message with control characters.
+    pub fn set_status_message(&mut self, message: &str) -> bool {
+        if message.bytes().any(|b| b != b'\t' && b.is_ascii_control()) {
+            return false;
+        }
+        self.status_message = Some(message.to_string());
+        true
+    }
+
     pub async fn write_head(
         &mut self,
         status_code: u16,
         headers: impl IntoIterator<Item = (impl AsRef<str>, impl AsRef<str>)>,
     ) -> io::Result<()> {
-        let date = Utc::now().to_rfc2822();
-
-        let mut response_header = format!(
-            "HTTP/1.1 {status_code} OK\r\n\
-            Date: {date}\r\n\
-            Keep-Alive: timeout=5\r\n\
-            Transfer-Encoding: chunked\r\n"
-        );
+        let mut status_code = status_code;
+        let mut headers: Vec<(String, String)> = headers
+            .into_iter()
+            .map(|(key, value)| (key.as_ref().to_string(), value.as_ref().to_string()))
+            .collect();
+        for hook in self.head_hooks.drain(..).rev() {
+            hook(&mut status_code, &mut headers);
+        }
+        self.status_code = status_code;
+        let has_body = self.has_body();
 
+        let mut fields = vec![("Date".to_string(), http_date().to_string())];
+        let mut has_request_id = false;
+        let mut has_length = false;
+        let mut has_server = false;
         for (key, value) in headers {
-            // FIXME: use .into_ok() later
+            if !is_valid_header(&key, &value) {
+                self.log(1, &format!("Dropped invalid header {:?}", key));
+                continue;
+            }
+            has_request_id |= key.eq_ignore_ascii_case("X-Request-Id");
+            has_length |= key.eq_ignore_ascii_case("Content-Length");
+            has_server |= key.eq_ignore_ascii_case("Server");
+            fields.push((key, value));
+        }
+        if !has_request_id {
+            fields.push(("X-Request-Id".to_string(), self.request_id.clone()));
+        }
+        if !has_server {
+            if let Some(server) = SERVER_HEADER.lock().unwrap().clone() {
+                fields.push(("Server".to_string(), server));
+            }
+        }
+
+        let (stream, keep_alive) = match &mut self.transport {
+            Transport::Http1 {
+                stream, keep_alive, ..
+            } => (stream, *keep_alive),
+            Transport::Http2(stream) => {
+                return stream.send_head(status_code, &fields, has_body && !self.head)
+            }
+        };
+
+        let reason = match &self.status_message {
+            Some(message) => message.as_str(),
+            None => reason_phrase(status_code),
+        };
+        let mut response_header = format!("HTTP/1.1 {status_code} {reason}\r\n");
+        // Framed as the GET would be, unless a HEAD response gives the length outright
+        if has_body && !self.sized && !(self.head && has_length) {
+            response_header.push_str("Transfer-Encoding: chunked\r\n");
+        }
+
+        // Rounded down, so clients give up on the connection no later than we do
+        if let Some(timeout) = keep_alive {
+            let timeout = timeout.as_secs();
             write!(
                 &mut response_header,
-                "{}: {}\r\n",
-                key.as_ref(),
-                value.as_ref()
+                "Connection: keep-alive\r\nKeep-Alive: timeout={timeout}\r\n"
             )
             .unwrap();
+        } else {
+            response_header.push_str("Connection: close\r\n");
+        }
+
+        for (key, value) in &fields {
+            // FIXME: use .into_ok() later
+            write!(&mut response_header, "{}: {}\r\n", key, value).unwrap();
         }
 
         response_header.push_str("\r\n"); // End of headers
 
-        self.stream.write_all(response_header.as_bytes()).await
+        stream.write_all(response_header.as_bytes()).await
+    }
+
+    // Sends one chunk of the body right away
+    pub async fn write(&mut self, chunk: impl AsRef<[u8]>) -> io::Result<()> {
+        let chunk = chunk.as_ref();
+        // An empty chunk would end the body
+        let send = !chunk.is_empty() && self.sends_body();
+        match &mut self.transport {
+            Transport::Http1 { stream, .. } if self.sized => {
+                if send {
+                    stream.write_all(chunk).await?;
+                }
+                stream.flush().await
+            }
+            Transport::Http1 { stream, .. } => {
+                if send {
+                    let size = format!("{:X}\r\n", chunk.len());
+                    stream.write_all(size.as_bytes()).await?;
+                    stream.write_all(chunk).await?;
+                    stream.write_all(b"\r\n").await?;
+                }
+                stream.flush().await
+            }
+            Transport::Http2(stream) if send => stream.send_data(chunk).await,
+            Transport::Http2(_) => Ok(()),
+        }
     }
 
-    pub async fn end(&mut self, body: &str) {
-        let body_len = body.len();
-        let mut chunked_body = String::new();
+    // Sends the `len` bytes of `body` with a Content-Length rather than chunked, copied
+    // straight onto an HTTP/1.1 connection. HTTP/2 frames the data itself.
+    pub async fn send_sized(
+        mut self,
+        status_code: u16,
+        mut headers: Vec<(String, String)>,
+        mut body: impl AsyncRead + Unpin,
+        len: u64,
+    ) -> io::Result<()> {
+        headers.push(("Content-Length".to_string(), len.to_string()));
+        self.sized = true;
+        self.write_head(status_code, headers).await?;
+        if !self.sends_body() {
+            self.finish(0).await;
+            return Ok(());
+        }
+
+        let mut body = (&mut body).take(len);
+        let sent = match &mut self.transport {
+            Transport::Http1 { stream, .. } => {
+                let sent = tokio::io::copy(&mut body, stream).await?;
+                stream.flush().await?;
+                sent
+            }
+            Transport::Http2(stream) => {
+                let mut chunk = vec![0; SIZED_CHUNK_SIZE];
+                let mut sent = 0;
+                loop {
+                    let n = body.read(&mut chunk).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    stream.send_data(&chunk[..n]).await?;
+                    sent += n as u64;
+                }
+                sent
+            }
+        };
+        // A body shorter than announced leaves the client waiting for the rest
+        if sent < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.finish(sent as usize).await;
+        Ok(())
+    }
 
-        // Add chunked transfer encoding
-        // FIXME: use .into_ok() later
-        write!(
-            &mut chunked_body,
-            "{body_len:X}\r\n\
-            {body}\r\n\
-            0\r\n\r\n" // End of chunks
-                       // what is the 0 here
-        )
-        .unwrap();
+    // Answers with an error of the server's own making: `text`, or the page `set_error_pages`
+    // says
+    pub(crate) async fn send_error(
+        self,
+        status_code: u16,
+        headers: impl IntoIterator<Item = (impl AsRef<str>, impl AsRef<str>)>,
+        text: &str,
+    ) -> io::Result<()> {
+        let (content_type, body) = error_page::render(status_code, text);
+        let mut headers: Vec<(String, String)> = headers
+            .into_iter()
+            .map(|(key, value)| (key.as_ref().to_string(), value.as_ref().to_string()))
+            .collect();
+        headers.push(("Content-Type".to_string(), content_type.to_string()));
+        self.send_whole(status_code, headers, body.as_bytes(), Vec::new())
+            .await
+    }
+
+    // Sends the whole response at once, framed as `set_content_length_threshold` says
+    pub async fn send_whole(
+        mut self,
+        status_code: u16,
+        mut headers: Vec<(String, String)>,
+        body: &[u8],
+        trailers: Vec<(String, String)>,
+    ) -> io::Result<()> {
+        let mut asked_chunked = false;
+        let mut asked_length = false;
+        headers.retain(|(key, value)| {
+            if key.eq_ignore_ascii_case("Transfer-Encoding") {
+                asked_chunked |= value.to_ascii_lowercase().contains("chunked");
+                false
+            } else if key.eq_ignore_ascii_case("Content-Length") {
+                asked_length = true;
+                false
+            } else {
+                true
+            }
+        });
+        // Trailers only follow a chunked body. HEAD gets the length of the body it goes without.
+        let sized = trailers.is_empty()
+            && !asked_chunked
+            && (asked_length
+                || self.head
+                || body.len() <= CONTENT_LENGTH_THRESHOLD.load(Ordering::Relaxed));
+        if !sized && asked_length {
+            self.log(
+                1,
+                "Ignored the Content-Length of a response with trailers or chunked encoding",
+            );
+        }
+        if sized && !matches!(status_code, 100..=199 | 204 | 304) {
+            headers.push(("Content-Length".to_string(), body.len().to_string()));
+            self.sized = true;
+        }
+        self.write_head(status_code, headers).await?;
+        if !self.sized {
+            self.end_with_trailers(body, trailers).await;
+            return Ok(());
+        }
+        let body_len = if self.sends_body() { body.len() } else { 0 };
+        if body_len > 0 {
+            let written = match &mut self.transport {
+                Transport::Http1 { stream, .. } => stream.write_all(body).await,
+                Transport::Http2(stream) => stream.send_data(body).await,
+            };
+            if let Err(e) = written {
+                // The client is gone; dropping the response closes the connection
+                self.log(2, &format!("Failed to send response body: {}", e));
+                return Ok(());
+            }
+        }
+        self.finish(body_len).await;
+        Ok(())
+    }
+
+    // Logs `message` with the request it's about
+    pub(crate) fn log(&self, level: usize, message: &str) {
+        log_with(
+            level,
+            message,
+            &[
+                ("requestId", json!(self.request_id)),
+                ("remoteAddress", json!(self.client_ip.to_string())),
+            ],
+        );
+    }
+
+    // Keeps `value` alive until the response is done
+    pub(crate) fn hold(&mut self, value: impl Send + 'static) {
+        self.held.push(Box::new(value));
+    }
+
+    // A header of the request this answers, by lowercase name
+    pub fn request_header(&self, name: &str) -> Option<&str> {
+        self.request_headers.get(name).map(String::as_str)
+    }
 
-        self.stream.write_all(chunked_body.as_bytes()).await.unwrap();
-        self.stream.flush().await.unwrap();
+    pub async fn end(self, body: impl AsRef<[u8]>) {
+        self.end_with_trailers(body.as_ref(), std::iter::empty::<(&str, &str)>())
+            .await;
+    }
+
+    // Like `end`, with trailer fields after the last chunk. Clients only expect the fields
+    // announced in a `Trailer` header.
+    pub async fn end_with_trailers(
+        mut self,
+        body: &[u8],
+        trailers: impl IntoIterator<Item = (impl AsRef<str>, impl AsRef<str>)>,
+    ) {
+        let body_len = if self.sends_body() { body.len() } else { 0 };
+        if body_len > 0 {
+            match &mut self.transport {
+                Transport::Http1 { stream, .. } => {
+                    let size = format!("{body_len:X}\r\n");
+                    let written = async {
+                        stream.write_all(size.as_bytes()).await?;
+                        stream.write_all(body).await?;
+                        stream.write_all(b"\r\n").await
+                    }
+                    .await;
+                    // The client is gone; dropping the response closes the connection
+                    if let Err(e) = written {
+                        self.log(2, &format!("Failed to send response body: {}", e));
+                        return;
+                    }
+                }
+                Transport::Http2(stream) => {
+                    if let Err(e) = stream.send_data(body).await {
+                        self.log(2, &format!("Failed to send response body: {}", e));
+                    }
+                }
+            }
+        }
+        self.finish_with_trailers(body_len, trailers).await;
+    }
+
+    // Takes the connection out of HTTP handling, along with any bytes already read off it.
+    // A stream of an HTTP/2 connection can't be taken over.
+    pub fn into_raw(self) -> Option<(BoxedStream, Vec<u8>)> {
+        match self.transport {
+            Transport::Http1 {
+                stream, read_ahead, ..
+            } => Some((stream, read_ahead)),
+            Transport::Http2(_) => None,
+        }
+    }
+
+    // Writes the last chunk and gives the connection back for the next request
+    pub async fn finish(self, body_len: usize) {
+        self.complete(body_len, &[]).await;
+    }
+
+    // Like `finish`, with trailer fields after the last chunk
+    pub async fn finish_with_trailers(
+        self,
+        body_len: usize,
+        trailers: impl IntoIterator<Item = (impl AsRef<str>, impl AsRef<str>)>,
+    ) {
+        let mut fields = Vec::new();
+        for (key, value) in trailers {
+            if !is_valid_header(key.as_ref(), value.as_ref()) {
+                self.log(1, &format!("Dropped invalid trailer {:?}", key.as_ref()));
+                continue;
+            }
+            fields.push((key.as_ref().to_string(), value.as_ref().to_string()));
+        }
+        self.complete(body_len, &fields).await;
+    }
+
+    async fn complete(mut self, body_len: usize, trailers: &[(String, String)]) {
+        let chunked = self.sends_body() && !self.sized;
+        match &mut self.transport {
+            Transport::Http1 { stream, .. } => {
+                let written = async {
+                    if chunked {
+                        let mut last_chunk = "0\r\n".to_string();
+                        for (key, value) in trailers {
+                            write!(&mut last_chunk, "{}: {}\r\n", key, value).unwrap();
+                        }
+                        last_chunk.push_str("\r\n");
+                        stream.write_all(last_chunk.as_bytes()).await?;
+                    }
+                    stream.flush().await
+                }
+                .await;
+                if let Err(e) = written {
+                    self.log(2, &format!("Failed to end response: {}", e));
+                    return;
+                }
+            }
+            Transport::Http2(stream) => {
+                if let Err(e) = stream.send_end(trailers) {
+                    self.log(2, &format!("Failed to end response: {}", e));
+                }
+            }
+        }
+
+        access_log::record(
+            self.client_ip,
+            &self.request_line,
+            self.status_code,
+            body_len,
+        );
+
+        if let Transport::Http1 {
+            stream,
+            read_ahead,
+            done: Some(done),
+            ..
+        } = self.transport
+        {
+            let _ = done.send((stream, read_ahead));
+        }
+    }
+}
+
+/// The IP versions a server accepts connections over.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum IpStack {
+    V4,
+    V6,
+    /// IPv6, and IPv4 through mapped addresses; IPv4 only where IPv6 is unavailable
+    #[default]
+    Dual,
+}
+
+// Binds `port` on the unspecified address(es) of `stack`
+fn bind(port: u16, stack: IpStack) -> io::Result<std::net::TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let bind_to = |domain: Domain, addr: SocketAddr| -> io::Result<std::net::TcpListener> {
+        let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+        if domain == Domain::IPV6 {
+            socket.set_only_v6(stack == IpStack::V6)?;
+        }
+        // As tokio's `TcpListener::bind` does, so restarts don't wait out TIME_WAIT
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        Ok(socket.into())
+    };
+
+    let v4 = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+    let v6 = SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
+    match stack {
+        IpStack::V4 => bind_to(Domain::IPV4, v4),
+        IpStack::V6 => bind_to(Domain::IPV6, v6),
+        IpStack::Dual => match bind_to(Domain::IPV6, v6) {
+            Err(e) if e.kind() != io::ErrorKind::AddrInUse => {
+                log(
+                    1,
+                    &format!("IPv6 unavailable ({}), listening on IPv4 only", e),
+                );
+                bind_to(Domain::IPV4, v4)
+            }
+            result => result,
+        },
     }
 }
 
 pub fn create_server(handler: RequestHandler) -> Server {
-    Server { handler }
+    Server {
+        handler,
+        ip_stack: IpStack::default(),
+        tls: None,
+        listener: None,
+        rate_limit: None,
+        tcp_keepalive: None,
+        connection: ConnectionOptions {
+            max_requests: DEFAULT_MAX_REQUESTS_PER_CONNECTION,
+            keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
+            max_headers: DEFAULT_MAX_HEADERS,
+            strict_trailers: false,
+            stream_body: false,
+            tls: false,
+            internal: false,
+        },
+    }
+}
+
+// How a server handles each of its connections
+#[derive(Clone, Copy)]
+pub(crate) struct ConnectionOptions {
+    max_requests: usize,
+    keep_alive_timeout: Duration,
+    pub(crate) max_headers: usize,
+    // Refuse trailer fields the request's `Trailer` header didn't announce
+    pub(crate) strict_trailers: bool,
+    pub(crate) stream_body: bool,
+    // Connections are TLS, which makes `https` the scheme of requests not forwarded
+    pub(crate) tls: bool,
+    // Serves the host's own endpoints, not the guest's, see `Server::internal`
+    internal: bool,
 }
 
 pub struct Server {
     handler: RequestHandler,
+    ip_stack: IpStack,
+    tls: Option<TlsAcceptor>,
+    // Bound ahead of time, e.g. by socket activation
+    listener: Option<std::net::TcpListener>,
+    rate_limit: Option<RateLimiter>,
+    tcp_keepalive: Option<TcpKeepalive>,
+    connection: ConnectionOptions,
+}
+
+// TCP keepalive probes on accepted connections, so peers that vanished without a word are
+// noticed even while the connection is otherwise silent, e.g. streaming events
+#[derive(Clone, Copy)]
+pub(crate) struct TcpKeepalive {
+    // Silence before the first probe
+    pub(crate) idle: Duration,
+    // Between unanswered probes (default: the system's)
+    pub(crate) interval: Option<Duration>,
+    // Unanswered probes before the connection is dropped (default: the system's)
+    pub(crate) count: Option<u32>,
+}
+
+impl TcpKeepalive {
+    fn apply(&self, stream: &tokio::net::TcpStream) -> io::Result<()> {
+        let keepalive = socket2::TcpKeepalive::new().with_time(self.idle);
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "macos",
+            target_os = "freebsd",
+            windows
+        ))]
+        let keepalive = match self.interval {
+            Some(interval) => keepalive.with_interval(interval),
+            None => keepalive,
+        };
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "macos",
+            target_os = "freebsd"
+        ))]
+        let keepalive = match self.count {
+            Some(count) => keepalive.with_retries(count),
+            None => keepalive,
+        };
+        socket2::SockRef::from(stream).set_tcp_keepalive(&keepalive)
+    }
 }
 
 impl Server {
-    pub async fn listen(self, port: u16, on_listen: fn()) -> io::Result<()> {
-        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await?;
+    // Serves HTTPS, completing a TLS handshake on every accepted connection
+    pub fn tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self.connection.tls = true;
+        self
+    }
+
+    pub fn ip_stack(mut self, stack: IpStack) -> Self {
+        self.ip_stack = stack;
+        self
+    }
+
+    // Serves the host's own endpoints, e.g. the admin API: the guest isn't told about its
+    // connections, middleware doesn't see its requests, and binding it doesn't give up root
+    pub(crate) fn internal(mut self) -> Self {
+        self.connection.internal = true;
+        self
+    }
+
+    // Accepts on `listener` instead of binding the port given to `listen`
+    pub fn listener(mut self, listener: std::net::TcpListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    // Answers clients over their request rate with 429 before the handler sees the request
+    pub(crate) fn rate_limit(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limit = Some(limiter);
+        self
+    }
+
+    // Probes idle connections at the TCP level, closing those whose peer stopped answering
+    pub(crate) fn tcp_keepalive(mut self, keepalive: TcpKeepalive) -> Self {
+        self.tcp_keepalive = Some(keepalive);
+        self
+    }
+
+    pub fn max_requests_per_connection(mut self, max: usize) -> Self {
+        self.connection.max_requests = max;
+        self
+    }
+
+    // How long a keep-alive connection waits for the next request, also advertised to clients
+    // in the `Keep-Alive` header
+    pub fn keep_alive_timeout(mut self, timeout: Duration) -> Self {
+        self.connection.keep_alive_timeout = timeout;
+        self
+    }
+
+    // Answers requests with more header fields than `max` with 431
+    pub fn max_headers(mut self, max: usize) -> Self {
+        self.connection.max_headers = max;
+        self
+    }
+
+    // Answers requests with trailer fields their `Trailer` header didn't announce with 400
+    pub fn strict_trailers(mut self, enabled: bool) -> Self {
+        self.connection.strict_trailers = enabled;
+        self
+    }
+
+    // Leaves request bodies on the connection for the handler to read through `take_body`
+    pub fn stream_body(mut self, enabled: bool) -> Self {
+        self.connection.stream_body = enabled;
+        self
+    }
+
+    pub async fn listen(mut self, port: u16, on_listen: fn()) -> io::Result<()> {
+        let listener = match self.listener.take() {
+            Some(listener) => listener,
+            None => bind(port, self.ip_stack)?,
+        };
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        tick_date();
+        // Bound, so root is no longer needed, see `set_run_as`
+        if !self.connection.internal {
+            privileges::drop_after_bind()?;
+        }
         on_listen();
 
+        let mut prune = tokio::time::interval(RATE_LIMIT_PRUNE_INTERVAL);
         loop {
-            let (stream, _) = listener.accept().await?;
+            // Past the cap, `Block` leaves new connections in the listen backlog, as warmup does
+            let accepting = async {
+                if !self.connection.internal {
+                    warmup::wait().await;
+                }
+                let slot = match connection::overflow() {
+                    ConnectionOverflow::Block if !self.connection.internal => {
+                        connection::wait_for_slot(None).await
+                    }
+                    _ => None,
+                };
+                (listener.accept().await, slot)
+            };
+            let (accepted, slot) = tokio::select! {
+                accepted = accepting => accepted,
+                _ = prune.tick() => {
+                    if let Some(limiter) = &self.rate_limit {
+                        limiter.prune();
+                    }
+                    continue;
+                }
+            };
+            let (stream, remote_addr) = match accepted {
+                // IPv4 clients of a dual-stack listener show up as IPv4-mapped IPv6 addresses
+                Ok((stream, addr)) => (
+                    stream,
+                    SocketAddr::new(addr.ip().to_canonical(), addr.port()),
+                ),
+                Err(e) => {
+                    // e.g. out of file descriptors; back off rather than spin
+                    log(1, &format!("Failed to accept connection: {}", e));
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            if let Some(keepalive) = &self.tcp_keepalive {
+                if let Err(e) = keepalive.apply(&stream) {
+                    log(1, &format!("Failed to set TCP keepalive: {}", e));
+                }
+            }
             let handler = self.handler;
+            let tls = self.tls.clone();
+            let rate_limit = self.rate_limit.clone();
+            let options = self.connection;
             tokio::spawn(async move {
-                if let Err(e) = handle_connection(stream, handler).await {
-                    todo!("{e}")
+                // Held until this task ends; the server's own connections aren't counted
+                let slot = match slot {
+                    None if !options.internal => connection::admit(remote_addr).await,
+                    slot => slot,
+                };
+                // Told to the guest (if it asked) until this task ends
+                let connection = match options.internal {
+                    true => None,
+                    false => connection::open(remote_addr, options.tls).await,
+                };
+                let connection_id = connection.as_ref().map(|connection| connection.id);
+                let (stream, client_identity, server_name, h2): (BoxedStream, _, _, _) = match tls {
+                    Some(acceptor) => match acceptor.accept(stream).await {
+                        Ok(stream) => {
+                            let client_identity = tls::client_identity(&stream);
+                            let server_name = tls::server_name(&stream);
+                            let h2 = stream.get_ref().1.alpn_protocol() == Some(b"h2");
+                            (Box::new(stream), client_identity, server_name, h2)
+                        }
+                        Err(e) => {
+                            // Includes clients rejected by the client certificate verifier
+                            log_with(
+                                2,
+                                &format!("TLS handshake with {} failed: {}", remote_addr, e),
+                                &[("remoteAddress", json!(remote_addr.ip().to_string()))],
+                            );
+                            return;
+                        }
+                    },
+                    None => (Box::new(stream), None, None, false),
+                };
+                if slot.is_none() && !options.internal {
+                    // An HTTP/2 client wouldn't understand an HTTP/1.1 503, so it's only closed
+                    if !h2 {
+                        let mut stream = stream;
+                        let _ = reject(&mut stream, 503, &[("Retry-After", "1".to_string())]).await;
+                    }
+                    return;
+                }
+                let peer = Peer {
+                    addr: remote_addr,
+                    client_identity,
+                    server_name,
+                    connection_id,
+                };
+                let served = if h2 {
+                    http2::serve(stream, peer, rate_limit, options, handler).await
+                } else {
+                    handle_connection(stream, peer, rate_limit, options, handler).await
+                };
+                if let Err(e) = served {
+                    log_with(
+                        2,
+                        &format!("Connection from {} closed: {}", remote_addr, e),
+                        &[("remoteAddress", json!(remote_addr.ip().to_string()))],
+                    );
                 }
             });
         }
     }
 }
 
-async fn handle_connection(stream: TcpStream, handler: RequestHandler) -> io::Result<()> {
-    let mut buffer = [0; 512];
-    let mut stream = Response { stream };
-    stream.stream.read(&mut buffer).await?;
+impl Server {
+    // Answers `raw`, as a connection from localhost sending it would be answered, and returns
+    // what was written back. For requests the server makes up itself, see `set_warmup`.
+    pub(crate) async fn serve_local(self, raw: &[u8]) -> io::Result<Vec<u8>> {
+        let (mut client, stream) = tokio::io::duplex(SIZED_CHUNK_SIZE);
+        let peer = Peer {
+            addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+            client_identity: None,
+            server_name: None,
+            connection_id: None,
+        };
+        let served = tokio::spawn(handle_connection(
+            Box::new(stream),
+            peer,
+            None,
+            self.connection,
+            self.handler,
+        ));
+        client.write_all(raw).await?;
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await?;
+        served.await.map_err(io::Error::other)??;
+        Ok(response)
+    }
+}
+
+// The current time as an HTTP date (IMF-fixdate), e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+fn http_date() -> Arc<String> {
+    let now = Utc::now();
+    match &*DATE.read().unwrap() {
+        Some((second, date)) if *second == now.timestamp() => Arc::clone(date),
+        // Not ticking yet, or not anymore with the runtime it ran on gone
+        _ => Arc::new(now.format("%a, %d %b %Y %H:%M:%S GMT").to_string()),
+    }
+}
+
+// Reformats `DATE` at the turn of every second, from the first server listening on
+fn tick_date() {
+    DATE_TICKING.call_once(|| {
+        tokio::spawn(async {
+            loop {
+                let now = Utc::now();
+                let date = now.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+                *DATE.write().unwrap() = Some((now.timestamp(), Arc::new(date)));
+                // Past 1s during a leap second
+                let into_second = now.timestamp_subsec_nanos() % 1_000_000_000;
+                let to_next = Duration::from_nanos(u64::from(1_000_000_000 - into_second));
+                tokio::time::sleep(to_next).await;
+            }
+        });
+    });
+}
+
+async fn handle_connection(
+    mut stream: BoxedStream,
+    peer: Peer,
+    rate_limit: Option<RateLimiter>,
+    options: ConnectionOptions,
+    handler: RequestHandler,
+) -> io::Result<()> {
+    let ConnectionOptions {
+        max_requests,
+        keep_alive_timeout,
+        stream_body,
+        ..
+    } = options;
+    // Bytes read past the end of the previous request (e.g. pipelined requests)
+    let mut buffer = PooledBuffer::take();
+    let mut served = 0;
+    // Streamed bodies are read while the response is written, through a handle of their own
+    let shared = if stream_body {
+        let shared = Shared(Arc::new(Mutex::new(stream)));
+        stream = Box::new(shared.clone());
+        Some(shared)
+    } else {
+        None
+    };
+
+    loop {
+        // Idle until the next request starts, which only gets so long; the request itself
+        // then gets as long as it takes to send, up to the request timeout
+        if buffer.0.is_empty() {
+            buffer.0.reserve(READ_SIZE);
+            match tokio::time::timeout(keep_alive_timeout, stream.read_buf(&mut buffer.0)).await {
+                Ok(Ok(0)) | Err(_) => return Ok(()),
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => return Err(e),
+            }
+        }
+        let (request, unread) = match tokio::time::timeout(
+            REQUEST_TIMEOUT,
+            read_request(&mut stream, &mut buffer.0, &peer, &options),
+        )
+        .await
+        {
+            Ok(Ok(read)) => read,
+            Ok(Err(ReadError::Closed)) => return Ok(()),
+            Err(_) => return reject(&mut stream, 408, &[]).await,
+            Ok(Err(ReadError::Status(status_code))) => {
+                return reject(&mut stream, status_code, &[]).await;
+            }
+            Ok(Err(ReadError::Io(e))) => return Err(e),
+        };
+
+        // A plaintext HTTP/2 client's preface reads as a `PRI` request; hand the connection
+        // over, replaying what was read of it
+        if served == 0
+            && http2::enabled()
+            && request.method == "PRI"
+            && request.path == "*"
+            && request.version == "HTTP/2.0"
+        {
+            let mut read = http2::PREFACE_HEAD.to_vec();
+            read.append(&mut buffer.0);
+            let stream = Box::new(Rewind::new(read, stream));
+            return http2::serve(stream, peer, rate_limit, options, handler).await;
+        }
+
+        // Clients behind a trusted proxy are limited one by one, not as the proxy
+        if let Some(Err(wait)) = rate_limit.as_ref().map(|l| l.check(request.client.ip)) {
+            // Whole seconds, rounded up so a client retrying on time is let through
+            let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            return reject(
+                &mut stream,
+                429,
+                &[("Retry-After", retry_after.to_string())],
+            )
+            .await;
+        }
+
+        // The last request we'll take on this connection goes out with `Connection: close`
+        served += 1;
+        let keep_alive =
+            (request.keep_alive() && served < max_requests).then_some(keep_alive_timeout);
+        let (done, stream_returned) = oneshot::channel();
+        let mut body_finished = None;
+        let body = match &shared {
+            Some(shared) => match unread {
+                Unread::Length(0) => None,
+                Unread::Length(unread) => {
+                    let (finished, wait) = oneshot::channel();
+                    body_finished = Some(wait);
+                    let prefix: Vec<u8> = buffer.0.drain(..unread.min(buffer.0.len())).collect();
+                    Some(BodyReader {
+                        source: BodySource::Http1 {
+                            stream: shared.clone(),
+                            remaining: unread - prefix.len(),
+                            prefix,
+                            finished: Some(finished),
+                        },
+                    })
+                }
+                // Where the body ends is only known once it's decoded, so the reader gets
+                // everything read so far and hands back what comes after the body
+                Unread::Chunked(announced) => {
+                    let (finished, wait) = oneshot::channel();
+                    body_finished = Some(wait);
+                    Some(BodyReader {
+                        source: BodySource::Http1Chunked {
+                            stream: Box::new(shared.clone()),
+                            buffer: std::mem::take(&mut buffer.0),
+                            left: 0,
+                            read: 0,
+                            announced,
+                            trailers: HashMap::new(),
+                            finished: Some(finished),
+                        },
+                    })
+                }
+            },
+            None => None,
+        };
+        let response = Response {
+            client_ip: request.client.ip,
+            request_line: format!("{} {} {}", request.method, request.path, request.version),
+            status_code: 200,
+            request_id: request.id.clone(),
+            request_headers: request.headers.clone(),
+            transport: Transport::Http1 {
+                stream,
+                keep_alive,
+                read_ahead: std::mem::take(&mut buffer.0),
+                done: Some(done),
+            },
+            head: request.method == "HEAD",
+            sized: false,
+            activity: Activity::start(),
+            held: Vec::new(),
+            reads_body: body.is_some(),
+            body,
+            head_hooks: Vec::new(),
+            status_message: None,
+        };
+        let handled = match options.internal {
+            true => handler(&request, response),
+            false => middleware::dispatch(&request, response, handler),
+        };
+        if let Err(e) = handled.await {
+            return Err(io::Error::other(e.to_string()));
+        }
+
+        // Wait for the guest to finish the response; a dropped response closes the connection
+        match stream_returned.await {
+            Ok((returned, read_ahead)) if keep_alive.is_some() => {
+                stream = returned;
+                buffer.0 = read_ahead;
+            }
+            _ => return Ok(()),
+        }
+        // The next request starts where a streamed body ends, so one left unread ends the
+        // connection
+        if let Some(finished) = body_finished {
+            match finished.await {
+                Ok(mut read_past) => {
+                    read_past.append(&mut buffer.0);
+                    buffer.0 = read_past;
+                }
+                Err(_) => return Ok(()),
+            }
+        }
+    }
+}
 
-    let request_line = String::from_utf8_lossy(&buffer);
+// Answers a request we refuse to read, with an error page if there are any (see
+// `set_error_pages`), and closes the connection
+async fn reject(
+    stream: &mut BoxedStream,
+    status_code: u16,
+    headers: &[(&str, String)],
+) -> io::Result<()> {
+    let reason = reason_phrase(status_code);
+    log(2, &format!("Rejected request: {} {}", status_code, reason));
+    let (content_type, body) = error_page::render(status_code, "");
+    let mut response = format!(
+        "HTTP/1.1 {status_code} {reason}\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n",
+        body.len()
+    );
+    if !body.is_empty() {
+        write!(&mut response, "Content-Type: {content_type}\r\n").unwrap();
+    }
+    for (key, value) in headers {
+        write!(&mut response, "{key}: {value}\r\n").unwrap();
+    }
+    response.push_str("\r\n");
+    response.push_str(&body);
+    stream.write_all(response.as_bytes()).await?;
+    stream.flush().await
+}
 
+// The path of a request target, in origin form (`/path?query`), and the host it names if it
+// names one. Proxies send absolute URLs (`http://host/path`), `CONNECT` an authority
+// (`host:port`), which stays the path, and `OPTIONS` may ask about the whole server with `*`
+// (as does the HTTP/2 preface, with `PRI`).
+fn request_target(method: &str, target: &str) -> Option<(String, Option<String>)> {
+    if target.starts_with('/') {
+        return Some((target.to_string(), None));
+    }
+    if target == "*" {
+        return matches!(method, "OPTIONS" | "PRI").then(|| (target.to_string(), None));
+    }
+    let is_authority =
+        |authority: &str| !authority.is_empty() && !authority.contains(['@', '/', '?', '#']);
+    if method == "CONNECT" {
+        // Always with a port
+        let (_, port) = target.rsplit_once(':')?;
+        if !is_authority(target) || port.is_empty() || !port.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        return Some((target.to_string(), Some(target.to_string())));
+    }
+    let (scheme, rest) = target.split_once("://")?;
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+        return None;
+    }
+    let (authority, path) = match rest.find(['/', '?', '#']) {
+        Some(end) => rest.split_at(end),
+        None => (rest, ""),
+    };
+    if !is_authority(authority) {
+        return None;
+    }
+    // Fragments are the client's business
+    let path = match path.split('#').next().unwrap_or("") {
+        "" => "/".to_string(),
+        path if path.starts_with('/') => path.to_string(),
+        path => format!("/{}", path),
+    };
+    Some((path, Some(authority.to_string())))
+}
+
+// Reads one request off the stream. When streaming bodies, the body is left in `buffer` and on
+// the stream, and what's left of it returned alongside the request.
+async fn read_request(
+    stream: &mut BoxedStream,
+    buffer: &mut Vec<u8>,
+    peer: &Peer,
+    options: &ConnectionOptions,
+) -> Result<(Request, Unread), ReadError> {
+    let header_end = loop {
+        if let Some(pos) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if buffer.len() > MAX_HEADER_SIZE {
+            return Err(ReadError::Status(431));
+        }
+        buffer.reserve(READ_SIZE);
+        if stream.read_buf(buffer).await? == 0 {
+            return Err(ReadError::Closed);
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).into_owned();
+    buffer.drain(..header_end);
+
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or("");
+    // Only the request line: header values may carry credentials
+    log(2, request_line);
     let mut parts = request_line.split_whitespace();
     let method = parts.next().unwrap_or("").to_string();
-    let path = parts.next().unwrap_or("").to_string();
-    println!("{}", request_line.to_string());
+    let target = parts.next().unwrap_or("");
+    let version = parts.next().unwrap_or("HTTP/1.0").to_string();
+    // A request line we can't make out isn't worth guessing at
+    if !is_valid_header(&method, "") || !version.starts_with("HTTP/") || parts.next().is_some() {
+        return Err(ReadError::Status(400));
+    }
+    let (path, authority) = request_target(&method, target).ok_or(ReadError::Status(400))?;
+
+    let mut headers = HashMap::new();
+    // Every Content-Length value, including repeated fields and comma-separated lists
+    let mut content_lengths = Vec::new();
+    // The head ends with an empty line
+    if lines.clone().filter(|line| !line.is_empty()).count() > options.max_headers {
+        return Err(ReadError::Status(431));
+    }
+    for line in lines.filter(|line| !line.is_empty()) {
+        // Obsolete line folding, a line that isn't a field, and whitespace between a field
+        // name and its colon are all read differently by different parsers (RFC 9112 §5)
+        if line.starts_with([' ', '\t']) {
+            return Err(ReadError::Status(400));
+        }
+        let Some((key, value)) = line.split_once(':') else {
+            return Err(ReadError::Status(400));
+        };
+        if key.ends_with([' ', '\t']) {
+            return Err(ReadError::Status(400));
+        }
+        let key = key.to_ascii_lowercase();
+        if key == "content-length" {
+            content_lengths.extend(value.split(',').map(|v| v.trim().to_string()));
+        }
+        headers.insert(key, value.trim().to_string());
+    }
+
+    // The host a target names outranks the `Host` header
+    if let Some(authority) = authority {
+        headers.insert("host".to_string(), authority);
+    }
+
+    // Framing that a proxy in front of us might read differently is a request smuggling
+    // vector, so refuse it: Content-Length next to Transfer-Encoding, conflicting or
+    // malformed Content-Lengths
+    if !content_lengths.is_empty() && headers.contains_key("transfer-encoding") {
+        return Err(ReadError::Status(400));
+    }
+    let content_length = match content_lengths.first() {
+        Some(first) if content_lengths.iter().any(|value| value != first) => {
+            return Err(ReadError::Status(400));
+        }
+        Some(value) if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => {
+            value.parse::<usize>().map_err(|_| ReadError::Status(413))?
+        }
+        Some(_) => return Err(ReadError::Status(400)),
+        None => 0,
+    };
+    if content_length > MAX_BODY_SIZE {
+        return Err(ReadError::Status(413));
+    }
+    // A body in any other final coding has no end we could find
+    let chunked = match headers.get("transfer-encoding") {
+        Some(codings) => {
+            let last = codings.rsplit(',').next().unwrap_or("").trim();
+            if !last.eq_ignore_ascii_case("chunked") {
+                return Err(ReadError::Status(400));
+            }
+            true
+        }
+        None => false,
+    };
+    let id = request_id(&headers);
+    let client = forwarded::client(peer.addr, options.tls, &headers);
+    if chunked {
+        let announced = announced_trailers(&headers, options.strict_trailers);
+        if options.stream_body {
+            let request = Request {
+                method,
+                path,
+                version,
+                headers,
+                body: Vec::new(),
+                trailers: HashMap::new(),
+                id,
+                client_identity: peer.client_identity.clone(),
+                server_name: peer.server_name.clone(),
+                client,
+                connection_id: peer.connection_id,
+            };
+            return Ok((request, Unread::Chunked(announced)));
+        }
+        let (body, trailers) = read_chunked(stream, buffer, announced.as_deref()).await?;
+        let request = Request {
+            method,
+            path,
+            version,
+            headers,
+            body,
+            trailers,
+            id,
+            client_identity: peer.client_identity.clone(),
+            server_name: peer.server_name.clone(),
+            client,
+            connection_id: peer.connection_id,
+        };
+        return Ok((request, Unread::Length(0)));
+    }
+    if options.stream_body {
+        let request = Request {
+            method,
+            path,
+            version,
+            headers,
+            body: Vec::new(),
+            trailers: HashMap::new(),
+            id,
+            client_identity: peer.client_identity.clone(),
+            server_name: peer.server_name.clone(),
+            client,
+            connection_id: peer.connection_id,
+        };
+        return Ok((request, Unread::Length(content_length)));
+    }
+    buffer.reserve(content_length.saturating_sub(buffer.len()));
+    while buffer.len() < content_length {
+        if stream.read_buf(buffer).await? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+    }
+    let body = buffer.drain(..content_length).collect();
+
+    let request = Request {
+        method,
+        path,
+        version,
+        headers,
+        body,
+        trailers: HashMap::new(),
+        id,
+        client_identity: peer.client_identity.clone(),
+        server_name: peer.server_name.clone(),
+        client,
+        connection_id: peer.connection_id,
+    };
+    Ok((request, Unread::Length(0)))
+}
+
+// Reads requests off `stream` until it ends or one is refused, as a connection would, for
+// fuzzing the parser. The stream must never be pending: with all its bytes there up front, a
+// parser that still waits would hang the connection.
+#[cfg(any(test, feature = "fuzzing"))]
+pub(crate) fn read_all(
+    mut stream: BoxedStream,
+    strict_trailers: bool,
+) -> Vec<Result<Request, u16>> {
+    let options = ConnectionOptions {
+        max_requests: DEFAULT_MAX_REQUESTS_PER_CONNECTION,
+        keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
+        max_headers: DEFAULT_MAX_HEADERS,
+        strict_trailers,
+        stream_body: false,
+        tls: false,
+        internal: false,
+    };
+    let peer = Peer {
+        addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        client_identity: None,
+        server_name: None,
+        connection_id: None,
+    };
+    let mut buffer = Vec::new();
+    let mut requests = Vec::new();
+    let mut cx = Context::from_waker(std::task::Waker::noop());
+    loop {
+        let read = std::pin::pin!(read_request(&mut stream, &mut buffer, &peer, &options));
+        let Poll::Ready(read) = read.poll(&mut cx) else {
+            panic!("parser waited on a stream that had nothing more to give");
+        };
+        match read {
+            Ok((request, _)) => requests.push(Ok(request)),
+            Err(ReadError::Status(status_code)) => {
+                requests.push(Err(status_code));
+                return requests;
+            }
+            Err(ReadError::Closed) | Err(ReadError::Io(_)) => return requests,
+        }
+    }
+}
+
+// The trailer fields a request's `Trailer` header announces, in lowercase, when only those
+// are allowed; none at all when it has no `Trailer` header
+pub(crate) fn announced_trailers(
+    headers: &HashMap<String, String>,
+    strict: bool,
+) -> Option<Vec<String>> {
+    if !strict {
+        return None;
+    }
+    let names = headers.get("trailer").map_or("", String::as_str);
+    Some(
+        names
+            .split(',')
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect(),
+    )
+}
+
+// Reads a chunked body off the stream, then the trailer fields after it. With `announced`,
+// trailers not named in it are refused.
+async fn read_chunked(
+    stream: &mut BoxedStream,
+    buffer: &mut Vec<u8>,
+    announced: Option<&[String]>,
+) -> Result<(Vec<u8>, HashMap<String, String>), ReadError> {
+    let mut body = Vec::new();
+    loop {
+        let size = read_chunk_size(stream, buffer, body.len()).await?;
+        if size == 0 {
+            break;
+        }
+        while buffer.len() < size {
+            buffer.reserve(size - buffer.len());
+            if stream.read_buf(buffer).await? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+        body.extend(buffer.drain(..size));
+        read_chunk_end(stream, buffer).await?;
+    }
+    let trailers = read_trailers(stream, buffer, announced).await?;
+    Ok((body, trailers))
+}
 
-    let request = Request { method, path };
-    if let Err(e) = handler(&request, stream).await {
-        todo!("{e}")
+// Reads the size line of the next chunk of a body `read` bytes into, refusing chunks that
+// would take it over the limit. The last chunk is 0 bytes.
+async fn read_chunk_size(
+    stream: &mut BoxedStream,
+    buffer: &mut Vec<u8>,
+    read: usize,
+) -> Result<usize, ReadError> {
+    let line = read_line(stream, buffer).await?;
+    // Chunk extensions are allowed and ignored
+    let size = line.split(';').next().unwrap_or("").trim();
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(ReadError::Status(400));
+    }
+    let size = usize::from_str_radix(size, 16).map_err(|_| ReadError::Status(413))?;
+    // Checked before anything is added to `size`, which a client can make as large as it likes
+    if size > MAX_BODY_SIZE - read {
+        return Err(ReadError::Status(413));
     }
+    Ok(size)
+}
 
+// Reads the CRLF that ends a chunk's data
+async fn read_chunk_end(stream: &mut BoxedStream, buffer: &mut Vec<u8>) -> Result<(), ReadError> {
+    while buffer.len() < 2 {
+        buffer.reserve(READ_SIZE);
+        if stream.read_buf(buffer).await? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+    }
+    if &buffer[..2] != b"\r\n" {
+        return Err(ReadError::Status(400));
+    }
+    buffer.drain(..2);
     Ok(())
 }
+
+// Reads the trailer fields after the last chunk, up to the empty line that ends the body. With
+// `announced`, trailers not named in it are refused.
+async fn read_trailers(
+    stream: &mut BoxedStream,
+    buffer: &mut Vec<u8>,
+    announced: Option<&[String]>,
+) -> Result<HashMap<String, String>, ReadError> {
+    let mut trailers = HashMap::new();
+    let mut size = 0;
+    loop {
+        let line = read_line(stream, buffer).await?;
+        if line.is_empty() {
+            return Ok(trailers);
+        }
+        size += line.len();
+        if size > MAX_HEADER_SIZE {
+            return Err(ReadError::Status(431));
+        }
+        // Held to the same rules as header fields, see `read_request`
+        if line.starts_with([' ', '\t']) {
+            return Err(ReadError::Status(400));
+        }
+        let Some((key, value)) = line.split_once(':') else {
+            return Err(ReadError::Status(400));
+        };
+        if key.ends_with([' ', '\t']) {
+            return Err(ReadError::Status(400));
+        }
+        let key = key.to_ascii_lowercase();
+        if announced.is_some_and(|names| !names.contains(&key)) {
+            return Err(ReadError::Status(400));
+        }
+        trailers.insert(key, value.trim().to_string());
+    }
+}
+
+// Reads up to the next CRLF, which it consumes
+async fn read_line(stream: &mut BoxedStream, buffer: &mut Vec<u8>) -> Result<String, ReadError> {
+    loop {
+        if let Some(pos) = buffer.windows(2).position(|w| w == b"\r\n") {
+            let line = String::from_utf8_lossy(&buffer[..pos]).into_owned();
+            buffer.drain(..pos + 2);
+            return Ok(line);
+        }
+        if buffer.len() > MAX_HEADER_SIZE {
+            return Err(ReadError::Status(431));
+        }
+        buffer.reserve(READ_SIZE);
+        if stream.read_buf(buffer).await? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fuzzing::parse_requests;
+
+    fn parse(data: &[u8]) -> Vec<Result<String, u16>> {
+        parse_strict(data, false)
+    }
+
+    // Parses `data` a byte at a time and as one read, which must agree
+    fn parse_strict(data: &[u8], strict_trailers: bool) -> Vec<Result<String, u16>> {
+        let parsed = |read_size| {
+            parse_requests(data, read_size, strict_trailers)
+                .into_iter()
+                .map(|request| request.map(|request| request.method))
+                .collect::<Vec<_>>()
+        };
+        let whole = parsed(data.len());
+        assert_eq!(parsed(1), whole);
+        whole
+    }
+
+    #[test]
+    fn chunk_size_past_the_body_limit_is_refused() {
+        let data = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            1\r\na\r\nFFFFFFFFFFFFFFFF\r\n";
+        assert_eq!(parse(data), [Err(413)]);
+    }
+
+    #[test]
+    fn content_length_next_to_transfer_encoding_is_refused() {
+        let data = b"POST / HTTP/1.1\r\nContent-Length: 4\r\n\
+            Transfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        assert_eq!(parse(data), [Err(400)]);
+    }
+
+    #[test]
+    fn conflicting_content_lengths_are_refused() {
+        let data = b"POST / HTTP/1.1\r\nContent-Length: 4\r\nContent-Length: 5\r\n\r\nabcde";
+        assert_eq!(parse(data), [Err(400)]);
+        let data = b"POST / HTTP/1.1\r\nContent-Length: 4, 5\r\n\r\nabcde";
+        assert_eq!(parse(data), [Err(400)]);
+        // Repeats of the same length are one length
+        let data = b"POST / HTTP/1.1\r\nContent-Length: 4, 4\r\n\r\nabcd";
+        assert_eq!(parse(data), [Ok("POST".to_string())]);
+    }
+
+    #[test]
+    fn non_numeric_content_length_is_refused() {
+        for value in ["", "-1", "+4", "0x4", "4 4", "four"] {
+            let data = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\nabcd", value);
+            assert_eq!(parse(data.as_bytes()), [Err(400)], "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn whitespace_before_the_colon_is_refused() {
+        let data = b"POST / HTTP/1.1\r\nTransfer-Encoding : chunked\r\n\r\n0\r\n\r\n";
+        assert_eq!(parse(data), [Err(400)]);
+        let data = b"POST / HTTP/1.1\r\nContent-Length\t: 4\r\n\r\nabcd";
+        assert_eq!(parse(data), [Err(400)]);
+    }
+
+    #[test]
+    fn folded_and_colonless_lines_are_refused() {
+        let data = b"GET / HTTP/1.1\r\nX-Long: a\r\n  continued\r\n\r\n";
+        assert_eq!(parse(data), [Err(400)]);
+        let data = b"GET / HTTP/1.1\r\nNot a header\r\n\r\n";
+        assert_eq!(parse(data), [Err(400)]);
+    }
+
+    #[test]
+    fn strict_trailers_need_announcing() {
+        let data = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            0\r\nX-Checksum: 1\r\n\r\n";
+        assert_eq!(parse_strict(data, true), [Err(400)]);
+        assert_eq!(parse_strict(data, false), [Ok("POST".to_string())]);
+        let data = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTrailer: X-Checksum\r\n\r\n\
+            0\r\nX-Checksum: 1\r\n\r\n";
+        assert_eq!(parse_strict(data, true), [Ok("POST".to_string())]);
+    }
+
+    #[test]
+    fn whitespace_before_a_trailer_colon_is_refused() {
+        let data = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTrailer: X-Checksum\r\n\r\n\
+            0\r\nX-Checksum : 1\r\n\r\n";
+        assert_eq!(parse_strict(data, true), [Err(400)]);
+        assert_eq!(parse_strict(data, false), [Err(400)]);
+    }
+
+    #[test]
+    fn patch_bodies_come_through_as_post_bodies_do() {
+        let body = br#"{"op":"replace","path":"/name","value":"caf\u00e9 \r\n"}"#;
+        for method in ["POST", "PATCH"] {
+            let mut sized = format!(
+                "{} /items/1 HTTP/1.1\r\nContent-Type: application/json\r\n\
+                Content-Length: {}\r\n\r\n",
+                method,
+                body.len()
+            )
+            .into_bytes();
+            sized.extend_from_slice(body);
+            let mut chunked = format!(
+                "{} /items/1 HTTP/1.1\r\nContent-Type: application/json\r\n\
+                Transfer-Encoding: chunked\r\n\r\n{:x}\r\n",
+                method,
+                body.len()
+            )
+            .into_bytes();
+            chunked.extend_from_slice(body);
+            chunked.extend_from_slice(b"\r\n0\r\n\r\n");
+            for data in [sized, chunked] {
+                for read_size in [1, data.len()] {
+                    let parsed = parse_requests(&data, read_size, false);
+                    let [Ok(request)] = parsed.as_slice() else {
+                        panic!("{} not parsed: {:?}", method, parsed.len());
+                    };
+                    assert_eq!(request.method, method);
+                    assert_eq!(request.body, body);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn streamed_chunked_bodies_are_decoded() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let (finished, read_past) = oneshot::channel();
+        let mut body = BodyReader {
+            source: BodySource::Http1Chunked {
+                stream: Box::new(server),
+                buffer: b"5\r\nhel".to_vec(),
+                left: 0,
+                read: 0,
+                announced: Some(vec!["x-sum".to_string()]),
+                trailers: HashMap::new(),
+                finished: Some(finished),
+            },
+        };
+        client
+            .write_all(b"lo\r\n3\r\nabc\r\n0\r\nX-Sum: 1\r\n\r\nGET / HTTP/1.1\r\n")
+            .await
+            .unwrap();
+        let mut read = Vec::new();
+        while let Some(chunk) = body.chunk().await.unwrap() {
+            read.extend(chunk);
+        }
+        assert_eq!(read, b"helloabc");
+        assert_eq!(body.trailers().unwrap()["x-sum"], "1");
+        assert_eq!(read_past.await.unwrap(), b"GET / HTTP/1.1\r\n");
+    }
+}
@@ -0,0 +1,386 @@
+use serde_json::{json, Value};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::nodehttp::Response;
+
+// Finished spans are sent off this often, or as soon as this many are waiting
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+const BATCH_SIZE: usize = 512;
+// Spans finished while the collector can't keep up are dropped past this many
+const MAX_PENDING: usize = 8 * BATCH_SIZE;
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// OTLP span kinds
+const KIND_INTERNAL: u8 = 1;
+const KIND_SERVER: u8 = 2;
+
+// Where finished spans go
+struct Exporter {
+    // `host:port` to connect to, and the `Host` and path to post to
+    address: String,
+    host: String,
+    path: String,
+    service_name: String,
+}
+
+static EXPORTER: Mutex<Option<Arc<Exporter>>> = Mutex::new(None);
+// Spans in OTLP JSON, waiting to be exported
+static PENDING: Mutex<Vec<Value>> = Mutex::new(Vec::new());
+static EXPORTING: AtomicBool = AtomicBool::new(false);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref ID_KEYS: RandomState = RandomState::new();
+}
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Traces every request as an OpenTelemetry server span, exported with OTLP over HTTP (JSON)
+/// to the collector at `endpoint`, e.g. `http://localhost:4318`; an endpoint without a path
+/// posts to `/v1/traces`. A request's `traceparent` and `tracestate` headers make its span part
+/// of the caller's trace, and the guest gets the span's own in `http.request` (`traceparent`,
+/// `tracestate`) to pass on in the requests it makes. Its time in the guest's `http.request`
+/// handler is a child span. Requests whose caller isn't sampling aren't exported. Spans go out
+/// in batches every few seconds, named by `service_name` (default `mocketd`). Only plain HTTP
+/// collectors are supported. `None` (the default) traces nothing.
+pub fn set_otlp_exporter(endpoint: Option<&str>, service_name: Option<&str>) -> Result<(), String> {
+    let Some(endpoint) = endpoint else {
+        *EXPORTER.lock().unwrap() = None;
+        return Ok(());
+    };
+    let invalid = |reason: &str| format!("Invalid OTLP endpoint {:?}: {}", endpoint, reason);
+    let rest = endpoint
+        .strip_prefix("http://")
+        .ok_or_else(|| invalid("expected http://HOST[:PORT][/PATH]"))?;
+    let (host, path) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, ""),
+    };
+    if host.is_empty() {
+        return Err(invalid("no host"));
+    }
+    // A port after the last colon, unless that's inside a bracketed IPv6 address
+    let address = match host.rsplit_once(':') {
+        Some((_, port)) if !port.ends_with(']') => {
+            port.parse::<u16>().map_err(|_| invalid("bad port"))?;
+            host.to_string()
+        }
+        _ => format!("{}:80", host),
+    };
+    let path = match path {
+        "" | "/" => "/v1/traces",
+        path => path,
+    };
+    *EXPORTER.lock().unwrap() = Some(Arc::new(Exporter {
+        address,
+        host: host.to_string(),
+        path: path.to_string(),
+        service_name: service_name.unwrap_or("mocketd").to_string(),
+    }));
+    Ok(())
+}
+
+fn is_enabled() -> bool {
+    EXPORTER.lock().unwrap().is_some()
+}
+
+fn random_id() -> u64 {
+    let nanos = now_nanos();
+    ID_KEYS.hash_one((NEXT_ID.fetch_add(1, Ordering::Relaxed), nanos))
+}
+
+pub(crate) fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+}
+
+// Where a span sits in its trace, as `traceparent` carries it
+#[derive(Clone)]
+pub(crate) struct Context {
+    trace_id: String,
+    span_id: String,
+    sampled: bool,
+    tracestate: Option<String>,
+}
+
+impl Context {
+    // The `traceparent` header naming this span as the parent
+    pub(crate) fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            self.trace_id, self.span_id, self.sampled as u8
+        )
+    }
+
+    pub(crate) fn tracestate(&self) -> Option<&str> {
+        self.tracestate.as_deref()
+    }
+
+    // Records a span under this one for something that went from `start` to now, e.g. the
+    // guest handling the request
+    pub(crate) fn child(&self, name: &str, start: u64) {
+        if !self.sampled {
+            return;
+        }
+        finish(json!({
+            "traceId": self.trace_id,
+            "spanId": format!("{:016x}", random_id()),
+            "parentSpanId": self.span_id,
+            "name": name,
+            "kind": KIND_INTERNAL,
+            "startTimeUnixNano": start.to_string(),
+            "endTimeUnixNano": now_nanos().to_string(),
+        }));
+    }
+}
+
+// The trace and span ids and whether they're sampled from a `traceparent`, if it's valid
+fn parse_traceparent(value: &str) -> Option<(String, String, bool)> {
+    let mut parts = value.trim().split('-');
+    let (version, trace_id, span_id, flags) =
+        (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    let is_hex = |part: &str, len: usize| {
+        part.len() == len && part.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    };
+    // Later versions may add fields, but keep these first
+    if !is_hex(version, 2) || version == "ff" || (version == "00" && parts.next().is_some()) {
+        return None;
+    }
+    if !is_hex(trace_id, 32) || !is_hex(span_id, 16) || !is_hex(flags, 2) {
+        return None;
+    }
+    if trace_id.bytes().all(|b| b == b'0') || span_id.bytes().all(|b| b == b'0') {
+        return None;
+    }
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    Some((trace_id.to_string(), span_id.to_string(), flags & 1 == 1))
+}
+
+// A request's server span, held by its response and recorded when that's done
+struct Span {
+    context: Context,
+    parent_span_id: Option<String>,
+    name: String,
+    start: u64,
+    attributes: Vec<Value>,
+    status_code: Arc<AtomicU16>,
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if !self.context.sampled {
+            return;
+        }
+        let mut attributes = std::mem::take(&mut self.attributes);
+        let status_code = self.status_code.load(Ordering::Relaxed);
+        if status_code != 0 {
+            attributes.push(attribute("http.response.status_code", json!(status_code)));
+        }
+        let mut span = json!({
+            "traceId": self.context.trace_id,
+            "spanId": self.context.span_id,
+            "name": self.name,
+            "kind": KIND_SERVER,
+            "startTimeUnixNano": self.start.to_string(),
+            "endTimeUnixNano": now_nanos().to_string(),
+            "attributes": attributes,
+        });
+        if let Some(parent_span_id) = &self.parent_span_id {
+            span["parentSpanId"] = json!(parent_span_id);
+        }
+        // Only server errors are the server's fault
+        if status_code >= 500 {
+            span["status"] = json!({ "code": 2 });
+        }
+        if let Some(tracestate) = &self.context.tracestate {
+            span["traceState"] = json!(tracestate);
+        }
+        finish(span);
+    }
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+    match value {
+        Value::Number(number) => json!({ "key": key, "value": { "intValue": number.to_string() } }),
+        value => json!({ "key": key, "value": { "stringValue": value } }),
+    }
+}
+
+// Starts the span for the request `response` answers, which ends once the response is done,
+// and gives its context for the guest. `None` when there's no exporter.
+pub(crate) fn start(
+    response: &mut Response,
+    method: &str,
+    target: &str,
+    client_address: &str,
+) -> Option<Context> {
+    if !is_enabled() {
+        return None;
+    }
+    let parent = response
+        .request_header("traceparent")
+        .and_then(parse_traceparent);
+    let span_id = format!("{:016x}", random_id());
+    let (context, parent_span_id) = match parent {
+        Some((trace_id, parent_span_id, sampled)) => {
+            let tracestate = response
+                .request_header("tracestate")
+                .filter(|state| !state.trim().is_empty())
+                .map(str::to_string);
+            let context = Context {
+                trace_id,
+                span_id,
+                sampled,
+                tracestate,
+            };
+            (context, Some(parent_span_id))
+        }
+        None => {
+            let context = Context {
+                trace_id: format!("{:016x}{:016x}", random_id(), random_id()),
+                span_id,
+                sampled: true,
+                tracestate: None,
+            };
+            (context, None)
+        }
+    };
+
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    };
+    let mut attributes = vec![
+        attribute("http.request.method", json!(method)),
+        attribute("url.path", json!(path)),
+        attribute("client.address", json!(client_address)),
+    ];
+    if let Some(query) = query {
+        attributes.push(attribute("url.query", json!(query)));
+    }
+    if let Some(host) = response.request_header("host") {
+        attributes.push(attribute("server.address", json!(host)));
+    }
+    if let Some(user_agent) = response.request_header("user-agent") {
+        attributes.push(attribute("user_agent.original", json!(user_agent)));
+    }
+
+    let status_code = Arc::new(AtomicU16::new(0));
+    let answered = status_code.clone();
+    response.on_head(move |status_code, _| answered.store(*status_code, Ordering::Relaxed));
+    response.hold(Span {
+        context: context.clone(),
+        parent_span_id,
+        name: method.to_string(),
+        start: now_nanos(),
+        attributes,
+        status_code,
+    });
+    Some(context)
+}
+
+// Queues a finished span for export, starting the exporter if it isn't running
+fn finish(span: Value) {
+    let mut pending = PENDING.lock().unwrap();
+    if pending.len() >= MAX_PENDING {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    pending.push(span);
+    drop(pending);
+    if let Ok(handle) = tokio::runtime::Handle::try_current() {
+        if !EXPORTING.swap(true, Ordering::SeqCst) {
+            handle.spawn(export_loop());
+        }
+    }
+}
+
+// Sends the finished spans off in batches, for as long as there's an exporter
+async fn export_loop() {
+    loop {
+        let started = tokio::time::Instant::now();
+        while started.elapsed() < EXPORT_INTERVAL && PENDING.lock().unwrap().len() < BATCH_SIZE {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let Some(exporter) = EXPORTER.lock().unwrap().clone() else {
+            PENDING.lock().unwrap().clear();
+            EXPORTING.store(false, Ordering::SeqCst);
+            return;
+        };
+        loop {
+            let batch: Vec<Value> = {
+                let mut pending = PENDING.lock().unwrap();
+                let len = pending.len().min(BATCH_SIZE);
+                pending.drain(..len).collect()
+            };
+            if batch.is_empty() {
+                break;
+            }
+            let dropped = DROPPED.swap(0, Ordering::Relaxed);
+            if dropped > 0 {
+                crate::log(
+                    1,
+                    &format!("Dropped {} spans the collector couldn't take", dropped),
+                );
+            }
+            let count = batch.len();
+            match tokio::time::timeout(EXPORT_TIMEOUT, export(&exporter, batch)).await {
+                Ok(Ok(())) => crate::log(2, &format!("Exported {} spans", count)),
+                Ok(Err(err)) => {
+                    crate::log(1, &format!("Failed to export {} spans: {}", count, err));
+                    break;
+                }
+                Err(_) => {
+                    crate::log(1, &format!("Timed out exporting {} spans", count));
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// Posts `spans` to the collector as an OTLP `ExportTraceServiceRequest`
+async fn export(exporter: &Exporter, spans: Vec<Value>) -> io::Result<()> {
+    let body = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [attribute("service.name", json!(exporter.service_name))],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "mocketd", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    })
+    .to_string();
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        exporter.path,
+        exporter.host,
+        body.len(),
+        body
+    );
+    let mut stream = TcpStream::connect(&exporter.address).await?;
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let status_line = response
+        .split(|&b| b == b'\n')
+        .next()
+        .map(String::from_utf8_lossy)
+        .unwrap_or_default();
+    match status_line.split(' ').nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "collector answered {:?}",
+            status_line.trim_end()
+        ))),
+    }
+}
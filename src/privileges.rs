@@ -0,0 +1,150 @@
+use std::io;
+#[cfg(unix)]
+use std::sync::Mutex;
+
+// Who to become once the first listener is bound
+#[cfg(unix)]
+struct RunAs {
+    uid: Option<libc::uid_t>,
+    gid: libc::gid_t,
+    // Whose supplementary groups to take on, when switching users
+    user: Option<std::ffi::CString>,
+}
+
+#[cfg(unix)]
+static RUN_AS: Mutex<Option<RunAs>> = Mutex::new(None);
+
+/// Switches to `user` and/or `group`, by name or number, once the first listener is bound and
+/// before it accepts a connection, so privileged ports can be bound without serving as root.
+/// `group` defaults to the user's primary group. Ports bound afterwards, and files opened
+/// afterwards (e.g. TLS keys reloaded on `SIGHUP`), need to be accessible to them. A no-op
+/// elsewhere than on Unix.
+#[cfg(unix)]
+pub fn set_run_as(user: Option<&str>, group: Option<&str>) -> Result<(), String> {
+    let user = user.map(lookup_user).transpose()?;
+    let gid = match (group, &user) {
+        (Some(group), _) => lookup_group(group)?,
+        (None, Some((_, gid, _))) => *gid,
+        (None, None) => {
+            *RUN_AS.lock().unwrap() = None;
+            return Ok(());
+        }
+    };
+    let (uid, user) = match user {
+        Some((uid, _, name)) => (Some(uid), Some(name)),
+        None => (None, None),
+    };
+    *RUN_AS.lock().unwrap() = Some(RunAs { uid, gid, user });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn set_run_as(_user: Option<&str>, _group: Option<&str>) -> Result<(), String> {
+    Ok(())
+}
+
+// Gives up root for the user and group `set_run_as` asked for, the first time a listener is
+// bound. A listener whose drop failed doesn't get to accept anything.
+#[cfg(unix)]
+pub(crate) fn drop_after_bind() -> io::Result<()> {
+    let mut run_as = RUN_AS.lock().unwrap();
+    let Some(RunAs { uid, gid, user }) = run_as.as_ref() else {
+        return Ok(());
+    };
+    // SAFETY: plain system calls; `user` is a valid C string
+    let dropped = unsafe {
+        // Supplementary groups go first, while we still may set them
+        let groups = match user {
+            Some(user) => libc::initgroups(user.as_ptr(), *gid as _),
+            None => libc::setgroups(1, gid),
+        };
+        groups == 0 && libc::setgid(*gid) == 0 && uid.is_none_or(|uid| libc::setuid(uid) == 0)
+    };
+    if !dropped {
+        return Err(io::Error::other(format!(
+            "failed to drop privileges: {}",
+            io::Error::last_os_error()
+        )));
+    }
+    // Root must be out of reach now, unless it's who we were asked to become
+    if uid.is_some_and(|uid| uid != 0) && unsafe { libc::setuid(0) } == 0 {
+        return Err(io::Error::other(
+            "privileges could be regained after dropping",
+        ));
+    }
+    crate::log(
+        1,
+        &format!(
+            "Running as uid {}, gid {}",
+            unsafe { libc::getuid() },
+            unsafe { libc::getgid() }
+        ),
+    );
+    *run_as = None;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn drop_after_bind() -> io::Result<()> {
+    Ok(())
+}
+
+// The uid, primary gid and name of `user`, a name or a number
+#[cfg(unix)]
+fn lookup_user(user: &str) -> Result<(libc::uid_t, libc::gid_t, std::ffi::CString), String> {
+    let name = std::ffi::CString::new(user).map_err(|_| format!("Invalid user {:?}", user))?;
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    // SAFETY: every pointer is valid for the sizes we pass
+    let result = unsafe {
+        match user.parse::<libc::uid_t>() {
+            Ok(uid) => libc::getpwuid_r(
+                uid,
+                &mut entry,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut found,
+            ),
+            Err(_) => libc::getpwnam_r(
+                name.as_ptr(),
+                &mut entry,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut found,
+            ),
+        }
+    };
+    if result != 0 || found.is_null() {
+        return Err(format!("Unknown user {:?}", user));
+    }
+    // SAFETY: `pw_name` points into `buffer`, filled in by a successful lookup
+    let name = unsafe { std::ffi::CStr::from_ptr(entry.pw_name) }.to_owned();
+    Ok((entry.pw_uid, entry.pw_gid, name))
+}
+
+// The gid of `group`, a name or a number
+#[cfg(unix)]
+fn lookup_group(group: &str) -> Result<libc::gid_t, String> {
+    if let Ok(gid) = group.parse::<libc::gid_t>() {
+        return Ok(gid);
+    }
+    let name = std::ffi::CString::new(group).map_err(|_| format!("Invalid group {:?}", group))?;
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    // SAFETY: every pointer is valid for the sizes we pass
+    let result = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+    if result != 0 || found.is_null() {
+        return Err(format!("Unknown group {:?}", group));
+    }
+    Ok(entry.gr_gid)
+}
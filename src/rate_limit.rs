@@ -0,0 +1,71 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Clients are spread over this many independently locked maps
+const SHARDS: usize = 16;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// Token buckets per client IP. Buckets refill as they're used, and `prune` drops full ones.
+#[derive(Clone)]
+pub(crate) struct RateLimiter {
+    // Tokens added per second
+    rate: f64,
+    // Bucket capacity, i.e. how many requests a client can make at once
+    burst: f64,
+    shards: Arc<[Mutex<HashMap<IpAddr, Bucket>>; SHARDS]>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        RateLimiter {
+            rate,
+            burst: burst.max(1.0),
+            shards: Arc::new(std::array::from_fn(|_| Mutex::new(HashMap::new()))),
+        }
+    }
+
+    fn shard(&self, ip: IpAddr) -> &Mutex<HashMap<IpAddr, Bucket>> {
+        let mut hasher = DefaultHasher::new();
+        ip.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    // Takes a token for `ip`, or says how long until the next one is available
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut shard = self.shard(ip).lock().unwrap();
+        let bucket = shard.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    // Forgets clients whose buckets have refilled; they're no different from new ones
+    pub fn prune(&self) {
+        let now = Instant::now();
+        for shard in self.shards.iter() {
+            shard.lock().unwrap().retain(|_, bucket| {
+                let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens + elapsed * self.rate < self.burst
+            });
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// Routes the guest has declared through `http.route`, used to answer requests host-side
+struct Route {
+    method: String,
+    pattern: String,
+    limit: Option<Arc<Limit>>,
+    // How long the guest has to answer before the client gets 504
+    timeout: Option<Duration>,
+}
+
+impl Route {
+    fn accepts(&self, method: &str, path: &str) -> bool {
+        (self.method == "ALL"
+            || self.method == method
+            || (method == "HEAD" && self.method == "GET"))
+            && matches(&self.pattern, path)
+    }
+}
+
+// How many requests to a route the guest may be handling at once
+pub(crate) struct Limit {
+    max_concurrency: usize,
+    // Requests over the limit wait for a turn, up to this many; `None` for no bound
+    max_queue: Option<usize>,
+    semaphore: Arc<Semaphore>,
+    queued: AtomicUsize,
+}
+
+impl Limit {
+    pub(crate) fn new(max_concurrency: usize, max_queue: Option<usize>) -> Self {
+        Limit {
+            max_concurrency,
+            max_queue,
+            semaphore: Arc::new(Semaphore::new(max_concurrency)),
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    // A turn to handle a request, held until the response is done; `None` if the queue is full
+    pub(crate) async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        if let Ok(permit) = Arc::clone(&self.semaphore).try_acquire_owned() {
+            return Some(permit);
+        }
+        let queued = self.queued.fetch_add(1, Ordering::SeqCst);
+        if self.max_queue.is_some_and(|max| queued >= max) {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        let permit = Arc::clone(&self.semaphore).acquire_owned().await.ok();
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        permit
+    }
+
+    fn in_flight(&self) -> usize {
+        self.max_concurrency - self.semaphore.available_permits()
+    }
+}
+
+lazy_static! {
+    static ref ROUTES: Mutex<Vec<Route>> = Mutex::new(Vec::new());
+    // From `route.methods`: path patterns and the methods the guest handles on them
+    static ref METHODS: Mutex<Vec<(String, Vec<String>)>> = Mutex::new(Vec::new());
+}
+
+const ALL_METHODS: [&str; 7] = ["GET", "HEAD", "POST", "PUT", "DELETE", "PATCH", "OPTIONS"];
+
+pub(crate) fn register(
+    method: &str,
+    pattern: &str,
+    limit: Option<Limit>,
+    timeout: Option<Duration>,
+) {
+    ROUTES.lock().unwrap().push(Route {
+        method: method.to_uppercase(),
+        pattern: pattern.to_string(),
+        limit: limit.map(Arc::new),
+        timeout,
+    });
+}
+
+// The concurrency limit of the first limited route a request matches
+pub(crate) fn limit_for(method: &str, path: &str) -> Option<Arc<Limit>> {
+    let path = path.split('?').next().unwrap_or(path);
+    ROUTES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|route| route.accepts(method, path))
+        .find_map(|route| route.limit.clone())
+}
+
+// The time budget of the first route with one that a request matches
+pub(crate) fn timeout_for(method: &str, path: &str) -> Option<Duration> {
+    let path = path.split('?').next().unwrap_or(path);
+    ROUTES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|route| route.accepts(method, path))
+        .find_map(|route| route.timeout)
+}
+
+// The current load on each limited route, for `http.routeStats`
+pub(crate) fn stats() -> Value {
+    let routes = ROUTES.lock().unwrap();
+    let stats = routes
+        .iter()
+        .filter_map(|route| {
+            let limit = route.limit.as_ref()?;
+            Some(json!({
+                "method": route.method,
+                "path": route.pattern,
+                "maxConcurrency": limit.max_concurrency,
+                "inFlight": limit.in_flight(),
+                "queued": limit.queued.load(Ordering::SeqCst),
+            }))
+        })
+        .collect();
+    Value::Array(stats)
+}
+
+// The routes and method map of a guest being replaced, see `take`
+pub(crate) struct Saved {
+    routes: Vec<Route>,
+    methods: Vec<(String, Vec<String>)>,
+}
+
+// Clears the routes for a reloaded guest to declare its own, keeping the old ones in case it
+// fails to start
+pub(crate) fn take() -> Saved {
+    Saved {
+        routes: std::mem::take(&mut *ROUTES.lock().unwrap()),
+        methods: std::mem::take(&mut *METHODS.lock().unwrap()),
+    }
+}
+
+// Puts back the routes of the guest that keeps running
+pub(crate) fn restore(saved: Saved) {
+    *ROUTES.lock().unwrap() = saved.routes;
+    *METHODS.lock().unwrap() = saved.methods;
+}
+
+// Replaces the method map declared with `route.methods`
+pub(crate) fn set_methods(methods: Vec<(String, Vec<String>)>) {
+    let methods = methods
+        .into_iter()
+        .map(|(pattern, methods)| {
+            let methods = methods.iter().map(|method| method.to_uppercase()).collect();
+            (pattern, methods)
+        })
+        .collect();
+    *METHODS.lock().unwrap() = methods;
+}
+
+// The `Allow` header for a request the method map turns away, or `None` if the guest handles
+// `method` on `path` or declared nothing for it. Every pattern matching `path` (or every
+// pattern, when `path` is `*`) adds its methods; HEAD is implied by GET, and OPTIONS is always
+// allowed, answered host-side unless declared.
+pub(crate) fn disallowed(method: &str, path: &str) -> Option<String> {
+    let map = METHODS.lock().unwrap();
+    let path = path.split('?').next().unwrap_or(path);
+    let mut declared: Vec<&str> = Vec::new();
+    for (_, methods) in map
+        .iter()
+        .filter(|(pattern, _)| path == "*" || matches(pattern, path))
+    {
+        declared.extend(methods.iter().map(String::as_str));
+    }
+    if declared.is_empty()
+        || declared.contains(&method)
+        || (method == "HEAD" && declared.contains(&"GET"))
+    {
+        return None;
+    }
+
+    let mut allowed: Vec<&str> = ALL_METHODS
+        .into_iter()
+        .filter(|m| {
+            declared.contains(m) || (*m == "HEAD" && declared.contains(&"GET")) || *m == "OPTIONS"
+        })
+        .collect();
+    for method in declared {
+        if !allowed.contains(&method) {
+            allowed.push(method);
+        }
+    }
+    Some(allowed.join(", "))
+}
+
+pub(crate) fn is_configured() -> bool {
+    !ROUTES.lock().unwrap().is_empty()
+}
+
+// Whether a HEAD request to `path` goes to the guest as a GET: a GET route matches it and
+// none that declares HEAD (or every method) does
+pub(crate) fn head_via_get(path: &str) -> bool {
+    let routes = ROUTES.lock().unwrap();
+    let path = path.split('?').next().unwrap_or(path);
+    let mut has_get = false;
+    for route in routes.iter().filter(|route| matches(&route.pattern, path)) {
+        match route.method.as_str() {
+            "HEAD" | "ALL" => return false,
+            "GET" => has_get = true,
+            _ => {}
+        }
+    }
+    has_get
+}
+
+// The methods registered for `path` (or for any path when `path` is `*`), in a stable order.
+// HEAD is implied by GET and OPTIONS by any route; an empty list means no route matched.
+pub(crate) fn allowed_methods(path: &str) -> Vec<&'static str> {
+    let routes = ROUTES.lock().unwrap();
+    let path = path.split('?').next().unwrap_or(path);
+    let matched: Vec<&str> = routes
+        .iter()
+        .filter(|route| path == "*" || matches(&route.pattern, path))
+        .map(|route| route.method.as_str())
+        .collect();
+    if matched.is_empty() {
+        return Vec::new();
+    }
+
+    ALL_METHODS
+        .into_iter()
+        .filter(|method| {
+            matched
+                .iter()
+                .any(|m| *m == "ALL" || m == method || (*method == "HEAD" && *m == "GET"))
+                || *method == "OPTIONS"
+        })
+        .collect()
+}
+
+// Matches `/users/:id` style patterns segment by segment; a trailing `*` matches the rest
+pub(crate) fn matches(pattern: &str, path: &str) -> bool {
+    let mut pattern_segments = pattern.trim_matches('/').split('/');
+    let mut path_segments = path.trim_matches('/').split('/');
+    loop {
+        match (pattern_segments.next(), path_segments.next()) {
+            (Some("*"), _) => return true,
+            (Some(p), Some(s)) if p.starts_with(':') || p == s => continue,
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
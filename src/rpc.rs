@@ -0,0 +1,244 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// The error codes JSON-RPC 2.0 sets aside
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+
+static ENDPOINT: Mutex<Option<String>> = Mutex::new(None);
+
+lazy_static! {
+    // The calls of each request still waiting on the guest, by request id
+    static ref PENDING: Mutex<HashMap<usize, Pending>> = Mutex::new(HashMap::new());
+}
+
+/// Serves JSON-RPC 2.0 on `path`, or stops with `None`. The host takes `POST`s there apart
+/// itself, answering malformed ones with the standard `Parse error` and `Invalid Request`
+/// errors, and hands the guest each call of a request, or of a batch, as an `rpc.call` event
+/// of `[{ method, params?, id?, notification?, index, request }, { id, token }]`, where
+/// `request` is what `http.request` would carry. The guest answers each one that isn't a
+/// notification with `rpc.result` (`[id, index, result]`) or `rpc.error` (`[id, index,
+/// { code, message?, data? }]`), and once it has answered them all, the host sends back the
+/// response envelopes, in one array for a batch, or 204 when there's nothing to send. Other
+/// methods on `path` get 405.
+pub fn set_json_rpc(path: Option<&str>) -> Result<(), String> {
+    if let Some(path) = path.filter(|path| !path.starts_with('/')) {
+        return Err(format!(
+            "Invalid JSON-RPC path {:?}: expected one starting with /",
+            path
+        ));
+    }
+    *ENDPOINT.lock().unwrap() = path.map(str::to_string);
+    Ok(())
+}
+
+// Whether requests to `path` are JSON-RPC, see `set_json_rpc`
+pub(crate) fn is_endpoint(path: &str) -> bool {
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+    ENDPOINT.lock().unwrap().as_deref() == Some(path)
+}
+
+// Where each entry of a request stands
+enum Reply {
+    Waiting(Value),
+    Done(Value),
+    // Notifications get no response
+    Nothing,
+}
+
+// The replies to one request, in the order of its entries
+pub(crate) struct Pending {
+    batch: bool,
+    replies: Vec<Reply>,
+    // Still handing the calls to the guest, so not done even if it answered all so far
+    delivering: bool,
+}
+
+impl Pending {
+    // What goes back once no call is waiting: the response, the array of them for a batch,
+    // or `Null` for nothing at all
+    fn answer(&mut self) -> Option<Value> {
+        if self.delivering
+            || self
+                .replies
+                .iter()
+                .any(|reply| matches!(reply, Reply::Waiting(_)))
+        {
+            return None;
+        }
+        let mut responses = self.replies.drain(..).filter_map(|reply| match reply {
+            Reply::Done(response) => Some(response),
+            _ => None,
+        });
+        Some(if self.batch {
+            Value::Array(responses.collect())
+        } else {
+            responses.next().unwrap_or(Value::Null)
+        })
+    }
+
+    // The guest has had every call, so the answer only waits on the ones it hasn't answered
+    pub(crate) fn delivered(&mut self) -> Option<Value> {
+        self.delivering = false;
+        self.answer()
+    }
+}
+
+fn success(id: Value, result: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "result": result, "id": id })
+}
+
+fn failure(id: Value, error: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "error": error, "id": id })
+}
+
+fn standard_error(code: i64) -> Value {
+    let message = match code {
+        PARSE_ERROR => "Parse error",
+        INVALID_REQUEST => "Invalid Request",
+        -32601 => "Method not found",
+        -32602 => "Invalid params",
+        -32603 => "Internal error",
+        _ => "Server error",
+    };
+    json!({ "code": code, "message": message })
+}
+
+// Splits a request body into the calls for the guest, with the replies the host already
+// has for what's malformed. Once the calls are with the guest, see `begin` and `delivered`.
+pub(crate) fn parse(body: &[u8]) -> (Pending, Vec<Value>) {
+    let single = |reply| Pending {
+        batch: false,
+        replies: vec![reply],
+        delivering: true,
+    };
+    let (batch, entries) = match serde_json::from_slice(body) {
+        Ok(Value::Array(entries)) if !entries.is_empty() => (true, entries),
+        Ok(Value::Array(_)) => {
+            let error = failure(Value::Null, standard_error(INVALID_REQUEST));
+            return (single(Reply::Done(error)), Vec::new());
+        }
+        Ok(entry) => (false, vec![entry]),
+        Err(_) => {
+            let error = failure(Value::Null, standard_error(PARSE_ERROR));
+            return (single(Reply::Done(error)), Vec::new());
+        }
+    };
+    let mut replies = Vec::new();
+    let mut calls = Vec::new();
+    for (index, entry) in entries.into_iter().enumerate() {
+        match envelope(entry) {
+            Ok((id, mut call)) => {
+                call["index"] = json!(index);
+                match id {
+                    Some(id) => {
+                        call["id"] = id.clone();
+                        replies.push(Reply::Waiting(id));
+                    }
+                    None => {
+                        call["notification"] = Value::Bool(true);
+                        replies.push(Reply::Nothing);
+                    }
+                }
+                calls.push(call);
+            }
+            Err(id) => replies.push(Reply::Done(failure(id, standard_error(INVALID_REQUEST)))),
+        }
+    }
+    let pending = Pending {
+        batch,
+        replies,
+        delivering: true,
+    };
+    (pending, calls)
+}
+
+// A call's id, if it isn't a notification, and `{ method, params? }`, or the id to report it
+// invalid with
+fn envelope(entry: Value) -> Result<(Option<Value>, Value), Value> {
+    let Value::Object(mut entry) = entry else {
+        return Err(Value::Null);
+    };
+    let id = match entry.remove("id") {
+        Some(id @ (Value::String(_) | Value::Number(_) | Value::Null)) => Some(id),
+        Some(_) => return Err(Value::Null),
+        None => None,
+    };
+    let invalid = || Err(id.clone().unwrap_or(Value::Null));
+    if entry.get("jsonrpc").and_then(Value::as_str) != Some("2.0") {
+        return invalid();
+    }
+    let Some(Value::String(method)) = entry.remove("method") else {
+        return invalid();
+    };
+    let mut call = json!({ "method": method });
+    match entry.remove("params") {
+        Some(params @ (Value::Array(_) | Value::Object(_))) => call["params"] = params,
+        Some(_) => return invalid(),
+        None => {}
+    }
+    Ok((id, call))
+}
+
+// Starts waiting on the guest for request `id`'s calls
+pub(crate) fn begin(id: usize, pending: Pending) {
+    PENDING.lock().unwrap().insert(id, pending);
+}
+
+// The guest has had every call of request `id`; gives the answer if it's answered them all
+// already, or if none were waiting on it
+pub(crate) fn delivered(id: usize) -> Option<Value> {
+    let mut pending = PENDING.lock().unwrap();
+    let answer = pending.get_mut(&id)?.delivered();
+    if answer.is_some() {
+        pending.remove(&id);
+    }
+    answer
+}
+
+// Takes the guest's `result`, or `error` object, for call `index` of request `id`, and gives
+// the answer once no call is left waiting
+pub(crate) fn reply(
+    id: usize,
+    index: usize,
+    outcome: Result<Value, &Value>,
+) -> Result<Option<Value>, String> {
+    let mut pending = PENDING.lock().unwrap();
+    let calls = pending
+        .get_mut(&id)
+        .ok_or_else(|| format!("no JSON-RPC request with id {}", id))?;
+    let Some(Reply::Waiting(call_id)) = calls.replies.get(index) else {
+        return Err(format!("call {} of request {} isn't waiting", index, id));
+    };
+    let response = match outcome {
+        Ok(result) => success(call_id.clone(), result),
+        Err(Value::Object(error)) => {
+            let Some(code) = error.get("code").and_then(Value::as_i64) else {
+                return Err("the error's code isn't an integer".to_string());
+            };
+            let mut object = standard_error(code);
+            match error.get("message") {
+                Some(Value::String(message)) => object["message"] = json!(message),
+                Some(_) => return Err("the error's message isn't a string".to_string()),
+                None => {}
+            }
+            if let Some(data) = error.get("data") {
+                object["data"] = data.clone();
+            }
+            failure(call_id.clone(), object)
+        }
+        Err(_) => return Err("expected an error object".to_string()),
+    };
+    calls.replies[index] = Reply::Done(response);
+    let answer = calls.answer();
+    if answer.is_some() {
+        pending.remove(&id);
+    }
+    Ok(answer)
+}
+
+// Forgets request `id`'s calls once it's answered some other way
+pub(crate) fn cleanup(id: usize) {
+    PENDING.lock().unwrap().remove(&id);
+}
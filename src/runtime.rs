@@ -0,0 +1,811 @@
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::fs;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use wasmtime::component::Component;
+use wasmtime::*;
+
+use crate::middleware::{self, Middleware, MiddlewareFuture, Next};
+use crate::nodehttp::{Request, Response, MAX_BODY_SIZE};
+use crate::{
+    abandon_in_flight, abort_relisten, begin_relisten, cache, close_all, component, configure,
+    connection, end_relisten, handle_receive, idle_timeout, in_flight, is_listening, is_ready,
+    isolation, listen, log, nodehttp, port_override, profiling, ready_timeout, router, schema,
+    set_ready, take_sent_ready, warmup, watch_disconnects, Guest, ListenOptions, WASM,
+};
+
+/// How long [`Runtime::reload`] waits for the old guest's requests to finish.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+// How often to check whether the server has been idle for `set_idle_timeout`
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// How long responses in flight get to complete on shutdown, unless `set_shutdown_timeout`
+// says otherwise
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+// How long requests turned away on shutdown get to be answered
+const ABANDON_TIMEOUT: Duration = Duration::from_secs(1);
+// How long shutdown waits on the guest's lock at the least, however little of the shutdown
+// timeout responses in flight left over
+const GUEST_LOCK_GRACE: Duration = Duration::from_secs(1);
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+static SHUTDOWN_TIMEOUT: Mutex<Duration> = Mutex::new(DEFAULT_SHUTDOWN_TIMEOUT);
+
+/// How long a graceful shutdown (the guest's `runtime.shutdown`) waits for responses in
+/// flight, even if the guest is stuck answering them. The requests still unanswered then are
+/// logged and get 503, and a second later the process exits, closing whatever connections are
+/// left. `None` for the default of 30 seconds.
+pub fn set_shutdown_timeout(timeout: Option<Duration>) {
+    *SHUTDOWN_TIMEOUT.lock().unwrap() = timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT);
+}
+
+static MODULE_CACHE: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+// How modules are compiled, see `set_compiler`
+struct Compiler {
+    strategy: Strategy,
+    opt_level: OptLevel,
+    parallel: bool,
+}
+
+static COMPILER: Mutex<Compiler> = Mutex::new(Compiler {
+    strategy: Strategy::Auto,
+    opt_level: OptLevel::Speed,
+    parallel: true,
+});
+
+/// Chooses how modules are compiled, trading startup time for the speed of the guest:
+///
+/// - `Strategy::Cranelift` (what `Auto` picks) is the optimizing compiler. `OptLevel::Speed`
+///   (the default) or `SpeedAndSize` give the fastest guest, `OptLevel::None` compiles
+///   noticeably faster for slower code.
+/// - `Strategy::Winch` is a baseline compiler: it compiles many times faster than Cranelift,
+///   for code that runs a few times slower, and ignores `opt_level`. It needs the `winch`
+///   feature.
+/// - `parallel` compiles functions on every core; turn it off where a burst of CPU and memory
+///   at startup hurts more than a slower start.
+///
+/// With [`set_module_cache`], a module is only compiled once, so the slower settings cost
+/// less. Takes effect the next time a module is loaded.
+pub fn set_compiler(
+    strategy: Strategy,
+    opt_level: OptLevel,
+    parallel: bool,
+) -> std::result::Result<(), String> {
+    if strategy == Strategy::Winch && !cfg!(feature = "winch") {
+        return Err("Winch isn't available in this build; enable the winch feature".to_string());
+    }
+    *COMPILER.lock().unwrap() = Compiler {
+        strategy,
+        opt_level,
+        parallel,
+    };
+    Ok(())
+}
+
+/// Keeps compiled modules in `dir`, so a module that was compiled before, by the same version
+/// of wasmtime with the same settings, starts without compiling it again. `None` compiles
+/// every time.
+///
+/// Cached modules are loaded as native code without further checks, so `dir` must not be
+/// writable by anyone who couldn't already replace this binary.
+pub fn set_module_cache(dir: Option<&str>) {
+    *MODULE_CACHE.lock().unwrap() = dir.map(PathBuf::from);
+}
+
+// What a guest compiles to: a core module or a component, both of which can be cached
+trait Compiled: Sized {
+    fn compile(engine: &Engine, wasm_bytes: &[u8]) -> Result<Self>;
+    // SAFETY: `path` must hold an artifact `serialize` wrote
+    unsafe fn load(engine: &Engine, path: &Path) -> Result<Self>;
+    fn serialize(&self) -> Result<Vec<u8>>;
+}
+
+impl Compiled for Module {
+    fn compile(engine: &Engine, wasm_bytes: &[u8]) -> Result<Self> {
+        Module::new(engine, wasm_bytes)
+    }
+
+    unsafe fn load(engine: &Engine, path: &Path) -> Result<Self> {
+        Module::deserialize_file(engine, path)
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        Module::serialize(self)
+    }
+}
+
+impl Compiled for Component {
+    fn compile(engine: &Engine, wasm_bytes: &[u8]) -> Result<Self> {
+        Component::new(engine, wasm_bytes)
+    }
+
+    unsafe fn load(engine: &Engine, path: &Path) -> Result<Self> {
+        Component::deserialize_file(engine, path)
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        Component::serialize(self)
+    }
+}
+
+// Compiles `wasm_bytes`, or loads it from the module cache when it was compiled before
+fn compile<C: Compiled>(engine: &Engine, wasm_bytes: &[u8]) -> std::result::Result<C, String> {
+    let Some(dir) = MODULE_CACHE.lock().unwrap().clone() else {
+        return C::compile(engine, wasm_bytes)
+            .map_err(|err| format!("Failed to create module: {}", err));
+    };
+
+    // Artifacts only load into an engine of the same version and settings, so those are part
+    // of the key
+    let mut hasher = DefaultHasher::new();
+    wasm_bytes.hash(&mut hasher);
+    engine.precompile_compatibility_hash().hash(&mut hasher);
+    let path = dir.join(format!("{:016x}.cwasm", hasher.finish()));
+
+    if path.exists() {
+        // SAFETY: the cache only holds artifacts `Compiled::serialize` wrote, see
+        // `set_module_cache`; wasmtime rejects those from another version or configuration
+        match unsafe { C::load(engine, &path) } {
+            Ok(module) => {
+                log(
+                    2,
+                    &format!("Loaded compiled module from {}", path.display()),
+                );
+                return Ok(module);
+            }
+            Err(err) => log(
+                1,
+                &format!("Ignoring cached module {}: {}", path.display(), err),
+            ),
+        }
+    }
+
+    let module = C::compile(engine, wasm_bytes)
+        .map_err(|err| format!("Failed to create module: {}", err))?;
+    // A cache that can't be written only costs the next start its head start
+    if let Err(err) = save(&module, &path) {
+        log(
+            1,
+            &format!(
+                "Failed to cache compiled module in {}: {}",
+                dir.display(),
+                err
+            ),
+        );
+    }
+    Ok(module)
+}
+
+// Writes the compiled module to `path`, through a temporary file so a concurrent start never
+// reads half of it
+fn save(module: &impl Compiled, path: &Path) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let partial = path.with_extension(format!("{}.tmp", process::id()));
+    fs::write(&partial, module.serialize()?)?;
+    fs::rename(&partial, path).inspect_err(|_| {
+        let _ = fs::remove_file(&partial);
+    })?;
+    log(2, &format!("Cached compiled module in {}", path.display()));
+    Ok(())
+}
+
+// Exits with `code` after a graceful shutdown, as the guest asks with `runtime.shutdown`: no
+// new connections are accepted, responses in flight get up to the shutdown timeout to
+// complete, and requests still unanswered after that get 503
+pub(crate) fn shutdown(code: i32) {
+    if SHUTTING_DOWN.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    log(1, &format!("Shutting down with exit code {}", code));
+    close_all();
+    let timeout = *SHUTDOWN_TIMEOUT.lock().unwrap();
+    let deadline = Instant::now() + timeout;
+    tokio::spawn(async move {
+        drain(timeout).await;
+        // Takes the guest's lock, so this waits for a call in progress to return, but only
+        // for as long as the timeout has left, or the grace period when that's longer
+        let abandoned = tokio::task::spawn_blocking(|| {
+            let _wasm = WASM.lock().unwrap();
+            abandon_in_flight();
+        });
+        let left = deadline.saturating_duration_since(Instant::now());
+        if tokio::time::timeout(left.max(GUEST_LOCK_GRACE), abandoned)
+            .await
+            .is_err()
+        {
+            log(1, "The guest is still busy, abandoning its requests anyway");
+            abandon_in_flight();
+        }
+        drain(ABANDON_TIMEOUT).await;
+        let open = connection::open_connections();
+        if open > 0 {
+            log(1, &format!("Forcibly closing {} connections", open));
+        }
+        flush_guest_output();
+        process::exit(code);
+    });
+}
+
+// Waits up to `timeout` for every response to complete
+async fn drain(timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    while nodehttp::active_requests() > 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+type HostFnCallback = dyn Fn(Caller<'_, ()>, &[Val], &mut [Val]) -> Result<()> + Send + Sync;
+
+// A host function the guest can import, linked alongside the built-in set
+#[derive(Clone)]
+struct HostFn {
+    module: String,
+    name: String,
+    params: Vec<ValType>,
+    results: Vec<ValType>,
+    func: Arc<HostFnCallback>,
+}
+
+/// An embeddable Mocket runtime.
+///
+/// The guest is a core module, or a component of the `mocket` world in `wit/mocket.wit`; which
+/// one is told by the file's format.
+///
+/// ```no_run
+/// use mocketd::{Runtime, Val, ValType};
+///
+/// let mut runtime = Runtime::new("main.wasm");
+/// runtime.register_host_fn("db", "query", [ValType::I32], [ValType::I32], |_, params, results| {
+///     if let [Val::I32(key)] = params {
+///         results[0] = Val::I32(key * 2);
+///     }
+///     Ok(())
+/// });
+/// runtime.start();
+/// ```
+pub struct Runtime {
+    wasm_path: String,
+    host_fns: Vec<HostFn>,
+    middleware: Vec<Arc<Middleware>>,
+}
+
+impl Runtime {
+    pub fn new(wasm_path: impl Into<String>) -> Self {
+        Runtime {
+            wasm_path: wasm_path.into(),
+            host_fns: Vec::new(),
+            middleware: Vec::new(),
+        }
+    }
+
+    /// Exposes `func` to the guest as the import `module`.`name` with the given signature.
+    ///
+    /// Registered functions are linked after the built-in `__h` and `spectest` imports, and
+    /// may not shadow them. Components only get the `host` interface.
+    pub fn register_host_fn<F>(
+        &mut self,
+        module: &str,
+        name: &str,
+        params: impl IntoIterator<Item = ValType>,
+        results: impl IntoIterator<Item = ValType>,
+        func: F,
+    ) -> &mut Self
+    where
+        F: Fn(Caller<'_, ()>, &[Val], &mut [Val]) -> Result<()> + Send + Sync + 'static,
+    {
+        self.host_fns.push(HostFn {
+            module: module.to_string(),
+            name: name.to_string(),
+            params: params.into_iter().collect(),
+            results: results.into_iter().collect(),
+            func: Arc::new(func),
+        });
+        self
+    }
+
+    /// Runs `middleware` on every request before the guest sees it, in the order they were
+    /// added, like Connect or Express middleware. Each gets the request, its response and the
+    /// rest of the chain: it may change the request and pass it on with [`Next::run`], add
+    /// [`Response::on_head`] hooks to change the response, or answer the request itself.
+    ///
+    /// ```no_run
+    /// use mocketd::Runtime;
+    ///
+    /// let mut runtime = Runtime::new("main.wasm");
+    /// runtime.use_middleware(|mut req, mut res, next| async move {
+    ///     if !req.headers.contains_key("authorization") {
+    ///         res.write_head(401, [("WWW-Authenticate", "Bearer")]).await?;
+    ///         res.end("").await;
+    ///         return Ok(());
+    ///     }
+    ///     req.headers.insert("x-authenticated".to_string(), "1".to_string());
+    ///     res.on_head(|_, headers| headers.push(("X-Frame-Options".into(), "DENY".into())));
+    ///     next.run(req, res).await
+    /// });
+    /// runtime.start();
+    /// ```
+    pub fn use_middleware<F, Fut>(&mut self, middleware: F) -> &mut Self
+    where
+        F: Fn(Request, Response, Next) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = std::result::Result<(), Box<dyn std::error::Error>>> + Send + 'static,
+    {
+        self.middleware
+            .push(Arc::new(move |request, response, next| {
+                Box::pin(middleware(request, response, next)) as MiddlewareFuture
+            }));
+        self
+    }
+
+    /// Instantiates the guest and runs its `_start` export, if any.
+    ///
+    /// With a port set through [`set_port`](crate::set_port), the server listens on it as
+    /// soon as the guest's `http.listen` asks, with that call's options, or once `_start`
+    /// returns if it never does; requests wait until `_start` returns.
+    pub fn start(&self) {
+        let loaded = self.load().unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        });
+        let (store, guest) = loaded.instantiate().unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        });
+        middleware::install(&self.middleware);
+
+        // Hold the lock while '_start' runs so early requests wait for the guest to be ready
+        let mut wasm = WASM.lock().unwrap();
+        let (store, guest) = wasm.insert((store, guest));
+
+        if let Some(timeout) = ready_timeout() {
+            set_ready(false);
+            tokio::spawn(async move {
+                tokio::time::sleep(timeout).await;
+                if !is_ready() {
+                    eprintln!(
+                        "Guest didn't send runtime.ready within {} seconds",
+                        timeout.as_secs_f64()
+                    );
+                    process::exit(1);
+                }
+            });
+        }
+
+        tokio::spawn(watch_disconnects());
+
+        if let Some(timeout) = idle_timeout() {
+            tokio::spawn(async move {
+                let mut check = tokio::time::interval(IDLE_CHECK_INTERVAL.min(timeout));
+                loop {
+                    check.tick().await;
+                    if nodehttp::idle_for().is_some_and(|idle| idle >= timeout) {
+                        log(
+                            1,
+                            &format!("Idle for {} seconds, exiting", timeout.as_secs_f64()),
+                        );
+                        flush_guest_output();
+                        process::exit(0);
+                    }
+                }
+            });
+        }
+
+        // Before there are listeners, so they wait for warmup
+        warmup::begin();
+
+        if let Err(err) = configure(store, guest) {
+            log(1, &format!("Failed to execute 'configure': {}", err));
+            process::exit(1);
+        }
+
+        // Optionally call '_start' if it exists
+        match guest.start(store) {
+            Some(Err(err)) => {
+                log(1, &format!("Failed to execute '_start': {}", err));
+                process::exit(1);
+            }
+            Some(Ok(())) => {}
+            None => log(
+                2,
+                &format!("No '_start' function found in {}", self.wasm_path),
+            ),
+        }
+        // Unless the guest asked for it with options of its own
+        if let Some(port) = port_override().filter(|port| !is_listening(*port)) {
+            listen(port, ListenOptions::default());
+        }
+        // Only once the shared instance has set up its routes
+        isolation::install(loaded);
+        tokio::spawn(warmup::run());
+    }
+
+    /// Replaces the running guest with a fresh instance of the module, e.g. after it was
+    /// rebuilt, and runs its `_start`.
+    ///
+    /// Requests the old guest is still answering get up to [`DRAIN_TIMEOUT`] to finish, then
+    /// are answered with 503. Listeners the new guest asks for again keep their sockets (and
+    /// options); those it doesn't are closed. The new guest only takes over once its `_start`
+    /// returns (and, with a [`set_ready_timeout`](crate::set_ready_timeout), it has sent
+    /// `runtime.ready` by then): if the module fails to load or start, the old guest keeps
+    /// running with its routes and listeners, and the error says why.
+    pub async fn reload(&self) -> std::result::Result<(), String> {
+        let loaded = self.load()?;
+        let (mut store, guest) = loaded.instantiate()?;
+
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        while in_flight() > 0 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        // Held while the new guest starts, so requests wait for whichever guest comes out
+        let mut wasm = WASM.lock().unwrap();
+        let routes = router::take();
+        let schemas = schema::take();
+        let listening = begin_relisten();
+        if let Some(port) = port_override() {
+            listen(port, ListenOptions::default());
+        }
+        take_sent_ready();
+        let started = configure(&mut store, &guest)
+            .map_err(|err| format!("Failed to execute 'configure': {:#}", err))
+            .and_then(|()| match guest.start(&mut store) {
+                Some(started) => {
+                    started.map_err(|err| format!("Failed to execute '_start': {:#}", err))
+                }
+                None => Ok(()),
+            })
+            .and_then(|()| match ready_timeout() {
+                Some(_) if !take_sent_ready() => {
+                    Err("The new guest didn't send runtime.ready during '_start'".to_string())
+                }
+                _ => Ok(()),
+            });
+        if let Err(err) = started {
+            router::restore(routes);
+            schema::restore(schemas);
+            abort_relisten(&listening);
+            return Err(err);
+        }
+
+        abandon_in_flight();
+        *wasm = Some((store, guest));
+        cache::clear();
+        end_relisten();
+        isolation::install(loaded);
+        Ok(())
+    }
+
+    // Reads and compiles the guest. Components are told apart from core modules by their
+    // format, see `component::is_component`.
+    fn load(&self) -> std::result::Result<Loaded, String> {
+        let wasm_bytes = fs::read(&self.wasm_path)
+            .map_err(|err| format!("Failed to read file {}: {}", self.wasm_path, err))?;
+        let is_component = component::is_component(&wasm_bytes);
+
+        let mut config = Config::new();
+        config.consume_fuel(profiling());
+        config.wasm_component_model(is_component);
+        {
+            let compiler = COMPILER.lock().unwrap();
+            config
+                .strategy(compiler.strategy)
+                .cranelift_opt_level(compiler.opt_level)
+                .parallel_compilation(compiler.parallel);
+        }
+        let engine =
+            Engine::new(&config).map_err(|err| format!("Failed to create engine: {}", err))?;
+
+        let code = if is_component {
+            if !self.host_fns.is_empty() {
+                log(
+                    1,
+                    "Host functions from register_host_fn are only linked into core modules",
+                );
+            }
+            Code::Component(compile::<Component>(&engine, &wasm_bytes)?)
+        } else {
+            Code::Module(compile::<Module>(&engine, &wasm_bytes)?)
+        };
+        Ok(Loaded {
+            engine,
+            code,
+            host_fns: self.host_fns.clone(),
+        })
+    }
+}
+
+enum Code {
+    Module(Module),
+    Component(Component),
+}
+
+// A compiled guest, to be instantiated once for the shared instance and again for every
+// pooled one (see `isolation`)
+pub(crate) struct Loaded {
+    engine: Engine,
+    code: Code,
+    host_fns: Vec<HostFn>,
+}
+
+impl Loaded {
+    // A fresh instance in a store of its own. Each gets its own linker, as the built-ins keep
+    // the event being sent between calls.
+    pub(crate) fn instantiate(&self) -> std::result::Result<(Store<()>, Guest), String> {
+        let engine = &self.engine;
+        let mut store = Store::new(engine, ());
+        if profiling() {
+            // Only metered to be measured, never to stop the guest
+            store.set_fuel(u64::MAX).unwrap();
+        }
+
+        let module = match &self.code {
+            Code::Module(module) => module,
+            Code::Component(component) => {
+                let linker = component::linker(engine)
+                    .map_err(|err| format!("Failed to link host interface: {}", err))?;
+                let instance = linker
+                    .instantiate(&mut store, component)
+                    .map_err(|err| format!("Failed to instantiate component: {}", err))?;
+                return Ok((store, Guest::Component(instance)));
+            }
+        };
+
+        let mut linker = Linker::new(engine);
+
+        define_builtins(engine, &mut linker);
+
+        for host_fn in &self.host_fns {
+            let ty = FuncType::new(engine, host_fn.params.clone(), host_fn.results.clone());
+            let func = Arc::clone(&host_fn.func);
+            linker
+                .func_new(
+                    &host_fn.module,
+                    &host_fn.name,
+                    ty,
+                    move |caller, params, results| func(caller, params, results),
+                )
+                .map_err(|err| {
+                    format!(
+                        "Failed to register host function {}.{}: {}",
+                        host_fn.module, host_fn.name, err
+                    )
+                })?;
+        }
+
+        let instance = linker
+            .instantiate(&mut store, module)
+            .map_err(|err| format!("Failed to instantiate module: {}", err))?;
+
+        Ok((store, Guest::Module(instance)))
+    }
+}
+
+// Handles one event the guest sent, `[type, data]` as JSON
+pub(crate) fn receive_message(message: &str) {
+    let clean_string = message.replace("\0", "");
+    log(1, &format!("Received JSON RAW: {}", clean_string));
+    if let Ok(json_value) = serde_json::from_str::<Value>(&clean_string) {
+        receive_value(json_value);
+    } else {
+        eprintln!("Failed to parse JSON.");
+        println!("{}", clean_string);
+    }
+}
+
+fn receive_value(json_value: Value) {
+    if isolation::starting() {
+        log(
+            2,
+            &format!(
+                "Ignoring event of a starting pooled instance: {}",
+                json_value
+            ),
+        );
+        return;
+    }
+    log(1, &format!("Received JSON Parse: {}", json_value));
+    if let Err(err) = handle_receive(json_value) {
+        eprintln!("Failed to handle event: {}", err);
+    }
+}
+
+// Longest line, in UTF-16 code units, `print_char` buffers before printing what it has
+const CONSOLE_LINE_CAP: usize = 64 * 1024;
+
+lazy_static! {
+    // The `print_char` buffers of every live instance, flushed before the process exits
+    static ref CONSOLES: Mutex<Vec<Weak<Mutex<Console>>>> = Mutex::new(Vec::new());
+}
+
+// A line an instance is printing with `print_char`, one UTF-16 code unit at a time. It's
+// printed at the newline, when it gets too long to hold, or when the instance goes away.
+#[derive(Default)]
+struct Console {
+    units: Vec<u16>,
+}
+
+impl Console {
+    fn open() -> Arc<Mutex<Console>> {
+        let console = Arc::new(Mutex::new(Console::default()));
+        let mut consoles = CONSOLES.lock().unwrap();
+        consoles.retain(|console| console.strong_count() > 0);
+        consoles.push(Arc::downgrade(&console));
+        console
+    }
+
+    fn write(&mut self, unit: u16) {
+        match unit {
+            0x0A => {
+                println!("{}", String::from_utf16_lossy(&self.units));
+                self.units.clear();
+            }
+            0x0D => {}
+            _ => {
+                self.units.push(unit);
+                if self.units.len() >= CONSOLE_LINE_CAP {
+                    self.write_partial();
+                }
+            }
+        }
+    }
+
+    // Prints what's buffered without ending the line, keeping the first half of a surrogate
+    // pair for its second
+    fn write_partial(&mut self) {
+        let end = match self.units.last() {
+            Some(0xD800..=0xDBFF) => self.units.len() - 1,
+            _ => self.units.len(),
+        };
+        let mut stdout = io::stdout().lock();
+        let _ = write!(stdout, "{}", String::from_utf16_lossy(&self.units[..end]));
+        let _ = stdout.flush();
+        self.units.drain(..end);
+    }
+
+    // Prints the unfinished line, if any
+    fn flush(&mut self) {
+        if !self.units.is_empty() {
+            println!("{}", String::from_utf16_lossy(&self.units));
+            self.units.clear();
+        }
+    }
+}
+
+impl Drop for Console {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Prints the lines guests started with `spectest::print_char` but didn't end with a newline.
+/// Call it before exiting the process, which would otherwise drop them.
+pub fn flush_guest_output() {
+    let consoles: Vec<_> = CONSOLES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect();
+    for console in consoles {
+        // A guest stuck mid-print holds its lock; its line is lost rather than the exit
+        if let Ok(mut console) = console.try_lock() {
+            console.flush();
+        }
+    }
+}
+
+// What a core module sent with `h_sd`, one UTF-16 code unit at a time, that hasn't been
+// handled yet. Each `h_se` handles the complete JSON values in it and keeps whatever comes
+// after them, in case a value was cut in two, for the next `h_se` to finish.
+#[derive(Default)]
+struct Outbox {
+    units: VecDeque<u16>,
+}
+
+impl Outbox {
+    fn flush(&mut self) {
+        let units = self.units.make_contiguous();
+        // The second half of a surrogate pair may still be on its way too
+        let end = match units.last() {
+            Some(0xD800..=0xDBFF) => units.len() - 1,
+            _ => units.len(),
+        };
+        let Ok(text) = String::from_utf16(&units[..end]) else {
+            eprintln!("Failed to decode event: invalid UTF-16");
+            self.units.clear();
+            return;
+        };
+        let text = text.replace("\0", "");
+        if !text.trim().is_empty() {
+            log(1, &format!("Received JSON RAW: {}", text));
+        }
+
+        let mut values = serde_json::Deserializer::from_str(&text).into_iter::<Value>();
+        let mut handled = 0;
+        let handled = loop {
+            match values.next() {
+                Some(Ok(value)) => {
+                    handled = values.byte_offset();
+                    receive_value(value);
+                }
+                None => break text.len(),
+                // Cut off; the rest of it comes with the next `h_se`. Each `h_se` parses what's
+                // held again, so a value that never ends can't be held for long.
+                Some(Err(err)) if err.is_eof() && text.len() - handled <= MAX_BODY_SIZE => {
+                    break handled
+                }
+                Some(Err(err)) if err.is_eof() => {
+                    eprintln!(
+                        "Failed to parse JSON: dropped an event still unfinished after {} bytes",
+                        text.len() - handled
+                    );
+                    break text.len();
+                }
+                Some(Err(_)) => {
+                    eprintln!("Failed to parse JSON.");
+                    println!("{}", text[handled..].trim());
+                    break text.len();
+                }
+            }
+        };
+
+        let held: Vec<u16> = self.units.drain(end..).collect();
+        self.units.clear();
+        self.units.extend(text[handled..].encode_utf16());
+        self.units.extend(held);
+    }
+}
+
+// The imports every Mocket guest relies on: the `__h` event channel and `spectest::print_char`
+fn define_builtins(engine: &Engine, linker: &mut Linker<()>) {
+    // Define function types
+    let buffer = Arc::new(Mutex::new(Outbox::default()));
+    let h_sd_ty = FuncType::new(engine, vec![ValType::I32], vec![]);
+    let h_se_ty = FuncType::new(engine, vec![], vec![]);
+    let print_char_ty = FuncType::new(engine, vec![ValType::I32], vec![]);
+
+    // Define h_sd function
+    let buffer_for_h_sd = Arc::clone(&buffer);
+    linker
+        .func_new("__h", "h_sd", h_sd_ty, move |_, params: &[Val], _| {
+            if let [Val::I32(ch)] = params {
+                buffer_for_h_sd.lock().unwrap().units.push_back(*ch as u16);
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    // Define h_se function
+    let buffer_for_h_se = Arc::clone(&buffer);
+    linker
+        .func_new("__h", "h_se", h_se_ty, move |_, _, _| {
+            buffer_for_h_se.lock().unwrap().flush();
+            Ok(())
+        })
+        .unwrap();
+
+    // Define `spectest::print_char` function
+    let console = Console::open();
+    linker
+        .func_new(
+            "spectest",
+            "print_char",
+            print_char_ty,
+            move |_, params: &[Val], _| {
+                if let [Val::I32(ch)] = params {
+                    console.lock().unwrap().write(*ch as u16);
+                }
+                Ok(())
+            },
+        )
+        .unwrap();
+}
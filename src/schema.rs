@@ -0,0 +1,182 @@
+use jsonschema::Validator;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::router;
+
+// Reported problems with a body stop at this many
+const MAX_ERRORS: usize = 20;
+
+// A schema the guest registered with `schema.register` for the bodies of a route
+struct Registered {
+    method: String,
+    pattern: String,
+    validator: Arc<Validator>,
+}
+
+static SCHEMAS: Mutex<Vec<Registered>> = Mutex::new(Vec::new());
+
+// Checks JSON bodies of `method` requests to `pattern` (`ALL` for any method) against
+// `schema`, in place of whatever was registered for them. Schemas are JSON Schema, in the
+// draft their `$schema` names (2020-12 without one); `$ref`s resolve within the schema only.
+pub(crate) fn register(method: &str, pattern: &str, schema: Value) -> Result<(), String> {
+    let validator = jsonschema::validator_for(&schema).map_err(|err| format!("schema: {}", err))?;
+    let method = method.to_uppercase();
+    let mut schemas = SCHEMAS.lock().unwrap();
+    schemas.retain(|registered| registered.method != method || registered.pattern != pattern);
+    schemas.push(Registered {
+        method,
+        pattern: pattern.to_string(),
+        validator: Arc::new(validator),
+    });
+    Ok(())
+}
+
+// The schemas of a guest being replaced, see `take`
+pub(crate) struct Saved(Vec<Registered>);
+
+// Forgets every schema for a reloaded guest to register its own, keeping the old ones in case
+// it fails to start
+pub(crate) fn take() -> Saved {
+    Saved(std::mem::take(&mut *SCHEMAS.lock().unwrap()))
+}
+
+// Puts back the schemas of the guest that keeps running
+pub(crate) fn restore(saved: Saved) {
+    *SCHEMAS.lock().unwrap() = saved.0;
+}
+
+// The schema registered for the body of a `method` request to `path`. `None` when there's
+// none, or the body isn't JSON by its `Content-Type`.
+pub(crate) fn find(
+    method: &str,
+    path: &str,
+    headers: &HashMap<String, String>,
+) -> Option<Arc<Validator>> {
+    let content_type = headers.get("content-type").map_or("", String::as_str);
+    let media_type = content_type.split(';').next().unwrap_or("").trim();
+    if !media_type.eq_ignore_ascii_case("application/json") && !media_type.ends_with("+json") {
+        return None;
+    }
+    let path = path.split('?').next().unwrap_or(path);
+    let schemas = SCHEMAS.lock().unwrap();
+    let registered = schemas.iter().find(|registered| {
+        (registered.method == "ALL" || registered.method == method)
+            && router::matches(&registered.pattern, path)
+    })?;
+    Some(Arc::clone(&registered.validator))
+}
+
+// What's wrong with `body` by `schema`, as `{ path, message }` objects naming where in it;
+// `None` when nothing is. Messages don't quote the body back. Checking a large body takes a
+// while, so this belongs off the async workers.
+pub(crate) fn check(schema: &Validator, body: &Result<Value, String>) -> Option<Vec<Value>> {
+    let errors: Vec<Value> = match body {
+        // An empty JSON body is passed on as an empty string
+        Ok(Value::String(text)) if text.is_empty() => {
+            vec![error("", "expected a JSON body")]
+        }
+        Ok(body) => schema
+            .iter_errors(body)
+            .take(MAX_ERRORS)
+            .map(|err| error(err.instance_path.as_str(), err.masked()))
+            .collect(),
+        Err(err) => vec![error("", format!("malformed JSON: {}", err))],
+    };
+    (!errors.is_empty()).then_some(errors)
+}
+
+fn error(at: &str, message: impl ToString) -> Value {
+    // A JSON Pointer, where the empty one is the whole body
+    json!({ "path": at, "message": message.to_string() })
+}
+
+pub(crate) fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Object(_) => "object",
+        Value::Array(_) => "array",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn errors(schema: Value, body: Value) -> Option<Vec<Value>> {
+        let validator = jsonschema::validator_for(&schema).unwrap();
+        check(&validator, &Ok(body))
+    }
+
+    #[test]
+    fn reports_where_the_body_is_wrong_without_quoting_it() {
+        let schema = json!({
+            "type": "object",
+            "properties": { "name": { "type": "string", "maxLength": 3 } },
+            "required": ["name"],
+        });
+        assert_eq!(errors(schema.clone(), json!({ "name": "abc" })), None);
+        let errors = errors(schema, json!({ "name": "secret" })).unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0]["path"], "/name");
+        assert!(!errors[0]["message"].as_str().unwrap().contains("secret"));
+    }
+
+    #[test]
+    fn empty_and_malformed_bodies_are_wrong() {
+        let validator = jsonschema::validator_for(&json!({})).unwrap();
+        assert!(check(&validator, &Ok(Value::String(String::new()))).is_some());
+        assert!(check(&validator, &Err("expected value".to_string())).is_some());
+    }
+
+    #[test]
+    fn unique_items_of_a_large_array_are_checked_quickly() {
+        let schema = json!({ "type": "array", "uniqueItems": true });
+        let mut items: Vec<Value> = (0..1_000_000).map(|i| json!(i)).collect();
+        let started = Instant::now();
+        assert_eq!(errors(schema.clone(), Value::Array(items.clone())), None);
+        items.push(json!(0));
+        assert!(errors(schema, Value::Array(items)).is_some());
+        assert!(started.elapsed() < Duration::from_secs(30));
+    }
+
+    #[test]
+    fn errors_stop_at_the_limit() {
+        let schema = json!({ "items": { "type": "string" } });
+        let body = Value::Array((0..100).map(|i| json!(i)).collect());
+        assert_eq!(errors(schema, body).unwrap().len(), MAX_ERRORS);
+    }
+
+    #[test]
+    fn invalid_schemas_are_refused() {
+        assert!(register("POST", "/schema-test", json!({ "type": 5 })).is_err());
+        assert!(register(
+            "POST",
+            "/schema-test",
+            json!({ "$ref": "http://example.com/" })
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn only_json_bodies_are_checked() {
+        register("post", "/schema-test/json", json!({ "type": "object" })).unwrap();
+        let headers = |content_type: &str| {
+            HashMap::from([("content-type".to_string(), content_type.to_string())])
+        };
+        assert!(find("POST", "/schema-test/json", &headers("application/json")).is_some());
+        assert!(find(
+            "POST",
+            "/schema-test/json?a=1",
+            &headers("application/merge-patch+json")
+        )
+        .is_some());
+        assert!(find("POST", "/schema-test/json", &headers("text/plain")).is_none());
+        assert!(find("PUT", "/schema-test/json", &headers("application/json")).is_none());
+    }
+}
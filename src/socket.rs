@@ -0,0 +1,136 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+
+use crate::isolation::{self, Assignment};
+use crate::nodehttp::Response;
+use crate::{multipart, send_request_event};
+
+// A hijacked connection: the queue of bytes to write, and the task reading from the client
+struct Socket {
+    writes: mpsc::UnboundedSender<Vec<u8>>,
+    reading: AbortHandle,
+    // The instance the request had to itself, see `set_isolation_pool`
+    _assignment: Option<Assignment>,
+}
+
+lazy_static! {
+    // Hijacked connections by request id
+    static ref SOCKETS: Mutex<HashMap<usize, Socket>> = Mutex::new(HashMap::new());
+}
+
+// Raw bytes for the guest: a string when they're UTF-8, base64 otherwise
+pub(crate) fn encode(data: Vec<u8>) -> Value {
+    match String::from_utf8(data) {
+        Ok(text) => json!({ "data": text }),
+        Err(err) => json!({
+            "data": STANDARD.encode(err.into_bytes()),
+            "encoding": "base64",
+        }),
+    }
+}
+
+// The inverse of `encode`; a bare string is taken as text
+pub(crate) fn decode(value: &Value) -> Option<Vec<u8>> {
+    match value {
+        Value::String(text) => Some(text.clone().into_bytes()),
+        Value::Object(fields) => {
+            let data = fields.get("data")?.as_str()?;
+            match fields.get("encoding").and_then(Value::as_str) {
+                Some("base64") => STANDARD.decode(data).ok(),
+                None => Some(data.as_bytes().to_vec()),
+                Some(_) => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// Stops treating the connection behind `response` as HTTP. Everything the client sends from
+// then on reaches the guest as `socket.data` events, and `socket.close` tells it the client
+// went away. Streams of an HTTP/2 connection can't be taken over; they're reset instead.
+pub(crate) fn hijack(id: usize, response: Response) {
+    // The instance answering the request goes on to handle its socket
+    let instance = isolation::assigned(id);
+    let Some((stream, read_ahead)) = response.into_raw() else {
+        eprintln!("Can't hijack request {} on an HTTP/2 connection", id);
+        return;
+    };
+    let assignment = instance.map(|instance| isolation::attach(id, instance));
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (writes, mut receiver) = mpsc::unbounded_channel::<Vec<u8>>();
+
+    // Held until the socket is registered, so the reader can't finish and look for it first
+    let mut sockets = SOCKETS.lock().unwrap();
+    let reading = tokio::spawn(async move {
+        let mut data = read_ahead;
+        let mut chunk = [0; 4096];
+        loop {
+            if !data.is_empty() {
+                let event = json!([id, encode(std::mem::take(&mut data))]);
+                // Wait for each event to be delivered so the guest sees the bytes in order
+                let _ = tokio::task::spawn_blocking(move || {
+                    send_request_event(id, "socket.data", event)
+                })
+                .await;
+            }
+            match reader.read(&mut chunk).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => data.extend_from_slice(&chunk[..n]),
+            }
+        }
+        // Unless the guest closed it first
+        let socket = SOCKETS.lock().unwrap().remove(&id);
+        if socket.is_some() {
+            multipart::cleanup(id);
+            let _ = tokio::task::spawn_blocking(move || {
+                send_request_event(id, "socket.close", json!(id))
+            })
+            .await;
+        }
+    });
+
+    let socket = Socket {
+        writes,
+        reading: reading.abort_handle(),
+        _assignment: assignment,
+    };
+    sockets.insert(id, socket);
+    drop(sockets);
+
+    tokio::spawn(async move {
+        while let Some(data) = receiver.recv().await {
+            if writer.write_all(&data).await.is_err() || writer.flush().await.is_err() {
+                break;
+            }
+        }
+        let _ = writer.shutdown().await;
+    });
+}
+
+// Queues `data` (see `decode`) to be written, returning whether the socket is open
+pub(crate) fn write(id: usize, data: &Value) -> Result<bool, String> {
+    let data = decode(data).ok_or("invalid socket data")?;
+    match SOCKETS.lock().unwrap().get(&id) {
+        Some(socket) => Ok(socket.writes.send(data).is_ok()),
+        None => Ok(false),
+    }
+}
+
+// Closes the socket once queued writes are done, returning whether it was open
+pub(crate) fn close(id: usize) -> bool {
+    match SOCKETS.lock().unwrap().remove(&id) {
+        Some(socket) => {
+            // Dropping `writes` ends the writer after what's queued
+            socket.reading.abort();
+            multipart::cleanup(id);
+            true
+        }
+        None => false,
+    }
+}
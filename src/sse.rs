@@ -0,0 +1,124 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::nodehttp::Response;
+use crate::{log, multipart, notify_aborted};
+
+// Comment lines sent this often keep proxies from closing idle streams
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+enum Message {
+    Event(String),
+    Close,
+}
+
+lazy_static! {
+    // Open event streams by request id, each feeding the task that owns the response
+    static ref STREAMS: Mutex<HashMap<usize, mpsc::UnboundedSender<Message>>> =
+        Mutex::new(HashMap::new());
+}
+
+// Turns `response` into an event stream; `sse.send` and `sse.close` then go through `id`
+pub(crate) fn start(id: usize, mut response: Response, headers: Vec<(String, String)>) {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    STREAMS.lock().unwrap().insert(id, sender);
+
+    tokio::spawn(async move {
+        let mut headers = headers;
+        for (key, value) in [
+            ("Content-Type", "text/event-stream"),
+            ("Cache-Control", "no-cache"),
+        ] {
+            if !headers.iter().any(|(k, _)| k.eq_ignore_ascii_case(key)) {
+                headers.push((key.to_string(), value.to_string()));
+            }
+        }
+
+        let mut sent = 0;
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+        heartbeat.tick().await;
+        let result: io::Result<()> = async {
+            response.write_head(200, headers).await?;
+            response.write("").await?;
+            loop {
+                let chunk = tokio::select! {
+                    message = receiver.recv() => match message {
+                        Some(Message::Event(event)) => event,
+                        Some(Message::Close) | None => return Ok(()),
+                    },
+                    _ = heartbeat.tick() => ": heartbeat\n\n".to_string(),
+                    () = response.closed() => return Err(io::ErrorKind::ConnectionReset.into()),
+                };
+                response.write(&chunk).await?;
+                sent += chunk.len();
+            }
+        }
+        .await;
+
+        STREAMS.lock().unwrap().remove(&id);
+        multipart::cleanup(id);
+        match result {
+            Ok(()) => response.finish(sent).await,
+            Err(err) => {
+                log(2, &format!("Event stream {} closed: {}", id, err));
+                notify_aborted(id).await;
+            }
+        }
+    });
+}
+
+// Queues an event for the stream, returning whether it's open. `event` is either the data
+// itself or `{ data, event, id, retry }`; data that isn't a string is sent as JSON.
+pub(crate) fn send(id: usize, event: &Value) -> bool {
+    let (data, fields) = match event {
+        Value::Object(fields) => (fields.get("data").unwrap_or(&Value::Null), Some(fields)),
+        data => (data, None),
+    };
+
+    let mut message = String::new();
+    if let Some(fields) = fields {
+        for name in ["event", "id", "retry"] {
+            match fields.get(name) {
+                // A line break would end the field and start whatever follows it
+                Some(Value::String(value)) if value.contains(['\r', '\n']) => {
+                    log(
+                        1,
+                        &format!("Dropped the {} of an event: it has a line break", name),
+                    );
+                }
+                Some(Value::String(value)) => writeln!(message, "{}: {}", name, value).unwrap(),
+                Some(Value::Number(value)) => writeln!(message, "{}: {}", name, value).unwrap(),
+                _ => {}
+            }
+        }
+    }
+    let data = match data {
+        Value::String(data) => data.clone(),
+        Value::Null => String::new(),
+        data => data.to_string(),
+    };
+    // Every line of the data needs its own `data:` field. Clients end lines at CRLF, LF and
+    // a lone CR alike.
+    for line in data.replace("\r\n", "\n").split(['\n', '\r']) {
+        writeln!(message, "data: {}", line).unwrap();
+    }
+    message.push('\n');
+
+    match STREAMS.lock().unwrap().get(&id) {
+        Some(sender) => sender.send(Message::Event(message)).is_ok(),
+        None => false,
+    }
+}
+
+// Ends the stream after any queued events, returning whether it was open
+pub(crate) fn close(id: usize) -> bool {
+    match STREAMS.lock().unwrap().remove(&id) {
+        Some(sender) => sender.send(Message::Close).is_ok(),
+        None => false,
+    }
+}
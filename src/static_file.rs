@@ -0,0 +1,173 @@
+use chrono::{DateTime, Utc};
+use std::fs::Metadata;
+use std::io::{self, SeekFrom};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::nodehttp::{reason_phrase, Response};
+use crate::{log, NO_HEADERS};
+
+// Files are sent in pieces of this size rather than read into memory whole
+const CHUNK_SIZE: usize = 64 * 1024;
+
+static STRONG_ETAGS: AtomicBool = AtomicBool::new(false);
+
+/// Derives file ETags from a checksum of the contents instead of the size and modification
+/// time. This reads every file in full once more per request, but catches changes that keep
+/// both.
+pub fn set_strong_etags(strong: bool) {
+    STRONG_ETAGS.store(strong, Ordering::Relaxed);
+}
+
+// A weak ETag from the file's metadata, or a strong one from its contents
+async fn etag(file: &mut File, metadata: &Metadata) -> io::Result<String> {
+    if STRONG_ETAGS.load(Ordering::Relaxed) {
+        let mut hasher = crc32fast::Hasher::new();
+        let mut chunk = vec![0; CHUNK_SIZE];
+        loop {
+            let n = file.read(&mut chunk).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&chunk[..n]);
+        }
+        file.rewind().await?;
+        Ok(format!(
+            "\"{:x}-{:08x}\"",
+            metadata.len(),
+            hasher.finalize()
+        ))
+    } else {
+        let modified = metadata.modified().map_or(0, |modified| {
+            DateTime::<Utc>::from(modified)
+                .timestamp_nanos_opt()
+                .unwrap_or(0)
+        });
+        Ok(format!("W/\"{:x}-{:x}\"", metadata.len(), modified))
+    }
+}
+
+// Whether the client's cached copy is still current, so a `304` will do. `If-None-Match`
+// takes precedence; it compares weakly, as a GET should.
+fn is_not_modified(response: &Response, etag: &str, modified: Option<DateTime<Utc>>) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if let Some(if_none_match) = response.request_header("if-none-match") {
+        return if_none_match
+            .split(',')
+            .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag));
+    }
+    match (response.request_header("if-modified-since"), modified) {
+        (Some(since), Some(modified)) => DateTime::parse_from_rfc2822(since)
+            .is_ok_and(|since| modified.timestamp() <= since.timestamp()),
+        _ => false,
+    }
+}
+
+// The byte range `start..end` requested by a `Range` header, for a file of `len` bytes.
+// `Ok(None)` means the whole file: no header, or one we can ignore (malformed, another unit,
+// several ranges). `Err` means the range lies outside the file.
+fn requested_range(range: Option<&str>, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = range.and_then(|range| range.trim().strip_prefix("bytes=")) else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return Ok(None);
+    };
+    let (start, end) = (start.trim(), end.trim());
+    let parse = |n: &str| n.parse::<u64>().ok();
+    match (parse(start), parse(end)) {
+        // `bytes=-N`: the last N bytes
+        (None, Some(suffix)) if start.is_empty() && suffix > 0 => {
+            Ok(Some((len.saturating_sub(suffix), len)))
+        }
+        (None, Some(_)) if start.is_empty() => Err(()),
+        // `bytes=N-`
+        (Some(start), None) if end.is_empty() && start < len => Ok(Some((start, len))),
+        // `bytes=N-M`, inclusive
+        (Some(start), Some(end)) if start <= end && start < len => {
+            Ok(Some((start, (end + 1).min(len))))
+        }
+        (Some(_), None) if end.is_empty() => Err(()),
+        (Some(start), Some(end)) if start <= end => Err(()),
+        _ => Ok(None),
+    }
+}
+
+// Answers with the contents of `path`, as the guest asks with a `{ _T: "file", path }` body.
+// A `Range` request on a 200 response gets `206` and just that slice.
+pub(crate) async fn send(
+    mut response: Response,
+    status_code: u16,
+    mut headers: Vec<(String, String)>,
+    path: &str,
+) -> io::Result<()> {
+    let mut file = match File::open(path).await {
+        Ok(file) => file,
+        Err(err) => return send_error(response, path, err).await,
+    };
+    let metadata = file.metadata().await?;
+    if !metadata.is_file() {
+        let err = io::Error::new(io::ErrorKind::NotFound, "not a file");
+        return send_error(response, path, err).await;
+    }
+    let len = metadata.len();
+
+    let mut status_code = status_code;
+    let (start, end) = if status_code == 200 {
+        let etag = etag(&mut file, &metadata).await?;
+        let modified = metadata.modified().ok().map(DateTime::<Utc>::from);
+        let not_modified = is_not_modified(&response, &etag, modified);
+        headers.push(("ETag".to_string(), etag));
+        if let Some(modified) = modified {
+            let last_modified = modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+            headers.push(("Last-Modified".to_string(), last_modified));
+        }
+        if not_modified {
+            response.write_head(304, headers).await?;
+            response.end("").await;
+            return Ok(());
+        }
+
+        headers.push(("Accept-Ranges".to_string(), "bytes".to_string()));
+        match requested_range(response.request_header("range"), len) {
+            Ok(Some((start, end))) => {
+                status_code = 206;
+                let content_range = format!("bytes {}-{}/{}", start, end - 1, len);
+                headers.push(("Content-Range".to_string(), content_range));
+                (start, end)
+            }
+            Ok(None) => (0, len),
+            Err(()) => {
+                headers.push(("Content-Range".to_string(), format!("bytes */{}", len)));
+                response.write_head(416, headers).await?;
+                response.end("").await;
+                return Ok(());
+            }
+        }
+    } else {
+        (0, len)
+    };
+
+    // The file goes out as is, without a copy of it in memory
+    file.seek(SeekFrom::Start(start)).await?;
+    response
+        .send_sized(status_code, headers, file, end - start)
+        .await
+}
+
+async fn send_error(response: Response, path: &str, err: io::Error) -> io::Result<()> {
+    log(1, &format!("Failed to serve {}: {}", path, err));
+    let status_code = match err.kind() {
+        io::ErrorKind::NotFound => 404,
+        io::ErrorKind::PermissionDenied => 403,
+        _ => 500,
+    };
+    let reason = reason_phrase(status_code);
+    response
+        .send_error(status_code, NO_HEADERS, &format!("{}\n", reason))
+        .await
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+
+use crate::nodehttp::Response;
+use crate::{log, multipart, notify_aborted, response_body_room};
+
+enum Message {
+    Write(Vec<u8>),
+    // Like Node's `cork`/`uncork`: writes are held back until every cork is undone
+    Cork,
+    Uncork,
+    // Sends what's held back, corked or not
+    Flush,
+    End(Vec<u8>, Vec<(String, String)>),
+    // The body went over `set_max_response_body`; the response is cut short
+    Fail,
+}
+
+// A response started with `http.writeHead`
+struct Stream {
+    sender: mpsc::UnboundedSender<Message>,
+    // Body bytes queued so far, counted against `set_max_response_body`
+    written: usize,
+    // Whether the body was cut off at the limit, dropping whatever comes after
+    truncated: bool,
+}
+
+lazy_static! {
+    // Responses started with `http.writeHead`, each feeding the task that owns the response
+    static ref STREAMS: Mutex<HashMap<usize, Stream>> =
+        Mutex::new(HashMap::new());
+}
+
+// Sends the head of `response` right away; the body follows through `write` and `end`, each
+// write going out (and being flushed) as its own chunk unless the stream is corked
+pub(crate) fn start(
+    id: usize,
+    mut response: Response,
+    status_code: u16,
+    mut headers: Vec<(String, String)>,
+) {
+    // The body goes out chunked over a connection we manage, so the guest doesn't get to
+    // frame it. A HEAD response may still give the length of the body it goes without.
+    let head = response.is_head();
+    let mut dropped_length = false;
+    headers.retain(|(key, _)| {
+        if key.eq_ignore_ascii_case("Content-Length") {
+            dropped_length |= !head;
+            head
+        } else {
+            !["Transfer-Encoding", "Connection", "Keep-Alive"]
+                .iter()
+                .any(|name| key.eq_ignore_ascii_case(name))
+        }
+    });
+    if dropped_length {
+        response.log(1, "Ignored the Content-Length of a streamed response");
+    }
+
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let stream = Stream {
+        sender,
+        written: 0,
+        truncated: false,
+    };
+    STREAMS.lock().unwrap().insert(id, stream);
+
+    tokio::spawn(async move {
+        let mut sent = 0;
+        let mut corks: usize = 0;
+        let mut held = Vec::new();
+        let result: io::Result<Vec<(String, String)>> = async {
+            response.write_head(status_code, headers).await?;
+            response.write("").await?;
+            loop {
+                let message = tokio::select! {
+                    message = receiver.recv() => message,
+                    () = response.closed() => return Err(io::ErrorKind::ConnectionReset.into()),
+                };
+                let send = match message {
+                    Some(Message::Write(data)) => {
+                        held.extend_from_slice(&data);
+                        corks == 0
+                    }
+                    Some(Message::Cork) => {
+                        corks += 1;
+                        false
+                    }
+                    Some(Message::Uncork) => {
+                        corks = corks.saturating_sub(1);
+                        corks == 0
+                    }
+                    Some(Message::Flush) => true,
+                    Some(Message::End(body, trailers)) => {
+                        held.extend_from_slice(&body);
+                        response.write(&held).await?;
+                        sent += held.len();
+                        return Ok(trailers);
+                    }
+                    Some(Message::Fail) => {
+                        return Err(io::Error::other("body over the size limit"));
+                    }
+                    None => return Ok(Vec::new()),
+                };
+                if send {
+                    response.write(&held).await?;
+                    sent += held.len();
+                    held.clear();
+                }
+            }
+        }
+        .await;
+
+        STREAMS.lock().unwrap().remove(&id);
+        multipart::cleanup(id);
+        match result {
+            Ok(trailers) => response.finish_with_trailers(sent, trailers).await,
+            Err(err) => {
+                log(2, &format!("Response stream {} closed: {}", id, err));
+                notify_aborted(id).await;
+            }
+        }
+    });
+}
+
+fn send(id: usize, message: Message) -> bool {
+    match STREAMS.lock().unwrap().get(&id) {
+        Some(stream) => stream.sender.send(message).is_ok(),
+        None => false,
+    }
+}
+
+// Counts `data` against the limit on the body of stream `id`, cutting it down to what's left
+// of it, or failing the stream when it's over; `None` when there's no stream
+fn limit(streams: &mut HashMap<usize, Stream>, id: usize, data: &mut Vec<u8>) -> Option<()> {
+    let stream = streams.get_mut(&id)?;
+    if stream.truncated {
+        data.clear();
+        return Some(());
+    }
+    match response_body_room(id, stream.written, data.len()) {
+        Some(room) => {
+            if room < data.len() {
+                data.truncate(room);
+                stream.truncated = true;
+            }
+            stream.written += data.len();
+        }
+        None => {
+            // Removed so nothing more is queued behind the failure
+            let stream = streams.remove(&id)?;
+            let _ = stream.sender.send(Message::Fail);
+            data.clear();
+        }
+    }
+    Some(())
+}
+
+// Whether `id` is a response started with `start` that hasn't ended
+pub(crate) fn is_open(id: usize) -> bool {
+    STREAMS.lock().unwrap().contains_key(&id)
+}
+
+// Each of these returns whether the stream is open
+
+pub(crate) fn write(id: usize, mut data: Vec<u8>) -> bool {
+    let mut streams = STREAMS.lock().unwrap();
+    if limit(&mut streams, id, &mut data).is_none() {
+        return false;
+    }
+    match streams.get(&id) {
+        Some(stream) if !data.is_empty() => stream.sender.send(Message::Write(data)).is_ok(),
+        // Dropped past the limit, or the stream failed over it
+        _ => true,
+    }
+}
+
+pub(crate) fn cork(id: usize) -> bool {
+    send(id, Message::Cork)
+}
+
+pub(crate) fn uncork(id: usize) -> bool {
+    send(id, Message::Uncork)
+}
+
+pub(crate) fn flush(id: usize) -> bool {
+    send(id, Message::Flush)
+}
+
+// Sends `body` as the last of the data, then `trailers`
+pub(crate) fn end(id: usize, mut body: Vec<u8>, trailers: Vec<(String, String)>) -> bool {
+    let mut streams = STREAMS.lock().unwrap();
+    if limit(&mut streams, id, &mut body).is_none() {
+        return false;
+    }
+    // Removed here so nothing can be queued after the end
+    match streams.remove(&id) {
+        Some(stream) => stream.sender.send(Message::End(body, trailers)).is_ok(),
+        // The stream failed over the limit
+        None => true,
+    }
+}
@@ -0,0 +1,64 @@
+use serde_json::Value;
+
+use crate::log;
+
+// Fills in a template for `http.endTemplate`. `{{ name }}` is replaced with the value of `name`
+// HTML-escaped, `{{{ name }}}` with the value as is. Names may be dotted paths into nested
+// objects (`{{ user.name }}`); missing values render as nothing. A tag that isn't closed is
+// left as text.
+pub(crate) fn render(template: &str, values: &Value) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let (raw, open, close) = if rest.starts_with("{{{") {
+            (true, "{{{", "}}}")
+        } else {
+            (false, "{{", "}}")
+        };
+        let Some(end) = rest[open.len()..].find(close) else {
+            break;
+        };
+        let name = rest[open.len()..open.len() + end].trim();
+        let value = lookup(values, name);
+        if raw {
+            out.push_str(&value);
+        } else {
+            escape_html(&value, &mut out);
+        }
+        rest = &rest[open.len() + end + close.len()..];
+    }
+    out.push_str(rest);
+    out
+}
+
+// The text of the value at a dotted path; objects and arrays as JSON
+fn lookup(values: &Value, name: &str) -> String {
+    let value = name.split('.').try_fold(values, |value, key| match value {
+        Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+        value => value.get(key),
+    });
+    match value {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Null) => String::new(),
+        Some(value) => value.to_string(),
+        None => {
+            log(1, &format!("Template value {:?} is missing", name));
+            String::new()
+        }
+    }
+}
+
+pub(crate) fn escape_html(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
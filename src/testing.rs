@@ -0,0 +1,248 @@
+//! An in-process client for testing guest modules, enabled by the `testing` feature.
+//!
+//! ```no_run
+//! let server = mocketd::testing::start("main.wasm");
+//! server.get("/").assert_status(200);
+//! let echoed = server.post("/echo", r#"{"a":1}"#).assert_status(200).json().unwrap();
+//! ```
+//!
+//! The runtime is process-wide, so every test in a binary shares one server (and one guest
+//! instance). It runs on its own thread with its own async runtime, and the client blocks, so
+//! it works from both plain and async tests.
+
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{mpsc, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use crate::{set_listener, Runtime};
+
+// How long a request may take before the client gives up
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+static SERVER: OnceLock<TestServer> = OnceLock::new();
+
+/// A running guest, listening on an ephemeral port on localhost.
+pub struct TestServer {
+    wasm_path: String,
+    addr: SocketAddr,
+}
+
+/// Starts `wasm_path` the first time it's called, and returns the running server after that.
+///
+/// Panics if the module can't be served, or if a different module is already running.
+pub fn start(wasm_path: &str) -> &'static TestServer {
+    let server = SERVER.get_or_init(|| {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind a test port");
+        let addr = listener.local_addr().unwrap();
+        set_listener(listener).unwrap();
+
+        let path = wasm_path.to_string();
+        let (started, wait) = mpsc::channel();
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().expect("failed to start the runtime");
+            runtime.block_on(async {
+                Runtime::new(path).start();
+                let _ = started.send(());
+                std::future::pending::<()>().await
+            })
+        });
+        wait.recv().expect("the test server failed to start");
+
+        TestServer {
+            wasm_path: wasm_path.to_string(),
+            addr,
+        }
+    });
+    assert_eq!(
+        server.wasm_path, wasm_path,
+        "the test server already runs {}",
+        server.wasm_path
+    );
+    server
+}
+
+impl TestServer {
+    /// The address the server listens on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn get(&self, path: &str) -> TestResponse {
+        self.request("GET", path, &[], b"")
+    }
+
+    pub fn post(&self, path: &str, body: impl AsRef<[u8]>) -> TestResponse {
+        self.request("POST", path, &[], body.as_ref())
+    }
+
+    pub fn put(&self, path: &str, body: impl AsRef<[u8]>) -> TestResponse {
+        self.request("PUT", path, &[], body.as_ref())
+    }
+
+    pub fn patch(&self, path: &str, body: impl AsRef<[u8]>) -> TestResponse {
+        self.request("PATCH", path, &[], body.as_ref())
+    }
+
+    pub fn delete(&self, path: &str) -> TestResponse {
+        self.request("DELETE", path, &[], b"")
+    }
+
+    /// Sends a request over a new connection and reads the whole response.
+    ///
+    /// Panics if the server can't be reached or the response can't be parsed.
+    pub fn request(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> TestResponse {
+        self.try_request(method, path, headers, body)
+            .unwrap_or_else(|err| panic!("{} {} failed: {}", method, path, err))
+    }
+
+    fn try_request(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<TestResponse> {
+        let mut stream = TcpStream::connect(self.addr)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+
+        let mut request = format!(
+            "{method} {path} HTTP/1.1\r\n\
+            Host: {}\r\n\
+            Connection: close\r\n\
+            Content-Length: {}\r\n",
+            self.addr,
+            body.len()
+        );
+        for (key, value) in headers {
+            request.push_str(&format!("{key}: {value}\r\n"));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+        stream.write_all(body)?;
+
+        let mut raw = Vec::new();
+        stream.read_to_end(&mut raw)?;
+        TestResponse::parse(&raw)
+    }
+}
+
+/// A complete response. Header and trailer names are lowercased.
+#[derive(Debug)]
+pub struct TestResponse {
+    pub status: u16,
+    pub headers: HashMap<String, String>,
+    pub trailers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    fn parse(raw: &[u8]) -> io::Result<Self> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        let header_end = raw
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or_else(|| invalid("incomplete response head"))?;
+        let head = String::from_utf8_lossy(&raw[..header_end]);
+        let mut lines = head.split("\r\n");
+        let status = lines
+            .next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| invalid("malformed status line"))?;
+        let headers = parse_fields(lines);
+
+        let rest = &raw[header_end + 4..];
+        let chunked = headers
+            .get("transfer-encoding")
+            .is_some_and(|value| value.eq_ignore_ascii_case("chunked"));
+        let (body, trailers) = if chunked {
+            decode_chunked(rest).ok_or_else(|| invalid("malformed chunked body"))?
+        } else {
+            (rest.to_vec(), HashMap::new())
+        };
+
+        Ok(TestResponse {
+            status,
+            headers,
+            trailers,
+            body,
+        })
+    }
+
+    /// A header by case-insensitive name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+
+    /// The body as text, with invalid UTF-8 replaced.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    pub fn json(&self) -> serde_json::Result<Value> {
+        serde_json::from_slice(&self.body)
+    }
+
+    /// Panics, showing the body, unless the status is `expected`.
+    #[track_caller]
+    pub fn assert_status(&self, expected: u16) -> &Self {
+        assert_eq!(
+            self.status,
+            expected,
+            "unexpected status; body: {}",
+            self.text()
+        );
+        self
+    }
+
+    /// Panics unless the header `name` is `expected`.
+    #[track_caller]
+    pub fn assert_header(&self, name: &str, expected: &str) -> &Self {
+        assert_eq!(self.header(name), Some(expected), "header {}", name);
+        self
+    }
+
+    /// Panics unless the body is the text `expected`.
+    #[track_caller]
+    pub fn assert_body(&self, expected: &str) -> &Self {
+        assert_eq!(self.text(), expected);
+        self
+    }
+}
+
+fn parse_fields<'a>(lines: impl Iterator<Item = &'a str>) -> HashMap<String, String> {
+    lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect()
+}
+
+// The data and trailer fields of a chunked body
+fn decode_chunked(mut rest: &[u8]) -> Option<(Vec<u8>, HashMap<String, String>)> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = rest.windows(2).position(|w| w == b"\r\n")?;
+        let size = std::str::from_utf8(&rest[..line_end]).ok()?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        rest = &rest[line_end + 2..];
+        if size == 0 {
+            let trailers = String::from_utf8_lossy(rest);
+            return Some((body, parse_fields(trailers.split("\r\n"))));
+        }
+        body.extend_from_slice(rest.get(..size)?);
+        rest = rest.get(size + 2..)?;
+    }
+}
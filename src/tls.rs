@@ -0,0 +1,197 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::{Arc, Mutex};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::http2;
+
+lazy_static! {
+    static ref TLS_CONFIG: Mutex<Option<Arc<ServerConfig>>> = Mutex::new(None);
+    // Certificates by the lowercase hostname they're served for, see `set_tls_hosts`
+    static ref HOSTS: Mutex<HashMap<String, Arc<CertifiedKey>>> = Mutex::new(HashMap::new());
+}
+
+// Picks the certificate for the name the client asked for in its ClientHello (SNI)
+#[derive(Debug)]
+struct Certificates {
+    default: Arc<CertifiedKey>,
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let host = client_hello.server_name().and_then(host_certificate);
+        Some(host.unwrap_or_else(|| Arc::clone(&self.default)))
+    }
+}
+
+// The certificate set for `server_name`, or for a wildcard (`*.example.com`) covering it
+fn host_certificate(server_name: &str) -> Option<Arc<CertifiedKey>> {
+    let name = server_name.trim_end_matches('.').to_ascii_lowercase();
+    let hosts = HOSTS.lock().unwrap();
+    if let Some(certificate) = hosts.get(&name) {
+        return Some(Arc::clone(certificate));
+    }
+    let (_, parent) = name.split_once('.')?;
+    hosts.get(&format!("*.{}", parent)).cloned()
+}
+
+// The identity of a client that presented a verified certificate (mutual TLS)
+#[derive(Clone)]
+pub struct ClientIdentity {
+    pub common_name: Option<String>,
+    pub subject_alt_names: Vec<String>,
+}
+
+impl ClientIdentity {
+    fn from_der(cert: &CertificateDer) -> Option<Self> {
+        let (_, cert) = X509Certificate::from_der(cert.as_ref()).ok()?;
+        let common_name = cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(str::to_string);
+        let subject_alt_names = cert
+            .subject_alternative_name()
+            .ok()
+            .flatten()
+            .map(|san| {
+                san.value
+                    .general_names
+                    .iter()
+                    .filter_map(|name| match name {
+                        GeneralName::DNSName(name) | GeneralName::RFC822Name(name) => {
+                            Some(name.to_string())
+                        }
+                        GeneralName::URI(uri) => Some(uri.to_string()),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Some(ClientIdentity {
+            common_name,
+            subject_alt_names,
+        })
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "commonName": self.common_name,
+            "subjectAltNames": self.subject_alt_names,
+        })
+    }
+}
+
+/// Serves HTTPS using the PEM certificate chain and private key.
+///
+/// With `client_ca_path`, clients must present a certificate signed by one of the CAs in that
+/// PEM bundle (mutual TLS); the handshake fails for anyone else.
+///
+/// This certificate is the default; see [`set_tls_hosts`] for serving others by hostname.
+pub fn set_tls(cert_path: &str, key_path: &str, client_ca_path: Option<&str>) -> io::Result<()> {
+    let default = load_certified_key(cert_path, key_path)?;
+
+    let builder = match client_ca_path {
+        Some(client_ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca_path)? {
+                roots.add(cert).map_err(invalid)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(invalid)?;
+            ServerConfig::builder().with_client_cert_verifier(verifier)
+        }
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+    let config = builder.with_cert_resolver(Arc::new(Certificates { default }));
+
+    *TLS_CONFIG.lock().unwrap() = Some(Arc::new(config));
+    Ok(())
+}
+
+/// Serves the PEM certificate chain and private key of each `(hostname, cert_path, key_path)`
+/// to clients that ask for that hostname with SNI, instead of the one from [`set_tls`].
+/// A hostname of `*.example.com` covers every name one level below `example.com`; names that
+/// match no hostname get the default certificate. Requests over a connection whose name
+/// matched carry it as `serverName`.
+pub fn set_tls_hosts(hosts: &[(&str, &str, &str)]) -> io::Result<()> {
+    let hosts = hosts
+        .iter()
+        .map(|(hostname, cert_path, key_path)| {
+            let certificate = load_certified_key(cert_path, key_path)?;
+            Ok((
+                hostname.trim_end_matches('.').to_ascii_lowercase(),
+                certificate,
+            ))
+        })
+        .collect::<io::Result<_>>()?;
+    *HOSTS.lock().unwrap() = hosts;
+    Ok(())
+}
+
+pub(crate) fn enabled() -> bool {
+    TLS_CONFIG.lock().unwrap().is_some()
+}
+
+pub(crate) fn acceptor() -> Option<TlsAcceptor> {
+    let config = TLS_CONFIG.lock().unwrap().clone()?;
+    if !http2::enabled() {
+        return Some(TlsAcceptor::from(config));
+    }
+    // Clients that don't offer `h2` get HTTP/1.1
+    let mut config = (*config).clone();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Some(TlsAcceptor::from(Arc::new(config)))
+}
+
+// The verified client identity of an established TLS connection, if the client sent a
+// certificate
+pub(crate) fn client_identity<S>(
+    stream: &tokio_rustls::server::TlsStream<S>,
+) -> Option<ClientIdentity> {
+    let (_, connection) = stream.get_ref();
+    connection
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(ClientIdentity::from_der)
+}
+
+// The name the client asked for with SNI, if a certificate was set for it
+pub(crate) fn server_name<S>(stream: &tokio_rustls::server::TlsStream<S>) -> Option<String> {
+    let (_, connection) = stream.get_ref();
+    let server_name = connection.server_name()?;
+    host_certificate(server_name)?;
+    Some(server_name.trim_end_matches('.').to_ascii_lowercase())
+}
+
+fn load_certified_key(cert_path: &str, key_path: &str) -> io::Result<Arc<CertifiedKey>> {
+    let certs = load_certs(cert_path)?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+        .ok_or_else(|| invalid(format!("no private key found in {}", key_path)))?;
+    let certified_key =
+        CertifiedKey::from_der(certs, key, &ring::default_provider()).map_err(invalid)?;
+    Ok(Arc::new(certified_key))
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificates found in {}", path)));
+    }
+    Ok(certs)
+}
+
+fn invalid(err: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err.to_string())
+}
@@ -0,0 +1,102 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// Logs request and response bodies, for debugging guest handlers
+struct Trace {
+    // Bodies are cut off after this many bytes
+    max_len: usize,
+    // Lowercase header and JSON field names whose values are hidden
+    redact: Vec<String>,
+}
+
+static TRACE: Mutex<Option<Trace>> = Mutex::new(None);
+
+/// Logs the headers and body of every request and the body of every response, cut off after
+/// `max_len` bytes. Values of headers and JSON fields named in `redact` (case-insensitive) are
+/// replaced with `[redacted]`.
+pub fn set_trace_bodies(max_len: usize, redact: &[&str]) {
+    *TRACE.lock().unwrap() = Some(Trace {
+        max_len,
+        redact: redact
+            .iter()
+            .map(|name| name.to_ascii_lowercase())
+            .collect(),
+    });
+}
+
+impl Trace {
+    fn is_redacted(&self, name: &str) -> bool {
+        self.redact.iter().any(|r| r.eq_ignore_ascii_case(name))
+    }
+
+    fn redact_json(&self, value: &mut Value) {
+        match value {
+            Value::Object(fields) => {
+                for (name, value) in fields.iter_mut() {
+                    if self.is_redacted(name) {
+                        *value = Value::String("[redacted]".to_string());
+                    } else {
+                        self.redact_json(value);
+                    }
+                }
+            }
+            Value::Array(values) => values.iter_mut().for_each(|value| self.redact_json(value)),
+            _ => {}
+        }
+    }
+
+    // Printable form of a body: JSON with secrets redacted, text as is, binary elided
+    fn format_body(&self, body: &[u8]) -> String {
+        if body.is_empty() {
+            return "(empty)".to_string();
+        }
+        let text = match serde_json::from_slice::<Value>(body) {
+            Ok(mut json) if !self.redact.is_empty() => {
+                self.redact_json(&mut json);
+                json.to_string()
+            }
+            _ => match std::str::from_utf8(body) {
+                Ok(text) => text.to_string(),
+                Err(_) => return format!("({} bytes of binary data)", body.len()),
+            },
+        };
+        if text.len() <= self.max_len {
+            return text;
+        }
+        let mut end = self.max_len;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        format!("{}... ({} bytes)", &text[..end], text.len())
+    }
+}
+
+pub(crate) fn request(id: usize, headers: &HashMap<String, String>, body: &[u8]) {
+    if let Some(trace) = TRACE.lock().unwrap().as_ref() {
+        let mut headers: Vec<_> = headers
+            .iter()
+            .map(|(name, value)| {
+                if trace.is_redacted(name) {
+                    format!("{}: [redacted]", name)
+                } else {
+                    format!("{}: {}", name, value)
+                }
+            })
+            .collect();
+        headers.sort();
+        println!("Request {} headers: {}", id, headers.join(", "));
+        println!("Request {} body: {}", id, trace.format_body(body));
+    }
+}
+
+pub(crate) fn response(id: usize, status_code: u16, body: &[u8]) {
+    if let Some(trace) = TRACE.lock().unwrap().as_ref() {
+        println!(
+            "Response {} ({}) body: {}",
+            id,
+            status_code,
+            trace.format_body(body)
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+use crate::config::WarmupRequest;
+use crate::{handle_request, is_ready, log, nodehttp};
+
+// A warmup request the guest hasn't answered by then is given up on
+const WARMUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Each one a whole HTTP/1.1 request, with its request line for the logs
+static REQUESTS: Mutex<Vec<(String, Vec<u8>)>> = Mutex::new(Vec::new());
+
+// Listeners don't accept connections until warmup is over
+static WARMING: AtomicBool = AtomicBool::new(false);
+static WARMED: Notify = Notify::const_new();
+
+/// Before the guest's listeners accept any connection, runs `requests` through it as if a
+/// client on localhost had sent them, and throws away the responses. The guest's first real
+/// requests then find its code compiled and its caches filled. Connections arriving
+/// meanwhile wait in the listen backlog.
+pub fn set_warmup(requests: &[WarmupRequest]) -> Result<(), String> {
+    let requests = requests
+        .iter()
+        .map(|request| {
+            let method = request.method.as_deref().unwrap_or("GET");
+            if !nodehttp::is_valid_header(method, "") {
+                return Err(format!("Invalid warmup method {:?}", method));
+            }
+            if !request.path.starts_with('/') || request.path.contains(char::is_whitespace) {
+                return Err(format!("Invalid warmup path {:?}", request.path));
+            }
+            let line = format!("{} {}", method, request.path);
+            let mut raw = format!(
+                "{} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n",
+                line
+            );
+            let headers = request.headers.iter().flatten();
+            for (name, value) in headers.clone() {
+                if !nodehttp::is_valid_header(name, value) {
+                    return Err(format!("Invalid warmup header {:?}", name));
+                }
+                write!(&mut raw, "{}: {}\r\n", name, value).unwrap();
+            }
+            let has_content_type = || {
+                headers
+                    .clone()
+                    .any(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+            };
+            // A string is sent as it is, anything else as JSON
+            let body = match &request.body {
+                None => String::new(),
+                Some(serde_json::Value::String(text)) => text.clone(),
+                Some(value) => {
+                    if !has_content_type() {
+                        raw.push_str("Content-Type: application/json\r\n");
+                    }
+                    value.to_string()
+                }
+            };
+            write!(&mut raw, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+            Ok((line, raw.into_bytes()))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    *REQUESTS.lock().unwrap() = requests;
+    Ok(())
+}
+
+// Holds off listeners until `run` is done, if there are warmup requests
+pub(crate) fn begin() {
+    if !REQUESTS.lock().unwrap().is_empty() {
+        WARMING.store(true, Ordering::SeqCst);
+    }
+}
+
+// Waits until listeners may accept connections
+pub(crate) async fn wait() {
+    loop {
+        // Registered before looking, so the end of warmup in between isn't missed
+        let warmed = WARMED.notified();
+        tokio::pin!(warmed);
+        warmed.as_mut().enable();
+        if !WARMING.load(Ordering::SeqCst) {
+            return;
+        }
+        warmed.await;
+    }
+}
+
+// Sends the warmup requests one by one once the guest is ready, then lets listeners accept
+pub(crate) async fn run() {
+    if !WARMING.load(Ordering::SeqCst) {
+        return;
+    }
+    // Only until `runtime.ready`, see `set_ready_timeout`
+    while !is_ready() {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let requests = std::mem::take(&mut *REQUESTS.lock().unwrap());
+    let start = Instant::now();
+    for (line, raw) in &requests {
+        let sent = Instant::now();
+        let served = nodehttp::create_server(handle_request).serve_local(raw);
+        match tokio::time::timeout(WARMUP_TIMEOUT, served).await {
+            Ok(Ok(response)) => {
+                // The status line is all that's kept of the response
+                let status = response.split(|&b| b == b'\r').next().unwrap_or_default();
+                log(
+                    2,
+                    &format!(
+                        "Warmup {}: {} in {:.1}ms",
+                        line,
+                        String::from_utf8_lossy(status),
+                        sent.elapsed().as_secs_f64() * 1000.0
+                    ),
+                );
+            }
+            Ok(Err(err)) => log(1, &format!("Warmup {} failed: {}", line, err)),
+            Err(_) => log(
+                1,
+                &format!(
+                    "Warmup {} not answered within {} seconds",
+                    line,
+                    WARMUP_TIMEOUT.as_secs()
+                ),
+            ),
+        }
+    }
+    log(
+        1,
+        &format!(
+            "Warmed up with {} requests in {:.1}ms",
+            requests.len(),
+            start.elapsed().as_secs_f64() * 1000.0
+        ),
+    );
+    WARMING.store(false, Ordering::SeqCst);
+    WARMED.notify_waiters();
+}